@@ -0,0 +1,151 @@
+use crate::config::AclRule;
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::Packet;
+use tracing::info;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AclAction {
+    Permit,
+    Deny,
+    /// Deny with ICMP communication administratively prohibited.
+    Reject,
+    /// Log the packet and go on to the next rule.
+    Log,
+}
+
+/// What to do with a packet evaluated by an access-control list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Permit,
+    Deny,
+    Reject,
+}
+
+/// An access-control list applied to an interface in a direction. Rules are evaluated in order
+/// and the first one permitting or denying the packet applies; packets matching none are
+/// denied.
+pub(crate) struct AccessList {
+    name: String,
+    rules: Vec<AclRule>,
+    /// The number of packets each rule has matched.
+    hits: Vec<u64>,
+}
+
+impl AccessList {
+    pub(crate) fn new(name: &str, rules: Vec<AclRule>) -> Self {
+        AccessList {
+            name: name.to_string(),
+            hits: vec![0; rules.len()],
+            rules,
+        }
+    }
+
+    pub(crate) fn evaluate(&mut self, packet: &Ipv4Packet) -> Verdict {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if !matches(rule, packet) {
+                continue;
+            }
+            self.hits[i] += 1;
+
+            match rule.action {
+                AclAction::Permit => return Verdict::Permit,
+                AclAction::Deny => return Verdict::Deny,
+                AclAction::Reject => return Verdict::Reject,
+                AclAction::Log => info!(
+                    "ACL {} rule {}: {:?} from {} to {}, {} bytes",
+                    self.name,
+                    i + 1,
+                    packet.get_next_level_protocol(),
+                    packet.get_source(),
+                    packet.get_destination(),
+                    packet.get_total_length()
+                ),
+            }
+        }
+        Verdict::Deny
+    }
+
+    /// Logs the hit counters, e.g. on shutdown.
+    pub(crate) fn log(&self, handler: &str, attachment: &str) {
+        for (i, (rule, hits)) in self.rules.iter().zip(&self.hits).enumerate() {
+            info!(
+                "{} ACL {} ({}) rule {} ({:?}) matched {} packets",
+                handler,
+                self.name,
+                attachment,
+                i + 1,
+                rule.action,
+                hits
+            );
+        }
+    }
+}
+
+/// Whether the packet meets all of the conditions of the rule. Conditions on the transport
+/// header don't match non-initial fragments.
+fn matches(rule: &AclRule, packet: &Ipv4Packet) -> bool {
+    if rule
+        .source
+        .is_some_and(|p| !p.contains(packet.get_source()))
+        || rule
+            .destination
+            .is_some_and(|p| !p.contains(packet.get_destination()))
+        || rule
+            .protocol
+            .is_some_and(|p| p != packet.get_next_level_protocol().0)
+        || rule.dscp.is_some_and(|dscp| dscp != packet.get_dscp())
+    {
+        return false;
+    }
+
+    if rule.source_ports.is_none()
+        && rule.destination_ports.is_none()
+        && rule.icmp_type.is_none()
+        && rule.tcp_flags.is_none()
+    {
+        return true;
+    }
+    if packet.get_fragment_offset() != 0 {
+        return false;
+    }
+
+    let protocol = packet.get_next_level_protocol();
+    let payload = packet.payload();
+    let port = |offset: usize| {
+        matches!(
+            protocol,
+            IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp
+        )
+        .then(|| payload.get(offset..offset + 2))
+        .flatten()
+        .map(|port| u16::from_be_bytes([port[0], port[1]]))
+    };
+
+    if let Some(ports) = &rule.source_ports {
+        if !port(0).is_some_and(|port| ports.contains(&port)) {
+            return false;
+        }
+    }
+    if let Some(ports) = &rule.destination_ports {
+        if !port(2).is_some_and(|port| ports.contains(&port)) {
+            return false;
+        }
+    }
+    if let Some(icmp_type) = rule.icmp_type {
+        if protocol != IpNextHeaderProtocols::Icmp || payload.first() != Some(&icmp_type) {
+            return false;
+        }
+    }
+    if let Some((flags, mask)) = rule.tcp_flags {
+        match payload.get(13) {
+            Some(&actual) if protocol == IpNextHeaderProtocols::Tcp => {
+                if actual as u16 & mask != flags & mask {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+    true
+}
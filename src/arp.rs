@@ -1,5 +1,6 @@
 use crate::ethernet::{ETHERNET_ADDRESS_LENGTH, ETHERNET_TYPE_IP};
 use crate::ipv4::IPV4_ADDRESS_LENGTH;
+use crate::vrf::VrfAssignments;
 use ipnetwork::IpNetwork;
 use pnet_datalink::{MacAddr, NetworkInterface};
 use pnet_packet::arp::{Arp, ArpHardwareType, ArpOperation, ArpPacket};
@@ -9,15 +10,16 @@ use std::net::Ipv4Addr;
 use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::task::JoinHandle;
-use tracing::debug;
+use tracing::{debug, error};
 
 const ARP_HARDWARE_TYPE_ETHERNET: u16 = 0x0001;
 
 const ARP_OPERATION_CODE_REQUEST: u16 = 0x0001;
 const ARP_OPERATION_CODE_REPLY: u16 = 0x0002;
 
+/// ARP entries scoped by VRF, as the same address may be in use in several VRFs.
 pub(crate) struct ArpTable {
-    entries: HashMap<Ipv4Addr, MacAddr>,
+    entries: HashMap<String, HashMap<Ipv4Addr, MacAddr>>,
 }
 
 impl ArpTable {
@@ -27,15 +29,20 @@ impl ArpTable {
         }
     }
 
-    pub(crate) fn get(&self, ipv4: &Ipv4Addr) -> Option<&MacAddr> {
-        self.entries.get(ipv4)
+    pub(crate) fn get(&self, vrf: &str, ipv4: &Ipv4Addr) -> Option<&MacAddr> {
+        self.entries.get(vrf).and_then(|entries| entries.get(ipv4))
     }
 
-    pub(crate) fn put(&mut self, ipv4: Ipv4Addr, mac: MacAddr) {
-        if let Some(old) = self.entries.insert(ipv4, mac) {
+    pub(crate) fn put(&mut self, vrf: &str, ipv4: Ipv4Addr, mac: MacAddr) {
+        if let Some(old) = self
+            .entries
+            .entry(vrf.to_string())
+            .or_default()
+            .insert(ipv4, mac)
+        {
             debug!(
-                "Replaced ARP table. ipv4: {}, old_mac: {}, new_mac: {}",
-                ipv4, mac, old
+                "Replaced ARP table. vrf: {}, ipv4: {}, old_mac: {}, new_mac: {}",
+                vrf, ipv4, mac, old
             );
         }
     }
@@ -44,7 +51,11 @@ impl ArpTable {
 #[derive(Debug)]
 pub(crate) enum ArpHandlerEvent {
     /// Received an ARP packet.
-    ReceivedPacket(ArpPacket<'static>),
+    ReceivedPacket {
+        /// The interface index (operating system specific) the packet arrived on.
+        interface_index: u32,
+        packet: ArpPacket<'static>,
+    },
     /// An event let ArpHandler to send ARP request.
     SendArpRequest(ArpRequest),
     Shutdown,
@@ -58,34 +69,30 @@ pub(crate) struct ArpRequest {
 }
 
 pub(crate) async fn spawn_arp_handler(
-    interfaces: &Vec<NetworkInterface>,
+    interfaces: &[NetworkInterface],
+    vrfs: VrfAssignments,
     arp_table: Arc<RwLock<ArpTable>>,
     receiver: UnboundedReceiver<ArpHandlerEvent>,
 ) -> JoinHandle<()> {
-    let mut interface_map = HashMap::new();
-    for i in interfaces {
-        i.ips
-            .iter()
-            .filter_map(|ipn| match ipn {
-                IpNetwork::V4(ipv4n) => Some(ipv4n.ip()),
-                IpNetwork::V6(_) => None,
-            })
-            .for_each(|ipv4| {
-                interface_map.insert(ipv4, i.clone());
-            });
-    }
+    let interface_map = interfaces
+        .iter()
+        .map(|i| (i.index, i.clone()))
+        .collect::<HashMap<_, _>>();
 
     ArpHandler {
         arp_table,
         receiver,
         interfaces: interface_map,
+        vrfs,
     }
     .spawn()
 }
 
 struct ArpHandler {
     arp_table: Arc<RwLock<ArpTable>>,
-    interfaces: HashMap<Ipv4Addr, NetworkInterface>,
+    /// The interfaces keyed by the interface index (operating system specific).
+    interfaces: HashMap<u32, NetworkInterface>,
+    vrfs: VrfAssignments,
     receiver: UnboundedReceiver<ArpHandlerEvent>,
 }
 
@@ -97,10 +104,13 @@ impl ArpHandler {
             loop {
                 if let Some(event) = self.receiver.recv().await {
                     match event {
-                        ArpHandlerEvent::ReceivedPacket(arp_packet) => {
-                            match arp_packet.get_operation().0 {
+                        ArpHandlerEvent::ReceivedPacket {
+                            interface_index,
+                            packet,
+                        } => {
+                            match packet.get_operation().0 {
                                 ARP_OPERATION_CODE_REQUEST => {
-                                    self.handle_request_packet(arp_packet)
+                                    self.handle_request_packet(interface_index, packet)
                                 }
                                 // TODO: Handle ARP response operation
                                 other => debug!("Unsupported ARP operation code: {}", other),
@@ -120,15 +130,28 @@ impl ArpHandler {
         tokio::runtime::Handle::current().spawn(fut)
     }
 
-    fn handle_request_packet(&self, packet: ArpPacket<'static>) {
+    fn handle_request_packet(&self, interface_index: u32, packet: ArpPacket<'static>) {
+        let interface = match self.interfaces.get(&interface_index) {
+            Some(interface) => interface,
+            None => {
+                error!("Unknown interface index: {}", interface_index);
+                return;
+            }
+        };
+
         // Update ARP table with the source mac/ipv4 address.
-        self.arp_table
-            .write()
-            .expect("write guard")
-            .put(packet.get_sender_proto_addr(), packet.get_sender_hw_addr());
+        self.arp_table.write().expect("write guard").put(
+            self.vrfs.get(interface_index),
+            packet.get_sender_proto_addr(),
+            packet.get_sender_hw_addr(),
+        );
 
         // Determine if the packet is ours.
-        if let Some(interface) = self.interfaces.get(&packet.get_target_proto_addr()) {
+        let target = packet.get_target_proto_addr();
+        if interface.ips.iter().any(|ipn| match ipn {
+            IpNetwork::V4(ipv4n) => ipv4n.ip() == target,
+            IpNetwork::V6(_) => false,
+        }) {
             let _reply = self.construct_reply(
                 interface.mac.expect("should have mac address"),
                 packet.get_target_proto_addr(),
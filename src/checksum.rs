@@ -0,0 +1,26 @@
+/// Incrementally updates a one's complement checksum for the data changed from `old` to `new`
+/// (RFC 1624 3). The data may differ in length, e.g. when a pseudo header no longer applies.
+pub(crate) fn update(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = !checksum as u32;
+    for word in old.chunks(2) {
+        sum += !to_word(word) as u32;
+    }
+    for word in new.chunks(2) {
+        sum += to_word(word) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Incrementally updates the checksum at the offset of the buffer.
+pub(crate) fn update_field(buffer: &mut [u8], offset: usize, old: &[u8], new: &[u8]) {
+    let checksum = u16::from_be_bytes([buffer[offset], buffer[offset + 1]]);
+    buffer[offset..offset + 2].copy_from_slice(&update(checksum, old, new).to_be_bytes());
+}
+
+/// A 16-bit word, padded with zero if the data has an odd length.
+fn to_word(chunk: &[u8]) -> u16 {
+    u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or_default()])
+}
@@ -0,0 +1,1181 @@
+use crate::acl::AclAction;
+use crate::router_advertisement::MAX_ROUTER_LIFETIME;
+use crate::routing::{RouteType, RpfMode, MAIN_TABLE};
+use crate::tcp_mss::MssClamp;
+use crate::vrf::DEFAULT_VRF;
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use pnet_datalink::NetworkInterface;
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::tcp::TcpFlags;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// Router configuration, loaded from a text file whose directives loosely follow the `ip(8)`
+/// syntax. Blank lines and anything after `#` are ignored.
+///
+/// ```text
+/// # ECMP default route over the two uplinks.
+/// route default nexthop via 192.168.0.2 dev router1-router2 nexthop via 192.168.3.2
+/// route 10.0.0.0/8 via 192.168.0.2
+///
+/// # Traffic from the upper half of host1's subnet uses the other uplink.
+/// route default via 192.168.3.2 table uplink2
+/// rule from 192.168.1.128/25 table uplink2
+///
+/// # An isolated routing instance.
+/// interface router1-cust1 vrf customer1
+/// route default via 192.168.1.254 vrf customer1
+///
+/// # Sink bogons, and fail fast for a decommissioned subnet.
+/// route blackhole 10.0.0.0/8
+/// route unreachable 192.168.10.0/24
+///
+/// # Drop spoofed traffic from host1.
+/// interface router1-host1 rpf strict
+///
+/// # Drop RFC 1918 space arriving from or destined beyond router2.
+/// bogon 10.0.0.0/8
+/// bogon 172.16.0.0/12
+/// interface router1-router2 bogon-filter on
+///
+/// # Let directed broadcasts from other networks reach host1's subnet.
+/// interface router1-host1 directed-broadcast on
+///
+/// # Follow strict/loose source routes instead of dropping them.
+/// source-route on
+///
+/// # ICMP redirects are sent by default, at most once per second per interface.
+/// interface router1-router2 redirects off
+/// interface router1-host1 redirect-rate 5
+///
+/// # Rate limits of the ICMP errors we generate.
+/// icmp-error-rate global 100 burst 20
+/// icmp-error-rate per-destination 2 burst 4
+///
+/// # Clamp the MSS of TCP SYNs via the tunnel to its MTU, or to a fixed value.
+/// interface tun0 mtu 1400 tcp-mss clamp
+/// interface router1-router2 tcp-mss 1360
+///
+/// # IPv6 static routes. Link-local gateways need the interface.
+/// route6 default via fe80::2 dev router1-router2
+/// route6 2001:db8:10::/48 via 2001:db8:0:1::2
+///
+/// # Router Advertisements for SLAAC on host1's link.
+/// interface router1-host1 ra on ra-interval 30 ra-mtu on ra-rdnss 2001:db8:1::53
+/// interface router1-host1 ra-prefix 2001:db8:1::/64 valid-lifetime 86400 preferred-lifetime 14400
+///
+/// # Stateful NAT64 from IPv6-only hosts to IPv4 via 64:ff9b::/96.
+/// nat64 pool 192.168.0.64/30
+/// nat64 prefix 64:ff9b::/96
+///
+/// # Masquerade host1's subnet behind the address of the uplink, or a given address.
+/// nat masquerade from 192.168.1.0/24 dev router1-router2
+/// nat snat from 192.168.4.0/24 dev router1-router2 to 192.168.0.10
+///
+/// # Forward a port of the router to host1.
+/// nat dnat tcp 192.168.0.1:8080 to 192.168.1.2:80
+///
+/// # The maximum number of tracked connections, and limits of new ones against floods.
+/// conntrack max 131072
+/// conntrack rate 100 burst 200
+/// conntrack half-open 1024 log-every 100
+///
+/// # Only let web traffic and pings in from router2, rejecting the rest.
+/// acl from-router2 permit ipproto tcp dport 80 tcp-flags syn/syn,ack
+/// acl from-router2 permit ipproto icmp icmp-type 8
+/// acl from-router2 log
+/// acl from-router2 reject
+/// interface router1-router2 acl in from-router2
+///
+/// # Protect host1 from connections originated beyond router2.
+/// interface router1-host1 zone inside
+/// interface router1-router2 zone outside
+/// zone-pair inside outside permit
+/// ```
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    /// Per-interface settings keyed by the interface name.
+    pub(crate) interfaces: HashMap<String, InterfaceConfig>,
+    pub(crate) routes: Vec<StaticRoute>,
+    pub(crate) ipv6_routes: Vec<StaticIpv6Route>,
+    /// Policy routing rules, in the order they are evaluated.
+    pub(crate) rules: Vec<PolicyRule>,
+    /// Prefixes dropped on the interfaces with the bogon filter enabled.
+    pub(crate) bogons: Vec<Ipv4Network>,
+    /// Whether to follow the source route options rather than dropping source routed packets.
+    pub(crate) source_route: bool,
+    /// The rate limit of the ICMP errors we generate in total.
+    pub(crate) icmp_error_global_rate_limit: Option<RateLimit>,
+    /// The rate limit of the ICMP errors we generate per destination.
+    pub(crate) icmp_error_per_destination_rate_limit: Option<RateLimit>,
+    pub(crate) nat64: Nat64Config,
+    /// Source NAT rules, in the order they are evaluated.
+    pub(crate) source_nat: Vec<SourceNatRule>,
+    pub(crate) destination_nat: Vec<DestinationNatRule>,
+    pub(crate) conntrack: ConntrackConfig,
+    /// Access-control lists keyed by their names, with the rules in the order they are
+    /// evaluated.
+    pub(crate) acls: HashMap<String, Vec<AclRule>>,
+    /// The policies of new connections between zones, keyed by the source and destination zones.
+    pub(crate) zone_pairs: HashMap<(String, String), ZonePairPolicy>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RateLimit {
+    /// Per second.
+    pub(crate) rate: u32,
+    pub(crate) burst: u32,
+}
+
+/// Translates the source of the new connections from the prefix leaving via the interface.
+#[derive(Clone, Debug)]
+pub(crate) struct SourceNatRule {
+    pub(crate) source: Ipv4Network,
+    /// The name of the egress interface.
+    pub(crate) interface: String,
+    /// The address to translate to. Defaults to the address of the interface (masquerade).
+    pub(crate) address: Option<Ipv4Addr>,
+}
+
+/// Translates the destination of the new connections to a port of the router (port forwarding).
+#[derive(Clone, Debug)]
+pub(crate) struct DestinationNatRule {
+    /// TCP or UDP.
+    pub(crate) protocol: u8,
+    /// The address and port of the router the connections are made to.
+    pub(crate) destination: SocketAddrV4,
+    /// The internal host and port to translate to.
+    pub(crate) target: SocketAddrV4,
+}
+
+/// A rule of an access-control list, matching the packets which meet all of the given
+/// conditions.
+#[derive(Clone, Debug)]
+pub(crate) struct AclRule {
+    pub(crate) action: AclAction,
+    pub(crate) source: Option<Ipv4Network>,
+    pub(crate) destination: Option<Ipv4Network>,
+    pub(crate) protocol: Option<u8>,
+    /// TCP or UDP ports.
+    pub(crate) source_ports: Option<RangeInclusive<u16>>,
+    pub(crate) destination_ports: Option<RangeInclusive<u16>>,
+    pub(crate) icmp_type: Option<u8>,
+    /// The TCP flags which must be set, among those in the mask.
+    pub(crate) tcp_flags: Option<(u16, u16)>,
+    pub(crate) dscp: Option<u8>,
+}
+
+/// What new connections from one zone to another are subject to.
+#[derive(Clone, Debug)]
+pub(crate) enum ZonePairPolicy {
+    Permit,
+    Deny,
+    /// Evaluated by the access-control list of the name.
+    Acl(String),
+}
+
+/// The connection tracking of forwarded flows.
+#[derive(Debug)]
+pub(crate) struct ConntrackConfig {
+    /// New connections are dropped while the table is full.
+    pub(crate) max_connections: usize,
+    /// The rate limit of new connections per source address.
+    pub(crate) per_source_rate_limit: Option<RateLimit>,
+    /// New TCP connections are dropped while this many are half-open.
+    pub(crate) max_half_open: Option<usize>,
+    /// One in this many new connections dropped by the limits is logged.
+    pub(crate) log_every: Option<u64>,
+}
+
+/// The default maximum number of tracked connections.
+const DEFAULT_MAX_CONNECTIONS: usize = 65536;
+
+impl Default for ConntrackConfig {
+    fn default() -> Self {
+        ConntrackConfig {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            per_source_rate_limit: None,
+            max_half_open: None,
+            log_every: None,
+        }
+    }
+}
+
+/// Stateful NAT64 (RFC 6146), enabled if the pool is given.
+#[derive(Debug)]
+pub(crate) struct Nat64Config {
+    /// The /96 prefix IPv4 addresses are embedded in (RFC 6052 2.2).
+    pub(crate) prefix: Ipv6Network,
+    /// The IPv4 addresses IPv6 hosts are translated to.
+    pub(crate) pool: Option<Ipv4Network>,
+}
+
+impl Default for Nat64Config {
+    fn default() -> Self {
+        Nat64Config {
+            prefix: Ipv6Network::new(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96)
+                .expect("valid prefix"),
+            pool: None,
+        }
+    }
+}
+
+/// The MTU of Ethernet.
+const DEFAULT_MTU: u16 = 1500;
+
+/// The MTU every IPv4 link must support, a 60-octet header and an 8-octet fragment (RFC 791).
+const MIN_IPV4_MTU: u16 = 68;
+
+/// The MTU every IPv6 link must support (RFC 8200 5).
+const MIN_IPV6_MTU: u16 = 1280;
+
+/// The lowest fixed MSS, which every IPv4 host must accept (RFC 879).
+const MIN_TCP_MSS: u16 = 536;
+
+#[derive(Clone, Debug)]
+pub(crate) struct InterfaceConfig {
+    /// The name of the VRF the interface is assigned to.
+    pub(crate) vrf: Option<String>,
+    pub(crate) rpf: RpfMode,
+    /// Whether to drop packets from or to the bogon prefixes.
+    pub(crate) bogon_filter: bool,
+    /// Whether to forward subnet-directed broadcasts to the network of the interface arriving
+    /// from other networks.
+    pub(crate) directed_broadcast: bool,
+    /// Whether to send ICMP redirects to the hosts on the network of the interface.
+    pub(crate) redirects: bool,
+    /// The maximum number of ICMP redirects sent per second via the interface.
+    pub(crate) redirect_rate: u32,
+    pub(crate) mtu: u16,
+    /// How the MSS option of the TCP SYNs forwarded to or from the interface is clamped.
+    pub(crate) tcp_mss: MssClamp,
+    pub(crate) router_advertisement: RouterAdvertisementConfig,
+    /// The names of the access-control lists applied to the packets arriving on and leaving via
+    /// the interface.
+    pub(crate) acl_in: Option<String>,
+    pub(crate) acl_out: Option<String>,
+    /// The name of the firewall zone the interface belongs to.
+    pub(crate) zone: Option<String>,
+}
+
+/// The Router Advertisements sent via an interface (RFC 4861 6.2.1).
+#[derive(Clone, Debug)]
+pub(crate) struct RouterAdvertisementConfig {
+    pub(crate) enabled: bool,
+    /// Addresses are available via DHCPv6.
+    pub(crate) managed: bool,
+    /// Other configuration is available via DHCPv6.
+    pub(crate) other: bool,
+    /// The router lifetime in seconds. Defaults to three times the interval.
+    pub(crate) lifetime: Option<u16>,
+    /// The maximum interval between unsolicited advertisements in seconds.
+    pub(crate) interval: u32,
+    /// Whether to advertise the MTU of the interface.
+    pub(crate) advertise_mtu: bool,
+    /// Defaults to the prefixes of the interface's global addresses.
+    pub(crate) prefixes: Vec<AdvertisedPrefix>,
+    /// Recursive DNS servers (RFC 8106).
+    pub(crate) rdnss: Vec<Ipv6Addr>,
+}
+
+impl Default for RouterAdvertisementConfig {
+    fn default() -> Self {
+        RouterAdvertisementConfig {
+            enabled: false,
+            managed: false,
+            other: false,
+            lifetime: None,
+            interval: 600,
+            advertise_mtu: false,
+            prefixes: vec![],
+            rdnss: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct AdvertisedPrefix {
+    pub(crate) prefix: Ipv6Network,
+    pub(crate) on_link: bool,
+    /// Whether hosts may autoconfigure addresses in the prefix (SLAAC).
+    pub(crate) autonomous: bool,
+    /// Seconds.
+    pub(crate) valid_lifetime: u32,
+    /// Seconds.
+    pub(crate) preferred_lifetime: u32,
+}
+
+impl AdvertisedPrefix {
+    pub(crate) fn new(prefix: Ipv6Network) -> Self {
+        AdvertisedPrefix {
+            prefix,
+            on_link: true,
+            autonomous: true,
+            valid_lifetime: 2_592_000,
+            preferred_lifetime: 604_800,
+        }
+    }
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        InterfaceConfig {
+            vrf: None,
+            rpf: RpfMode::Off,
+            bogon_filter: false,
+            directed_broadcast: false,
+            redirects: true,
+            redirect_rate: 1,
+            mtu: DEFAULT_MTU,
+            tcp_mss: MssClamp::Off,
+            router_advertisement: RouterAdvertisementConfig::default(),
+            acl_in: None,
+            acl_out: None,
+            zone: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct StaticRoute {
+    pub(crate) destination: Ipv4Network,
+    pub(crate) route_type: RouteType,
+    /// Empty unless the route is a unicast route.
+    pub(crate) next_hops: Vec<StaticNextHop>,
+    /// The name of the routing table the route belongs to.
+    pub(crate) table: String,
+    pub(crate) vrf: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct StaticIpv6Route {
+    pub(crate) destination: Ipv6Network,
+    pub(crate) gateway: Ipv6Addr,
+    /// Required if the gateway is a link-local address.
+    pub(crate) interface: Option<String>,
+    pub(crate) vrf: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct StaticNextHop {
+    pub(crate) gateway: Ipv4Addr,
+    /// The name of the egress interface. Resolved from the connected routes if omitted.
+    pub(crate) interface: Option<String>,
+}
+
+/// Selects a routing table for the packets matching all of the given conditions.
+#[derive(Debug)]
+pub(crate) struct PolicyRule {
+    pub(crate) source: Option<Ipv4Network>,
+    pub(crate) destination: Option<Ipv4Network>,
+    pub(crate) protocol: Option<u8>,
+    pub(crate) dscp: Option<u8>,
+    /// The name of the ingress interface.
+    pub(crate) interface: Option<String>,
+    pub(crate) table: String,
+    pub(crate) vrf: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct ConfigError {
+    line: usize,
+    message: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            0 => write!(f, "{}", self.message),
+            line => write!(f, "line {}: {}", line, self.message),
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn load(path: &str) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|e| ConfigError {
+            line: 0,
+            message: format!("failed to read {}: {}", path, e),
+        })?;
+        Self::parse(&content)
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<Self, ConfigError> {
+        let mut config = Config::default();
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut tokens = Tokens::new(line);
+            let keyword = match tokens.next() {
+                Some(keyword) => keyword,
+                None => continue,
+            };
+
+            let result = match keyword {
+                "interface" => parse_interface(&mut tokens, &mut config.interfaces),
+                "route" => parse_route(&mut tokens).map(|r| config.routes.push(r)),
+                "route6" => parse_ipv6_route(&mut tokens).map(|r| config.ipv6_routes.push(r)),
+                "rule" => parse_rule(&mut tokens).map(|r| config.rules.push(r)),
+                "source-route" => {
+                    parse_switch(&mut tokens, "source-route").map(|s| config.source_route = s)
+                }
+                "icmp-error-rate" => parse_icmp_error_rate(&mut tokens, &mut config),
+                "nat64" => parse_nat64(&mut tokens, &mut config.nat64),
+                "nat" => parse_nat(&mut tokens, &mut config),
+                "conntrack" => parse_conntrack(&mut tokens, &mut config.conntrack),
+                "acl" => parse_acl(&mut tokens, &mut config.acls),
+                "zone-pair" => parse_zone_pair(&mut tokens, &mut config.zone_pairs),
+                "bogon" => tokens
+                    .value("bogon prefix")
+                    .and_then(parse_prefix)
+                    .map(|p| config.bogons.push(p)),
+                other => Err(format!("unknown directive: {}", other)),
+            };
+
+            result
+                .and_then(|_| tokens.finish())
+                .map_err(|message| ConfigError {
+                    line: i + 1,
+                    message,
+                })?;
+        }
+
+        config
+            .validate()
+            .map_err(|message| ConfigError { line: 0, message })?;
+        Ok(config)
+    }
+
+    /// Checks the MTUs of the interfaces with IPv6 addresses, which must be at least the IPv6
+    /// minimum MTU.
+    pub(crate) fn check_interfaces(
+        &self,
+        interfaces: &[NetworkInterface],
+    ) -> Result<(), ConfigError> {
+        for interface in interfaces {
+            let mtu = match self.interfaces.get(&interface.name) {
+                Some(config) => config.mtu,
+                None => continue,
+            };
+            if mtu < MIN_IPV6_MTU && interface.ips.iter().any(|ipn| ipn.is_ipv6()) {
+                return Err(ConfigError {
+                    line: 0,
+                    message: format!(
+                        "interface {}: invalid MTU for IPv6: {} (must be at least {})",
+                        interface.name, mtu, MIN_IPV6_MTU
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the settings that may be given on different lines.
+    fn validate(&self) -> Result<(), String> {
+        for (name, interface) in &self.interfaces {
+            for acl in [&interface.acl_in, &interface.acl_out]
+                .into_iter()
+                .flatten()
+            {
+                if !self.acls.contains_key(acl) {
+                    return Err(format!("interface {}: unknown ACL: {}", name, acl));
+                }
+            }
+
+            let ra = &interface.router_advertisement;
+            if ra.enabled && interface.mtu < MIN_IPV6_MTU {
+                return Err(format!(
+                    "interface {}: invalid MTU for Router Advertisements: {} (must be at least {})",
+                    name, interface.mtu, MIN_IPV6_MTU
+                ));
+            }
+            if let Some(lifetime) = ra.lifetime {
+                let lifetime = lifetime as u32;
+                if lifetime != 0 && !(ra.interval..=MAX_ROUTER_LIFETIME).contains(&lifetime) {
+                    return Err(format!(
+                        "interface {}: invalid router lifetime: {} (must be 0 or {} to {})",
+                        name, lifetime, ra.interval, MAX_ROUTER_LIFETIME
+                    ));
+                }
+            }
+        }
+
+        for ((from, to), policy) in &self.zone_pairs {
+            if let ZonePairPolicy::Acl(acl) = policy {
+                if !self.acls.contains_key(acl) {
+                    return Err(format!("zone-pair {} {}: unknown ACL: {}", from, to, acl));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `interface <name> [vrf <name>] [rpf <off|strict|loose>] [bogon-filter <on|off>]
+/// [directed-broadcast <on|off>] [redirects <on|off>] [redirect-rate <per-second>] [mtu <bytes>]
+/// [tcp-mss <off|clamp|bytes>] [ra <on|off>] [ra-managed <on|off>] [ra-other <on|off>]
+/// [ra-lifetime <seconds>] [ra-interval <seconds>] [ra-mtu <on|off>] [ra-rdnss <address>]
+/// [ra-prefix <prefix> ...] [acl <in|out> <name>] [zone <name>]`
+///
+/// Settings given on multiple lines for the same interface are merged.
+fn parse_interface(
+    tokens: &mut Tokens,
+    interfaces: &mut HashMap<String, InterfaceConfig>,
+) -> Result<(), String> {
+    let name = tokens.value("interface name")?;
+    let interface = interfaces.entry(name.to_string()).or_default();
+
+    while let Some(option) = tokens.next() {
+        match option {
+            "vrf" => interface.vrf = Some(tokens.value("VRF name")?.to_string()),
+            "rpf" => {
+                interface.rpf = match tokens.value("RPF mode")? {
+                    "off" => RpfMode::Off,
+                    "strict" => RpfMode::Strict,
+                    "loose" => RpfMode::Loose,
+                    other => return Err(format!("invalid RPF mode: {}", other)),
+                }
+            }
+            "bogon-filter" => interface.bogon_filter = parse_switch(tokens, "bogon-filter")?,
+            "directed-broadcast" => {
+                interface.directed_broadcast = parse_switch(tokens, "directed-broadcast")?
+            }
+            "redirects" => interface.redirects = parse_switch(tokens, "redirects")?,
+            "redirect-rate" => {
+                let rate = tokens.parse("redirect rate")?;
+                if rate == 0 {
+                    return Err("invalid redirect rate: 0 (use redirects off)".to_string());
+                }
+                interface.redirect_rate = rate;
+            }
+            "mtu" => {
+                let mtu = tokens.parse("MTU")?;
+                if mtu < MIN_IPV4_MTU {
+                    return Err(format!(
+                        "invalid MTU: {} (must be at least {})",
+                        mtu, MIN_IPV4_MTU
+                    ));
+                }
+                interface.mtu = mtu;
+            }
+            "zone" => interface.zone = Some(tokens.value("zone name")?.to_string()),
+            "acl" => {
+                let direction = tokens.value("ACL direction")?;
+                let name = Some(tokens.value("ACL name")?.to_string());
+                match direction {
+                    "in" => interface.acl_in = name,
+                    "out" => interface.acl_out = name,
+                    other => return Err(format!("invalid ACL direction: {}", other)),
+                }
+            }
+            "tcp-mss" => {
+                interface.tcp_mss = match tokens.value("TCP MSS")? {
+                    "off" => MssClamp::Off,
+                    "clamp" => MssClamp::Mtu,
+                    other => match other.parse() {
+                        Ok(mss) if mss >= MIN_TCP_MSS => MssClamp::Fixed(mss),
+                        _ => return Err(format!("invalid TCP MSS: {}", other)),
+                    },
+                }
+            }
+            "ra" => interface.router_advertisement.enabled = parse_switch(tokens, "ra")?,
+            "ra-managed" => {
+                interface.router_advertisement.managed = parse_switch(tokens, "ra-managed")?
+            }
+            "ra-other" => interface.router_advertisement.other = parse_switch(tokens, "ra-other")?,
+            "ra-lifetime" => {
+                interface.router_advertisement.lifetime = Some(tokens.parse("router lifetime")?)
+            }
+            "ra-interval" => {
+                let interval = tokens.parse("RA interval")?;
+                if !(4..=1800).contains(&interval) {
+                    return Err(format!("invalid RA interval: {}", interval));
+                }
+                interface.router_advertisement.interval = interval;
+            }
+            "ra-mtu" => {
+                interface.router_advertisement.advertise_mtu = parse_switch(tokens, "ra-mtu")?
+            }
+            "ra-prefix" => interface
+                .router_advertisement
+                .prefixes
+                .push(parse_advertised_prefix(tokens)?),
+            "ra-rdnss" => interface
+                .router_advertisement
+                .rdnss
+                .push(tokens.parse("RDNSS address")?),
+            other => return Err(format!("unknown interface option: {}", other)),
+        }
+    }
+
+    Ok(())
+}
+
+/// `ra-prefix <prefix> [valid-lifetime <seconds>] [preferred-lifetime <seconds>] [on-link on|off]
+/// [autonomous on|off]`
+fn parse_advertised_prefix(tokens: &mut Tokens) -> Result<AdvertisedPrefix, String> {
+    let mut prefix = AdvertisedPrefix::new(parse_ipv6_prefix(tokens.value("RA prefix")?)?);
+
+    loop {
+        if tokens.accept("valid-lifetime") {
+            prefix.valid_lifetime = tokens.parse("valid lifetime")?;
+        } else if tokens.accept("preferred-lifetime") {
+            prefix.preferred_lifetime = tokens.parse("preferred lifetime")?;
+        } else if tokens.accept("on-link") {
+            prefix.on_link = parse_switch(tokens, "on-link")?;
+        } else if tokens.accept("autonomous") {
+            prefix.autonomous = parse_switch(tokens, "autonomous")?;
+        } else {
+            break;
+        }
+    }
+
+    if prefix.preferred_lifetime > prefix.valid_lifetime {
+        return Err("the preferred lifetime exceeds the valid lifetime".to_string());
+    }
+    Ok(prefix)
+}
+
+/// `route <prefix|default> via <gateway> [dev <interface>] [table <name>] [vrf <name>]`
+/// `route <prefix|default> nexthop via <gateway> [dev <interface>] [nexthop ...] [table <name>]
+/// [vrf <name>]`
+/// `route <blackhole|unreachable|reject|prohibit> <prefix|default> [table <name>] [vrf <name>]`
+fn parse_route(tokens: &mut Tokens) -> Result<StaticRoute, String> {
+    let route_type = match tokens.peek() {
+        Some("blackhole") => RouteType::Blackhole,
+        Some("unreachable") | Some("reject") => RouteType::Unreachable,
+        Some("prohibit") => RouteType::Prohibit,
+        _ => RouteType::Unicast,
+    };
+    if route_type != RouteType::Unicast {
+        tokens.next();
+    }
+
+    let destination = parse_prefix(tokens.value("route destination")?)?;
+
+    let mut next_hops = vec![];
+    if route_type == RouteType::Unicast {
+        match tokens.peek() {
+            Some("via") => next_hops.push(parse_next_hop(tokens)?),
+            Some("nexthop") => {
+                while tokens.accept("nexthop") {
+                    next_hops.push(parse_next_hop(tokens)?);
+                }
+            }
+            _ => return Err("expected `via` or `nexthop`".to_string()),
+        }
+    }
+
+    let mut route = StaticRoute {
+        destination,
+        route_type,
+        next_hops,
+        table: MAIN_TABLE.to_string(),
+        vrf: DEFAULT_VRF.to_string(),
+    };
+
+    while let Some(option) = tokens.next() {
+        match option {
+            "table" => route.table = tokens.value("table name")?.to_string(),
+            "vrf" => route.vrf = tokens.value("VRF name")?.to_string(),
+            other => return Err(format!("unexpected token: {}", other)),
+        }
+    }
+
+    Ok(route)
+}
+
+/// `route6 <prefix|default> via <gateway> [dev <interface>] [vrf <name>]`
+fn parse_ipv6_route(tokens: &mut Tokens) -> Result<StaticIpv6Route, String> {
+    let destination = parse_ipv6_prefix(tokens.value("route destination")?)?;
+    tokens.expect("via")?;
+    let gateway = tokens.parse("gateway")?;
+
+    let mut route = StaticIpv6Route {
+        destination,
+        gateway,
+        interface: None,
+        vrf: DEFAULT_VRF.to_string(),
+    };
+
+    while let Some(option) = tokens.next() {
+        match option {
+            "dev" => route.interface = Some(tokens.value("interface name")?.to_string()),
+            "vrf" => route.vrf = tokens.value("VRF name")?.to_string(),
+            other => return Err(format!("unexpected token: {}", other)),
+        }
+    }
+
+    Ok(route)
+}
+
+/// `rule [from <prefix>] [to <prefix>] [ipproto <protocol>] [dscp <value>] [iif <interface>]
+/// [vrf <name>] table <name>`
+fn parse_rule(tokens: &mut Tokens) -> Result<PolicyRule, String> {
+    let mut rule = PolicyRule {
+        source: None,
+        destination: None,
+        protocol: None,
+        dscp: None,
+        interface: None,
+        table: String::new(),
+        vrf: DEFAULT_VRF.to_string(),
+    };
+
+    loop {
+        match tokens.next() {
+            Some("from") => rule.source = Some(parse_prefix(tokens.value("source prefix")?)?),
+            Some("to") => {
+                rule.destination = Some(parse_prefix(tokens.value("destination prefix")?)?)
+            }
+            Some("ipproto") => rule.protocol = Some(parse_protocol(tokens.value("protocol")?)?),
+            Some("dscp") => {
+                let dscp: u8 = tokens.parse("DSCP")?;
+                if dscp > 63 {
+                    return Err(format!("invalid DSCP: {}", dscp));
+                }
+                rule.dscp = Some(dscp);
+            }
+            Some("iif") => rule.interface = Some(tokens.value("interface name")?.to_string()),
+            Some("vrf") => rule.vrf = tokens.value("VRF name")?.to_string(),
+            Some("table") => {
+                rule.table = tokens.value("table name")?.to_string();
+                return Ok(rule);
+            }
+            Some(other) => return Err(format!("unexpected token: {}", other)),
+            None => return Err("missing `table`".to_string()),
+        }
+    }
+}
+
+/// `icmp-error-rate <global|per-destination> <per-second> [burst <size>]`
+fn parse_icmp_error_rate(tokens: &mut Tokens, config: &mut Config) -> Result<(), String> {
+    let scope = tokens.value("scope")?;
+    let limit = Some(parse_rate_limit(tokens)?);
+
+    match scope {
+        "global" => config.icmp_error_global_rate_limit = limit,
+        "per-destination" => config.icmp_error_per_destination_rate_limit = limit,
+        other => return Err(format!("invalid scope: {}", other)),
+    }
+    Ok(())
+}
+
+/// `<per-second> [burst <size>]`, the burst defaulting to the rate. Zeros are rejected, as the
+/// limit would never let anything through.
+fn parse_rate_limit(tokens: &mut Tokens) -> Result<RateLimit, String> {
+    let rate = tokens.parse("rate")?;
+    if rate == 0 {
+        return Err("invalid rate: 0".to_string());
+    }
+    let burst = if tokens.accept("burst") {
+        tokens.parse("burst")?
+    } else {
+        rate
+    };
+    if burst == 0 {
+        return Err("invalid burst: 0".to_string());
+    }
+    Ok(RateLimit { rate, burst })
+}
+
+/// `nat masquerade from <prefix> dev <interface>`
+/// `nat snat from <prefix> dev <interface> to <address>`
+/// `nat dnat <tcp|udp> <address>:<port> to <address>:<port>`
+fn parse_nat(tokens: &mut Tokens, config: &mut Config) -> Result<(), String> {
+    match tokens.value("NAT type")? {
+        kind @ ("masquerade" | "snat") => {
+            tokens.expect("from")?;
+            let source = parse_prefix(tokens.value("source prefix")?)?;
+            tokens.expect("dev")?;
+            let interface = tokens.value("interface name")?.to_string();
+            let address = if kind == "snat" {
+                tokens.expect("to")?;
+                Some(tokens.parse("address")?)
+            } else {
+                None
+            };
+            config.source_nat.push(SourceNatRule {
+                source,
+                interface,
+                address,
+            });
+        }
+        "dnat" => {
+            let protocol = match parse_protocol(tokens.value("protocol")?)? {
+                p if p == IpNextHeaderProtocols::Tcp.0 || p == IpNextHeaderProtocols::Udp.0 => p,
+                p => return Err(format!("unsupported DNAT protocol: {}", p)),
+            };
+            let destination = tokens.parse("destination")?;
+            tokens.expect("to")?;
+            let target = tokens.parse("target")?;
+            config.destination_nat.push(DestinationNatRule {
+                protocol,
+                destination,
+                target,
+            });
+        }
+        other => return Err(format!("unknown NAT type: {}", other)),
+    }
+    Ok(())
+}
+
+/// `acl <name> <permit|deny|reject|log> [from <prefix>] [to <prefix>] [ipproto <protocol>]
+/// [sport <port>[-<port>]] [dport <port>[-<port>]] [icmp-type <type>]
+/// [tcp-flags <flags>[/<mask>]] [dscp <value>]`
+///
+/// Flags are comma separated, e.g. `syn/syn,ack` matches SYNs without ACK. Without a mask, the
+/// flags given must be set.
+fn parse_acl(tokens: &mut Tokens, acls: &mut HashMap<String, Vec<AclRule>>) -> Result<(), String> {
+    let name = tokens.value("ACL name")?.to_string();
+    let action = match tokens.value("ACL action")? {
+        "permit" => AclAction::Permit,
+        "deny" => AclAction::Deny,
+        "reject" => AclAction::Reject,
+        "log" => AclAction::Log,
+        other => return Err(format!("invalid ACL action: {}", other)),
+    };
+    let mut rule = AclRule {
+        action,
+        source: None,
+        destination: None,
+        protocol: None,
+        source_ports: None,
+        destination_ports: None,
+        icmp_type: None,
+        tcp_flags: None,
+        dscp: None,
+    };
+
+    while let Some(condition) = tokens.next() {
+        match condition {
+            "from" => rule.source = Some(parse_prefix(tokens.value("source prefix")?)?),
+            "to" => rule.destination = Some(parse_prefix(tokens.value("destination prefix")?)?),
+            "ipproto" => rule.protocol = Some(parse_protocol(tokens.value("protocol")?)?),
+            "sport" => rule.source_ports = Some(parse_port_range(tokens.value("source port")?)?),
+            "dport" => {
+                rule.destination_ports = Some(parse_port_range(tokens.value("destination port")?)?)
+            }
+            "icmp-type" => rule.icmp_type = Some(tokens.parse("ICMP type")?),
+            "tcp-flags" => rule.tcp_flags = Some(parse_tcp_flags(tokens.value("TCP flags")?)?),
+            "dscp" => {
+                let dscp: u8 = tokens.parse("DSCP")?;
+                if dscp > 63 {
+                    return Err(format!("invalid DSCP: {}", dscp));
+                }
+                rule.dscp = Some(dscp);
+            }
+            other => return Err(format!("unexpected token: {}", other)),
+        }
+    }
+
+    acls.entry(name).or_default().push(rule);
+    Ok(())
+}
+
+/// `zone-pair <from-zone> <to-zone> <permit|deny|acl <name>>`
+fn parse_zone_pair(
+    tokens: &mut Tokens,
+    zone_pairs: &mut HashMap<(String, String), ZonePairPolicy>,
+) -> Result<(), String> {
+    let from = tokens.value("source zone")?.to_string();
+    let to = tokens.value("destination zone")?.to_string();
+    let policy = match tokens.value("zone pair policy")? {
+        "permit" => ZonePairPolicy::Permit,
+        "deny" => ZonePairPolicy::Deny,
+        "acl" => ZonePairPolicy::Acl(tokens.value("ACL name")?.to_string()),
+        other => return Err(format!("invalid zone pair policy: {}", other)),
+    };
+    zone_pairs.insert((from, to), policy);
+    Ok(())
+}
+
+/// Parses a port or a range of ports, e.g. `1024-65535`.
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |p: &str| p.parse().map_err(|_| format!("invalid port: {}", s));
+    let (first, last) = match s.split_once('-') {
+        Some((first, last)) => (parse(first)?, parse(last)?),
+        None => (parse(s)?, parse(s)?),
+    };
+    if first > last {
+        return Err(format!("invalid port range: {}", s));
+    }
+    Ok(first..=last)
+}
+
+/// Parses `<flags>[/<mask>]` into the flags and the mask.
+fn parse_tcp_flags(s: &str) -> Result<(u16, u16), String> {
+    let parse = |flags: &str| {
+        flags.split(',').try_fold(0, |acc, flag| {
+            let flag = match flag {
+                "fin" => TcpFlags::FIN,
+                "syn" => TcpFlags::SYN,
+                "rst" => TcpFlags::RST,
+                "psh" => TcpFlags::PSH,
+                "ack" => TcpFlags::ACK,
+                "urg" => TcpFlags::URG,
+                "ece" => TcpFlags::ECE,
+                "cwr" => TcpFlags::CWR,
+                other => return Err(format!("invalid TCP flag: {}", other)),
+            };
+            Ok(acc | flag)
+        })
+    };
+    match s.split_once('/') {
+        Some((flags, mask)) => Ok((parse(flags)?, parse(mask)?)),
+        None => parse(s).map(|flags| (flags, flags)),
+    }
+}
+
+/// `conntrack [max <connections>] [rate <per-second> [burst <size>]] [half-open <connections>]
+/// [log-every <drops>]`
+fn parse_conntrack(tokens: &mut Tokens, conntrack: &mut ConntrackConfig) -> Result<(), String> {
+    while let Some(setting) = tokens.next() {
+        match setting {
+            "max" => conntrack.max_connections = tokens.parse("maximum connections")?,
+            "rate" => conntrack.per_source_rate_limit = Some(parse_rate_limit(tokens)?),
+            "half-open" => conntrack.max_half_open = Some(tokens.parse("half-open connections")?),
+            "log-every" => {
+                let n = tokens.parse("log sampling")?;
+                if n == 0 {
+                    return Err("invalid log sampling: 0".to_string());
+                }
+                conntrack.log_every = Some(n);
+            }
+            other => return Err(format!("unexpected token: {}", other)),
+        }
+    }
+    Ok(())
+}
+
+/// `nat64 pool <prefix>`
+/// `nat64 prefix <ipv6-prefix>`
+fn parse_nat64(tokens: &mut Tokens, nat64: &mut Nat64Config) -> Result<(), String> {
+    match tokens.value("NAT64 setting")? {
+        "pool" => nat64.pool = Some(parse_prefix(tokens.value("NAT64 pool")?)?),
+        "prefix" => {
+            let prefix = parse_ipv6_prefix(tokens.value("NAT64 prefix")?)?;
+            if prefix.prefix() != 96 {
+                return Err(format!("unsupported NAT64 prefix length: {}", prefix));
+            }
+            nat64.prefix = prefix;
+        }
+        other => return Err(format!("unexpected token: {}", other)),
+    }
+    Ok(())
+}
+
+/// Parses `on` or `off`.
+fn parse_switch(tokens: &mut Tokens, what: &str) -> Result<bool, String> {
+    match tokens.value(what)? {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        other => Err(format!(
+            "invalid {}: {} (expected `on` or `off`)",
+            what, other
+        )),
+    }
+}
+
+/// Parses an IP protocol given by its name or number.
+pub(crate) fn parse_protocol(s: &str) -> Result<u8, String> {
+    match s {
+        "icmp" => Ok(IpNextHeaderProtocols::Icmp.0),
+        "tcp" => Ok(IpNextHeaderProtocols::Tcp.0),
+        "udp" => Ok(IpNextHeaderProtocols::Udp.0),
+        _ => s.parse().map_err(|_| format!("invalid protocol: {}", s)),
+    }
+}
+
+fn parse_next_hop(tokens: &mut Tokens) -> Result<StaticNextHop, String> {
+    tokens.expect("via")?;
+    let gateway = tokens.parse("gateway address")?;
+    let interface = if tokens.accept("dev") {
+        Some(tokens.value("interface name")?.to_string())
+    } else {
+        None
+    };
+
+    Ok(StaticNextHop { gateway, interface })
+}
+
+/// Parses an IPv4 prefix, normalizing away any host bits. `default` means `0.0.0.0/0`.
+pub(crate) fn parse_prefix(s: &str) -> Result<Ipv4Network, String> {
+    if s == "default" {
+        return Ok(Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).expect("valid prefix"));
+    }
+
+    let network = Ipv4Network::from_str(s).map_err(|e| format!("invalid prefix {}: {}", s, e))?;
+    Ok(Ipv4Network::new(network.network(), network.prefix()).expect("valid prefix"))
+}
+
+/// Parses an IPv6 prefix, normalizing away any host bits. `default` means `::/0`.
+pub(crate) fn parse_ipv6_prefix(s: &str) -> Result<Ipv6Network, String> {
+    if s == "default" {
+        return Ok(Ipv6Network::new(Ipv6Addr::UNSPECIFIED, 0).expect("valid prefix"));
+    }
+
+    let network = Ipv6Network::from_str(s).map_err(|e| format!("invalid prefix {}: {}", s, e))?;
+    Ok(Ipv6Network::new(network.network(), network.prefix()).expect("valid prefix"))
+}
+
+/// A cursor over the whitespace separated tokens of a config line.
+pub(crate) struct Tokens<'a> {
+    inner: std::iter::Peekable<std::str::SplitWhitespace<'a>>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(line: &'a str) -> Self {
+        Tokens {
+            inner: line.split_whitespace().peekable(),
+        }
+    }
+
+    pub(crate) fn next(&mut self) -> Option<&'a str> {
+        self.inner.next()
+    }
+
+    pub(crate) fn peek(&mut self) -> Option<&'a str> {
+        self.inner.peek().copied()
+    }
+
+    /// Consumes the next token if it equals `keyword`.
+    pub(crate) fn accept(&mut self, keyword: &str) -> bool {
+        if self.peek() == Some(keyword) {
+            self.inner.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn expect(&mut self, keyword: &str) -> Result<(), String> {
+        if self.accept(keyword) {
+            Ok(())
+        } else {
+            Err(format!("expected `{}`", keyword))
+        }
+    }
+
+    pub(crate) fn value(&mut self, what: &str) -> Result<&'a str, String> {
+        self.inner.next().ok_or_else(|| format!("missing {}", what))
+    }
+
+    pub(crate) fn parse<T: FromStr>(&mut self, what: &str) -> Result<T, String> {
+        let value = self.value(what)?;
+        value
+            .parse()
+            .map_err(|_| format!("invalid {}: {}", what, value))
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        match self.inner.next() {
+            Some(token) => Err(format!("unexpected token: {}", token)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(content: &str) -> String {
+        Config::parse(content).unwrap_err().to_string()
+    }
+
+    fn interface(name: &str, ips: &[&str]) -> NetworkInterface {
+        NetworkInterface {
+            name: name.to_string(),
+            description: String::new(),
+            index: 1,
+            mac: None,
+            ips: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn parses_mtus() {
+        let config = Config::parse("interface eth0 mtu 68\ninterface eth1 mtu 1280 ra on").unwrap();
+        assert_eq!(config.interfaces["eth0"].mtu, 68);
+        assert_eq!(config.interfaces["eth1"].mtu, 1280);
+
+        assert_eq!(
+            parse_error("interface eth0 mtu 40"),
+            "line 1: invalid MTU: 40 (must be at least 68)"
+        );
+        assert_eq!(
+            parse_error("interface eth0 mtu 70000"),
+            "line 1: invalid MTU: 70000"
+        );
+        assert_eq!(
+            parse_error("interface eth0 ra on\ninterface eth0 mtu 1279"),
+            "interface eth0: invalid MTU for Router Advertisements: 1279 (must be at least 1280)"
+        );
+
+        let ipv4_only = interface("eth0", &["192.168.1.1/24"]);
+        let dual_stack = interface("eth0", &["192.168.1.1/24", "fe80::1/64"]);
+        assert!(config
+            .check_interfaces(std::slice::from_ref(&ipv4_only))
+            .is_ok());
+        assert_eq!(
+            config
+                .check_interfaces(&[dual_stack])
+                .unwrap_err()
+                .to_string(),
+            "interface eth0: invalid MTU for IPv6: 68 (must be at least 1280)"
+        );
+        assert!(Config::default().check_interfaces(&[ipv4_only]).is_ok());
+    }
+
+    #[test]
+    fn parses_icmp_error_rates() {
+        let config =
+            Config::parse("icmp-error-rate global 100 burst 20\nicmp-error-rate per-destination 2")
+                .unwrap();
+        let global = config.icmp_error_global_rate_limit.unwrap();
+        assert_eq!((global.rate, global.burst), (100, 20));
+        let per_destination = config.icmp_error_per_destination_rate_limit.unwrap();
+        assert_eq!((per_destination.rate, per_destination.burst), (2, 2));
+
+        assert_eq!(
+            parse_error("icmp-error-rate global 0"),
+            "line 1: invalid rate: 0"
+        );
+        assert_eq!(
+            parse_error("icmp-error-rate per-destination 1 burst 0"),
+            "line 1: invalid burst: 0"
+        );
+        assert_eq!(
+            parse_error("icmp-error-rate local 1"),
+            "line 1: invalid scope: local"
+        );
+        assert_eq!(
+            parse_error("icmp-error-rate global 1 burst"),
+            "line 1: missing burst"
+        );
+    }
+
+    #[test]
+    fn parses_conntrack_limits() {
+        let config = Config::parse("conntrack max 1000 rate 100 burst 200 half-open 10").unwrap();
+        assert_eq!(config.conntrack.max_connections, 1000);
+        let rate = config.conntrack.per_source_rate_limit.unwrap();
+        assert_eq!((rate.rate, rate.burst), (100, 200));
+        assert_eq!(config.conntrack.max_half_open, Some(10));
+
+        assert_eq!(parse_error("conntrack rate 0"), "line 1: invalid rate: 0");
+        assert_eq!(
+            parse_error("conntrack rate 10 burst 0"),
+            "line 1: invalid burst: 0"
+        );
+        assert_eq!(
+            parse_error("conntrack log-every 0"),
+            "line 1: invalid log sampling: 0"
+        );
+    }
+}
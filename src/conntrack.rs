@@ -0,0 +1,669 @@
+use crate::config::{Config, RateLimit};
+use crate::counters::DropReason;
+use crate::rate_limit::TokenBucket;
+use pnet_packet::icmp::IcmpTypes;
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::tcp::TcpFlags;
+use pnet_packet::Packet;
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Lifetimes of the connections by state. The TCP established, UDP and ICMP ones are the
+/// minimums NAT mappings must last: RFC 5382 5, RFC 4787 4.3 and RFC 5508 3.2.
+const TCP_SYN_SENT_TIMEOUT: Duration = Duration::from_secs(120);
+const TCP_SYN_RECEIVED_TIMEOUT: Duration = Duration::from_secs(60);
+const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(7440);
+const TCP_FIN_WAIT_TIMEOUT: Duration = Duration::from_secs(120);
+const TCP_TIME_WAIT_TIMEOUT: Duration = Duration::from_secs(120);
+const TCP_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+const UDP_UNREPLIED_TIMEOUT: Duration = Duration::from_secs(120);
+const UDP_ASSURED_TIMEOUT: Duration = Duration::from_secs(300);
+const ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The maximum number of sources whose new connections are rate limited. New connections from
+/// other sources are dropped while the limiters of all of them are in use.
+const MAX_PER_SOURCE_LIMITERS: usize = 4096;
+
+/// Per-source limiters idle for longer than this are evicted.
+const PER_SOURCE_LIMITER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often idle per-source limiters may be looked for while the limiters are full.
+const PER_SOURCE_EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The datagrams whose later fragments are dropped are remembered for this long, the time
+/// allowed for reassembly (RFC 791).
+const DROPPED_DATAGRAM_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The maximum number of datagrams whose later fragments are dropped.
+const MAX_DROPPED_DATAGRAMS: usize = 4096;
+
+/// An address and a TCP/UDP port or an ICMP query identifier.
+pub(crate) type Endpoint = (Ipv4Addr, u16);
+
+/// The source, destination, identification and protocol shared by the fragments of a datagram.
+type DatagramKey = (Ipv4Addr, Ipv4Addr, u16, u8);
+
+fn datagram_key(packet: &Ipv4Packet) -> DatagramKey {
+    (
+        packet.get_source(),
+        packet.get_destination(),
+        packet.get_identification(),
+        packet.get_next_level_protocol().0,
+    )
+}
+
+/// The protocol and endpoints of a packet. ICMP queries carry the identifier as both ports, so
+/// that a reply has the reverse tuple of its request.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct Tuple {
+    pub(crate) protocol: IpNextHeaderProtocol,
+    pub(crate) source: Endpoint,
+    pub(crate) destination: Endpoint,
+}
+
+impl Tuple {
+    /// Returns `None` for packets which aren't tracked: protocols other than TCP, UDP and ICMP
+    /// queries, and non-initial fragments.
+    pub(crate) fn new(packet: &Ipv4Packet) -> Option<Self> {
+        if packet.get_fragment_offset() != 0 {
+            return None;
+        }
+
+        let protocol = packet.get_next_level_protocol();
+        let payload = packet.payload();
+        let (source_port, destination_port) = match protocol {
+            IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp if payload.len() >= 4 => (
+                u16::from_be_bytes([payload[0], payload[1]]),
+                u16::from_be_bytes([payload[2], payload[3]]),
+            ),
+            IpNextHeaderProtocols::Icmp if payload.len() >= 8 && is_query(payload[0]) => {
+                let identifier = u16::from_be_bytes([payload[4], payload[5]]);
+                (identifier, identifier)
+            }
+            _ => return None,
+        };
+
+        Some(Tuple {
+            protocol,
+            source: (packet.get_source(), source_port),
+            destination: (packet.get_destination(), destination_port),
+        })
+    }
+
+    pub(crate) fn reverse(&self) -> Self {
+        Tuple {
+            protocol: self.protocol,
+            source: self.destination,
+            destination: self.source,
+        }
+    }
+}
+
+fn is_query(icmp_type: u8) -> bool {
+    icmp_type == IcmpTypes::EchoRequest.0 || icmp_type == IcmpTypes::EchoReply.0
+}
+
+/// The TCP flags of the packet, or zero for other protocols.
+pub(crate) fn tcp_flags(packet: &Ipv4Packet) -> u16 {
+    match packet.get_next_level_protocol() {
+        IpNextHeaderProtocols::Tcp if packet.payload().len() >= 14 => packet.payload()[13] as u16,
+        _ => 0,
+    }
+}
+
+/// Whether a packet with no tracked connection may start one. TCP connections start with a SYN.
+pub(crate) fn is_valid_new(tuple: &Tuple, tcp_flags: u16) -> bool {
+    tuple.protocol != IpNextHeaderProtocols::Tcp
+        || tcp_flags & (TcpFlags::SYN | TcpFlags::ACK | TcpFlags::RST | TcpFlags::FIN)
+            == TcpFlags::SYN
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum State {
+    /// A TCP SYN has been seen in the original direction.
+    SynSent,
+    /// The SYN has been answered by a SYN-ACK.
+    SynReceived,
+    /// The handshake has completed, or the connection was picked up in the middle.
+    Established,
+    /// A FIN has been seen in one direction.
+    FinWait,
+    /// FINs have been seen in both directions.
+    TimeWait,
+    /// A RST has been seen.
+    Close,
+    /// A UDP or ICMP query flow without replies yet.
+    Unreplied,
+    /// A UDP or ICMP query flow with replies.
+    Assured,
+}
+
+/// A tracked connection. The reply tuple is the reverse of the original one unless the
+/// connection is translated.
+#[derive(Debug)]
+struct Connection {
+    original: Tuple,
+    reply: Tuple,
+    state: State,
+    /// The directions FINs have been seen in, indexed by whether it is the reply direction.
+    fins: [bool; 2],
+    expires: Instant,
+    /// Packets and bytes, indexed by whether it is the reply direction.
+    packets: [u64; 2],
+    bytes: [u64; 2],
+}
+
+impl Connection {
+    fn new(original: Tuple, reply: Tuple, tcp_flags: u16, now: Instant) -> Self {
+        let state = match original.protocol {
+            IpNextHeaderProtocols::Tcp
+                if tcp_flags & (TcpFlags::SYN | TcpFlags::ACK) == TcpFlags::SYN =>
+            {
+                State::SynSent
+            }
+            IpNextHeaderProtocols::Tcp => State::Established,
+            _ => State::Unreplied,
+        };
+        Connection {
+            original,
+            reply,
+            state,
+            fins: [false; 2],
+            expires: now,
+            packets: [0; 2],
+            bytes: [0; 2],
+        }
+    }
+
+    /// Advances the state by a packet, and extends the lifetime.
+    fn update(&mut self, tcp_flags: u16, length: usize, is_reply: bool, now: Instant) {
+        let direction = is_reply as usize;
+        self.packets[direction] += 1;
+        self.bytes[direction] += length as u64;
+
+        self.state = match self.state {
+            State::Unreplied | State::Assured if is_reply => State::Assured,
+            State::Unreplied | State::Assured => self.state,
+            _ if tcp_flags & TcpFlags::RST != 0 => State::Close,
+            _ if tcp_flags & TcpFlags::FIN != 0 => {
+                self.fins[direction] = true;
+                if self.fins == [true; 2] {
+                    State::TimeWait
+                } else {
+                    State::FinWait
+                }
+            }
+            State::SynSent
+                if is_reply
+                    && tcp_flags & (TcpFlags::SYN | TcpFlags::ACK)
+                        == TcpFlags::SYN | TcpFlags::ACK =>
+            {
+                State::SynReceived
+            }
+            State::SynReceived if !is_reply && tcp_flags & TcpFlags::ACK != 0 => State::Established,
+            state => state,
+        };
+
+        self.expires = now + self.timeout();
+    }
+
+    /// Whether the TCP handshake hasn't completed.
+    fn is_half_open(&self) -> bool {
+        matches!(self.state, State::SynSent | State::SynReceived)
+    }
+
+    fn timeout(&self) -> Duration {
+        match self.state {
+            State::SynSent => TCP_SYN_SENT_TIMEOUT,
+            State::SynReceived => TCP_SYN_RECEIVED_TIMEOUT,
+            State::Established => TCP_ESTABLISHED_TIMEOUT,
+            State::FinWait => TCP_FIN_WAIT_TIMEOUT,
+            State::TimeWait => TCP_TIME_WAIT_TIMEOUT,
+            State::Close => TCP_CLOSE_TIMEOUT,
+            _ if self.original.protocol == IpNextHeaderProtocols::Icmp => ICMP_TIMEOUT,
+            State::Unreplied => UDP_UNREPLIED_TIMEOUT,
+            State::Assured => UDP_ASSURED_TIMEOUT,
+        }
+    }
+
+    /// The tuple packets in the direction are rewritten to.
+    fn target(&self, is_reply: bool) -> Tuple {
+        if is_reply {
+            self.original.reverse()
+        } else {
+            self.reply.reverse()
+        }
+    }
+}
+
+/// The connections of the flows forwarded by Ipv4Handler, keyed by their original tuples.
+pub(crate) struct ConnectionTable {
+    connections: HashMap<Tuple, Connection>,
+    /// The original tuples keyed by the reply tuples.
+    replies: HashMap<Tuple, Tuple>,
+    max_connections: usize,
+    /// The number of TCP connections whose handshake hasn't completed.
+    half_open: usize,
+    max_half_open: Option<usize>,
+    /// Rate limiters of new connections keyed by the source address.
+    per_source: HashMap<Ipv4Addr, TokenBucket>,
+    per_source_limit: Option<RateLimit>,
+    last_eviction: Instant,
+    /// One in this many new connections dropped by the limits is logged.
+    log_every: Option<u64>,
+    /// New connections dropped by the limits.
+    limited: u64,
+    /// The number of new connections dropped by the limits before the next one is logged.
+    until_logged: u64,
+    /// The datagrams whose later fragments are dropped, and when they are forgotten.
+    dropped_datagrams: HashMap<DatagramKey, Instant>,
+}
+
+impl ConnectionTable {
+    pub(crate) fn new(config: &Config) -> Self {
+        ConnectionTable {
+            connections: HashMap::new(),
+            replies: HashMap::new(),
+            max_connections: config.conntrack.max_connections,
+            half_open: 0,
+            max_half_open: config.conntrack.max_half_open,
+            per_source: HashMap::new(),
+            per_source_limit: config.conntrack.per_source_rate_limit,
+            last_eviction: Instant::now(),
+            log_every: config.conntrack.log_every,
+            limited: 0,
+            until_logged: 0,
+            dropped_datagrams: HashMap::new(),
+        }
+    }
+
+    /// Returns the tuple a packet of a tracked connection is to be rewritten to, which is the
+    /// tuple of the packet unless the connection is translated. The connection isn't updated, as
+    /// the packet may yet be dropped.
+    pub(crate) fn lookup(&self, tuple: &Tuple) -> Option<Tuple> {
+        let (original, is_reply) = self.find(tuple)?;
+        Some(self.connections.get(&original)?.target(is_reply))
+    }
+
+    /// The original tuple of the connection the packet belongs to, and whether it is a reply.
+    fn find(&self, tuple: &Tuple) -> Option<(Tuple, bool)> {
+        match self.replies.get(tuple) {
+            Some(original) => Some((*original, true)),
+            None if self.connections.contains_key(tuple) => Some((*tuple, false)),
+            None => None,
+        }
+    }
+
+    /// Advances the state of the connection of a forwarded packet by it.
+    pub(crate) fn update(&mut self, tuple: &Tuple, tcp_flags: u16, length: usize, now: Instant) {
+        let (original, is_reply) = match self.find(tuple) {
+            Some(found) => found,
+            None => return,
+        };
+        if let Some(connection) = self.connections.get_mut(&original) {
+            let was_half_open = connection.is_half_open();
+            connection.update(tcp_flags, length, is_reply, now);
+            if was_half_open && !connection.is_half_open() {
+                self.half_open -= 1;
+            }
+        }
+    }
+
+    /// Whether a packet with the tuple would be taken for one of a tracked connection.
+    pub(crate) fn is_in_use(&self, tuple: &Tuple) -> bool {
+        self.replies.contains_key(tuple) || self.connections.contains_key(tuple)
+    }
+
+    /// Starts tracking a connection by its first packet. `reply` is the tuple replies are
+    /// expected with.
+    pub(crate) fn insert(
+        &mut self,
+        original: Tuple,
+        reply: Tuple,
+        tcp_flags: u16,
+        length: usize,
+        now: Instant,
+    ) -> Result<(), DropReason> {
+        if self.is_in_use(&reply) {
+            return Err(DropReason::TupleInUse);
+        }
+
+        let mut connection = Connection::new(original, reply, tcp_flags, now);
+        connection.update(tcp_flags, length, false, now);
+        if connection.is_half_open() && self.max_half_open.is_some_and(|max| self.half_open >= max)
+        {
+            self.sample(&original, DropReason::HalfOpenLimit);
+            return Err(DropReason::HalfOpenLimit);
+        }
+        if self.connections.len() >= self.max_connections {
+            self.expire(now);
+            if self.connections.len() >= self.max_connections {
+                self.sample(&original, DropReason::ConnectionTableFull);
+                return Err(DropReason::ConnectionTableFull);
+            }
+        }
+        // Last, so that connections dropped by the other limits don't use up the source's rate.
+        if !self.try_acquire(original.source.0, now) {
+            self.sample(&original, DropReason::NewConnectionRate);
+            return Err(DropReason::NewConnectionRate);
+        }
+
+        debug!("New connection {:?} <-> {:?}", original, reply);
+        if connection.is_half_open() {
+            self.half_open += 1;
+        }
+        self.connections.insert(original, connection);
+        self.replies.insert(reply, original);
+        Ok(())
+    }
+
+    /// Returns whether the source may start a new connection now.
+    fn try_acquire(&mut self, source: Ipv4Addr, now: Instant) -> bool {
+        let limit = match self.per_source_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        if !self.per_source.contains_key(&source)
+            && self.per_source.len() >= MAX_PER_SOURCE_LIMITERS
+        {
+            self.evict_idle(now);
+            if self.per_source.len() >= MAX_PER_SOURCE_LIMITERS {
+                return false;
+            }
+        }
+
+        self.per_source
+            .entry(source)
+            .or_insert_with(|| TokenBucket::new(limit.rate, limit.burst))
+            .try_take()
+    }
+
+    /// Evicts the idle per-source limiters, at most once per eviction interval, so that a flood
+    /// from many sources doesn't scan them for every new connection.
+    fn evict_idle(&mut self, now: Instant) {
+        if now.duration_since(self.last_eviction) < PER_SOURCE_EVICTION_INTERVAL {
+            return;
+        }
+        self.last_eviction = now;
+        self.per_source
+            .retain(|_, bucket| bucket.idle_for() < PER_SOURCE_LIMITER_IDLE_TIMEOUT);
+    }
+
+    /// Logs one in `log_every` new connections dropped by the limits.
+    fn sample(&mut self, tuple: &Tuple, reason: DropReason) {
+        self.limited += 1;
+        let log_every = match self.log_every {
+            Some(log_every) => log_every,
+            None => return,
+        };
+        if self.until_logged == 0 {
+            info!(
+                "Dropped a new connection {:?}: {:?} ({} dropped by the limits so far)",
+                tuple, reason, self.limited
+            );
+            self.until_logged = log_every;
+        }
+        self.until_logged -= 1;
+    }
+
+    /// Drops the later fragments of the datagram with the identification whose first fragment has
+    /// the tuple. They carry no ports to find the connection by, so the fragments of translated
+    /// connections would otherwise be forwarded untranslated.
+    pub(crate) fn drop_fragments(&mut self, first: &Tuple, identification: u16, now: Instant) {
+        if self.dropped_datagrams.len() < MAX_DROPPED_DATAGRAMS {
+            let key = (
+                first.source.0,
+                first.destination.0,
+                identification,
+                first.protocol.0,
+            );
+            self.dropped_datagrams
+                .insert(key, now + DROPPED_DATAGRAM_TIMEOUT);
+        }
+    }
+
+    /// Whether the packet is a later fragment of a datagram being dropped.
+    pub(crate) fn is_dropped_fragment(&self, packet: &Ipv4Packet) -> bool {
+        packet.get_fragment_offset() != 0
+            && self.dropped_datagrams.contains_key(&datagram_key(packet))
+    }
+
+    /// Removes the expired connections.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.dropped_datagrams.retain(|_, expires| *expires > now);
+
+        let replies = &mut self.replies;
+        let half_open = &mut self.half_open;
+        self.connections.retain(|_, connection| {
+            let alive = connection.expires > now;
+            if !alive {
+                if connection.is_half_open() {
+                    *half_open -= 1;
+                }
+                debug!(
+                    "Connection expired in {:?}: {:?}, {}/{} packets, {}/{} bytes",
+                    connection.state,
+                    connection.original,
+                    connection.packets[0],
+                    connection.packets[1],
+                    connection.bytes[0],
+                    connection.bytes[1],
+                );
+                replies.remove(&connection.reply);
+            }
+            alive
+        });
+    }
+
+    /// Logs the number of connections by state, e.g. on shutdown.
+    pub(crate) fn log(&self, handler: &str) {
+        let mut counts: BTreeMap<State, usize> = BTreeMap::new();
+        for connection in self.connections.values() {
+            *counts.entry(connection.state).or_default() += 1;
+        }
+        for (state, count) in &counts {
+            info!("{} tracks {} connections: {:?}", handler, count, state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 2);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 2, 2);
+
+    fn tuple(protocol: IpNextHeaderProtocol, source: Ipv4Addr, source_port: u16) -> Tuple {
+        Tuple {
+            protocol,
+            source: (source, source_port),
+            destination: (SERVER, 80),
+        }
+    }
+
+    fn table(configure: impl FnOnce(&mut Config)) -> ConnectionTable {
+        let mut config = Config::default();
+        configure(&mut config);
+        ConnectionTable::new(&config)
+    }
+
+    fn insert(
+        table: &mut ConnectionTable,
+        tuple: Tuple,
+        tcp_flags: u16,
+        now: Instant,
+    ) -> Result<(), DropReason> {
+        table.insert(tuple, tuple.reverse(), tcp_flags, 60, now)
+    }
+
+    fn new_connection(protocol: IpNextHeaderProtocol, tcp_flags: u16, now: Instant) -> Connection {
+        let original = tuple(protocol, CLIENT, 40000);
+        let mut connection = Connection::new(original, original.reverse(), tcp_flags, now);
+        connection.update(tcp_flags, 60, false, now);
+        connection
+    }
+
+    #[test]
+    fn tracks_tcp_handshakes() {
+        let now = Instant::now();
+        let mut connection = new_connection(IpNextHeaderProtocols::Tcp, TcpFlags::SYN, now);
+        assert_eq!(connection.state, State::SynSent);
+        assert_eq!(connection.expires, now + TCP_SYN_SENT_TIMEOUT);
+
+        // Only a SYN-ACK in the reply direction answers the SYN.
+        connection.update(TcpFlags::SYN | TcpFlags::ACK, 60, false, now);
+        assert_eq!(connection.state, State::SynSent);
+        connection.update(TcpFlags::ACK, 52, true, now);
+        assert_eq!(connection.state, State::SynSent);
+        connection.update(TcpFlags::SYN | TcpFlags::ACK, 60, true, now);
+        assert_eq!(connection.state, State::SynReceived);
+        assert_eq!(connection.expires, now + TCP_SYN_RECEIVED_TIMEOUT);
+
+        // Only an ACK in the original direction completes the handshake.
+        connection.update(TcpFlags::ACK, 52, true, now);
+        assert_eq!(connection.state, State::SynReceived);
+        connection.update(TcpFlags::ACK, 52, false, now);
+        assert_eq!(connection.state, State::Established);
+        assert_eq!(connection.expires, now + TCP_ESTABLISHED_TIMEOUT);
+
+        assert_eq!(connection.packets, [3, 3]);
+        assert_eq!(connection.bytes, [172, 164]);
+    }
+
+    #[test]
+    fn tracks_tcp_teardowns() {
+        let now = Instant::now();
+        let mut connection = new_connection(IpNextHeaderProtocols::Tcp, TcpFlags::ACK, now);
+        assert_eq!(connection.state, State::Established);
+
+        connection.update(TcpFlags::FIN | TcpFlags::ACK, 52, false, now);
+        assert_eq!(connection.state, State::FinWait);
+        assert_eq!(connection.expires, now + TCP_FIN_WAIT_TIMEOUT);
+        // Retransmitted FINs need the other direction to close.
+        connection.update(TcpFlags::FIN | TcpFlags::ACK, 52, false, now);
+        assert_eq!(connection.state, State::FinWait);
+        connection.update(TcpFlags::FIN | TcpFlags::ACK, 52, true, now);
+        assert_eq!(connection.state, State::TimeWait);
+        assert_eq!(connection.expires, now + TCP_TIME_WAIT_TIMEOUT);
+
+        // A RST closes the connection in any state.
+        for tcp_flags in [TcpFlags::SYN, TcpFlags::ACK] {
+            let mut reset = new_connection(IpNextHeaderProtocols::Tcp, tcp_flags, now);
+            reset.update(TcpFlags::RST, 40, true, now);
+            assert_eq!(reset.state, State::Close);
+            assert_eq!(reset.expires, now + TCP_CLOSE_TIMEOUT);
+        }
+        connection.update(TcpFlags::RST | TcpFlags::ACK, 40, false, now);
+        assert_eq!(connection.state, State::Close);
+    }
+
+    #[test]
+    fn tracks_udp_and_icmp_flows() {
+        let now = Instant::now();
+        for (protocol, unreplied_timeout, assured_timeout) in [
+            (
+                IpNextHeaderProtocols::Udp,
+                UDP_UNREPLIED_TIMEOUT,
+                UDP_ASSURED_TIMEOUT,
+            ),
+            (IpNextHeaderProtocols::Icmp, ICMP_TIMEOUT, ICMP_TIMEOUT),
+        ] {
+            let mut connection = new_connection(protocol, 0, now);
+            assert_eq!(connection.state, State::Unreplied);
+            assert_eq!(connection.expires, now + unreplied_timeout);
+
+            connection.update(0, 60, false, now);
+            assert_eq!(connection.state, State::Unreplied);
+            connection.update(0, 60, true, now);
+            assert_eq!(connection.state, State::Assured);
+            assert_eq!(connection.expires, now + assured_timeout);
+            connection.update(0, 60, false, now);
+            assert_eq!(connection.state, State::Assured);
+        }
+    }
+
+    #[test]
+    fn limits_half_open_connections() {
+        let mut table = table(|config| config.conntrack.max_half_open = Some(2));
+        let now = Instant::now();
+        let first = tuple(IpNextHeaderProtocols::Tcp, CLIENT, 40000);
+        let second = tuple(IpNextHeaderProtocols::Tcp, CLIENT, 40001);
+        let third = tuple(IpNextHeaderProtocols::Tcp, CLIENT, 40002);
+        insert(&mut table, first, TcpFlags::SYN, now).unwrap();
+        insert(&mut table, second, TcpFlags::SYN, now).unwrap();
+        assert_eq!(
+            insert(&mut table, third, TcpFlags::SYN, now),
+            Err(DropReason::HalfOpenLimit)
+        );
+
+        // Connections picked up in the middle aren't half-open.
+        let established = tuple(IpNextHeaderProtocols::Tcp, CLIENT, 40003);
+        insert(&mut table, established, TcpFlags::ACK, now).unwrap();
+
+        // The handshake completes with the ACK of the SYN-ACK.
+        table.update(&first.reverse(), TcpFlags::SYN | TcpFlags::ACK, 60, now);
+        assert_eq!(table.half_open, 2);
+        table.update(&first, TcpFlags::ACK, 52, now);
+        assert_eq!(table.half_open, 1);
+        insert(&mut table, third, TcpFlags::SYN, now).unwrap();
+        assert_eq!(table.half_open, 2);
+
+        // Half-open connections expire sooner.
+        table.expire(now + TCP_SYN_SENT_TIMEOUT);
+        assert_eq!(table.half_open, 0);
+        assert_eq!(table.connections.len(), 2);
+    }
+
+    #[test]
+    fn caps_per_source_limiters() {
+        let mut table = table(|config| {
+            config.conntrack.per_source_rate_limit = Some(RateLimit { rate: 1, burst: 2 })
+        });
+        let now = Instant::now();
+        for i in 0..MAX_PER_SOURCE_LIMITERS as u32 {
+            let source = Ipv4Addr::from(u32::from(CLIENT) + i);
+            insert(
+                &mut table,
+                tuple(IpNextHeaderProtocols::Udp, source, 40000),
+                0,
+                now,
+            )
+            .unwrap();
+        }
+
+        // None of the limiters is idle yet.
+        let other = Ipv4Addr::new(10, 0, 0, 1);
+        assert_eq!(
+            insert(
+                &mut table,
+                tuple(IpNextHeaderProtocols::Udp, other, 40000),
+                0,
+                now
+            ),
+            Err(DropReason::NewConnectionRate)
+        );
+        assert_eq!(table.per_source.len(), MAX_PER_SOURCE_LIMITERS);
+
+        // The sources with a limiter go on up to their burst.
+        insert(
+            &mut table,
+            tuple(IpNextHeaderProtocols::Udp, CLIENT, 40001),
+            0,
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            insert(
+                &mut table,
+                tuple(IpNextHeaderProtocols::Udp, CLIENT, 40002),
+                0,
+                now
+            ),
+            Err(DropReason::NewConnectionRate)
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use pnet_packet::ipv4::Ipv4Packet;
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+#[derive(Debug)]
+pub(crate) enum ControlPlaneEvent {
+    /// A copy of a received IPv4 packet the router should examine, e.g. one with the router alert
+    /// option (RFC 2113).
+    Punted {
+        /// The interface index (operating system specific) the packet arrived on.
+        interface_index: u32,
+        packet: Vec<u8>,
+    },
+    Shutdown,
+}
+
+pub(crate) async fn spawn_control_plane_handler(
+    receiver: UnboundedReceiver<ControlPlaneEvent>,
+) -> JoinHandle<()> {
+    ControlPlaneHandler {
+        receiver,
+        punted: BTreeMap::new(),
+    }
+    .spawn()
+}
+
+/// The consumer of the packets punted by the data plane. No control protocol is implemented yet,
+/// so the packets are only logged and counted.
+struct ControlPlaneHandler {
+    receiver: UnboundedReceiver<ControlPlaneEvent>,
+    /// The number of punted packets keyed by the interface index.
+    punted: BTreeMap<u32, u64>,
+}
+
+impl ControlPlaneHandler {
+    fn spawn(mut self) -> JoinHandle<()> {
+        let fut = async move {
+            debug!("Started ControlPlaneHandler");
+
+            loop {
+                if let Some(event) = self.receiver.recv().await {
+                    match event {
+                        ControlPlaneEvent::Punted {
+                            interface_index,
+                            packet,
+                        } => {
+                            debug!(
+                                "Punted a packet from the interface {}: {:?}",
+                                interface_index,
+                                Ipv4Packet::new(&packet)
+                            );
+                            *self.punted.entry(interface_index).or_default() += 1;
+                        }
+                        ControlPlaneEvent::Shutdown => {
+                            for (interface_index, count) in &self.punted {
+                                info!(
+                                    "ControlPlaneHandler received {} punted packets from the interface {}",
+                                    count, interface_index
+                                );
+                            }
+                            return;
+                        }
+                    }
+                }
+            }
+        };
+
+        tokio::runtime::Handle::current().spawn(fut)
+    }
+}
@@ -0,0 +1,85 @@
+use crate::martian::MartianReason;
+use std::collections::BTreeMap;
+use tracing::info;
+
+/// Reasons a packet is dropped by a handler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum DropReason {
+    TtlExceeded,
+    NoRoute,
+    BlackholeRoute,
+    UnreachableRoute,
+    ProhibitRoute,
+    /// Failed the unicast reverse path forwarding check.
+    ReversePathCheck,
+    Martian(MartianReason),
+    MalformedHeader,
+    /// An IPv4 header whose checksum doesn't match (RFC 1812 5.2.2).
+    HeaderChecksum,
+    MalformedOptions,
+    /// A source routed packet, with source routing disabled or the route failed.
+    SourceRoute,
+    /// A subnet-directed broadcast from another network, with forwarding disabled.
+    DirectedBroadcast,
+    /// A link-layer broadcast which isn't an IP broadcast or multicast (RFC 1812 5.3.4).
+    LinkLayerBroadcast,
+    HopLimitExceeded,
+    /// Larger than the MTU of the egress interface, and either IPv6, which routers never
+    /// fragment, or translated from IPv6 with DF set.
+    PacketTooBig,
+    /// An IPv6 packet with an unspecified, loopback or multicast source, or an unspecified or
+    /// loopback destination (RFC 4291 2.5.2, 2.5.3).
+    InvalidAddress,
+    /// An IPv6 packet with a link-local source or destination, which must not leave the link.
+    LinkLocalScope,
+    /// An IPv6 packet addressed to us, discarded while processing its extension headers.
+    ExtensionHeader,
+    /// An IPv6 fragment addressed to us, as we don't reassemble.
+    Fragmented,
+    /// A packet addressed to us for an upper-layer protocol we don't handle.
+    UnsupportedProtocol,
+    /// No translation matches a packet to be translated, e.g. one arriving unsolicited at a NAT
+    /// pool address.
+    NoTranslation,
+    /// A packet the translator can't handle, e.g. a fragment or an unsupported ICMP type.
+    Untranslatable,
+    /// No address and port is left in the NAT pool for a new binding.
+    PoolExhausted,
+    /// A fragment of a translated connection. Only the first fragment carries the ports to
+    /// translate by, and we don't reassemble.
+    TranslatedFragment,
+    /// A new connection whose replies would be taken for those of a tracked connection.
+    TupleInUse,
+    /// A new connection while the connection table is full.
+    ConnectionTableFull,
+    /// A new connection over the rate limit of its source.
+    NewConnectionRate,
+    /// A new TCP connection while too many are half-open, e.g. during a SYN flood.
+    HalfOpenLimit,
+    /// Denied by an access-control list.
+    AccessList,
+    /// A new connection between zones not allowed by the zone pair policy.
+    ZonePolicy,
+    /// A packet between zones which neither starts nor belongs to a tracked connection, e.g. a
+    /// stray TCP ACK, or an ICMP error about no tracked connection.
+    InvalidState,
+}
+
+/// Counts dropped packets by reason.
+#[derive(Debug, Default)]
+pub(crate) struct DropCounters {
+    counts: BTreeMap<DropReason, u64>,
+}
+
+impl DropCounters {
+    pub(crate) fn increment(&mut self, reason: DropReason) {
+        *self.counts.entry(reason).or_default() += 1;
+    }
+
+    /// Logs the counts, e.g. on shutdown.
+    pub(crate) fn log(&self, handler: &str) {
+        for (reason, count) in &self.counts {
+            info!("{} dropped {} packets: {:?}", handler, count, reason);
+        }
+    }
+}
@@ -1,11 +1,15 @@
 use crate::arp::ArpHandlerEvent;
 use crate::ipv4::Ipv4HandlerEvent;
+use crate::ipv6::Ipv6HandlerEvent;
 use async_stream::stream;
 use futures_util::{pin_mut, StreamExt};
-use pnet_datalink::{Config, DataLinkReceiver, NetworkInterface};
+use pnet_datalink::{Config, DataLinkReceiver, DataLinkSender, MacAddr, NetworkInterface};
 use pnet_packet::arp::ArpPacket;
+use pnet_packet::ethernet::{EtherType, MutableEthernetPacket};
 use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::ipv6::Ipv6Packet;
 use pnet_packet::Packet;
+use std::collections::HashMap;
 use std::time::Duration;
 use tokio::select;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
@@ -14,25 +18,41 @@ use tracing::{debug, error};
 
 pub(crate) const ETHERNET_TYPE_IP: u16 = 0x0800;
 pub(crate) const ETHERNET_TYPE_ARP: u16 = 0x0806;
+pub(crate) const ETHERNET_TYPE_IPV6: u16 = 0x86DD;
 
 pub(crate) const ETHERNET_ADDRESS_LENGTH: u8 = 6;
 
+const ETHERNET_HEADER_LENGTH: usize = 14;
+
 #[derive(Debug)]
 pub(crate) enum EthernetHandlerEvent {
+    /// An event let EthernetHandler to send a frame via the interface.
+    SendFrame(OutgoingFrame),
     Shutdown,
 }
 
+#[derive(Debug)]
+pub(crate) struct OutgoingFrame {
+    /// The interface index (operating system specific).
+    pub(crate) interface_index: u32,
+    pub(crate) destination: MacAddr,
+    pub(crate) ethertype: u16,
+    pub(crate) payload: Vec<u8>,
+}
+
 pub(crate) async fn spawn_ethernet_handler(
     interfaces: &[NetworkInterface],
     receiver: UnboundedReceiver<EthernetHandlerEvent>,
     sender_arp: UnboundedSender<ArpHandlerEvent>,
     sender_ipv4: UnboundedSender<Ipv4HandlerEvent>,
+    sender_ipv6: UnboundedSender<Ipv6HandlerEvent>,
 ) -> JoinHandle<()> {
     EthernetHandler {
         interfaces: interfaces.to_owned(),
         receiver,
         sender_arp,
         sender_ipv4,
+        sender_ipv6,
     }
     .spawn()
 }
@@ -42,6 +62,7 @@ struct EthernetHandler {
     receiver: UnboundedReceiver<EthernetHandlerEvent>,
     sender_arp: UnboundedSender<ArpHandlerEvent>,
     sender_ipv4: UnboundedSender<Ipv4HandlerEvent>,
+    sender_ipv6: UnboundedSender<Ipv6HandlerEvent>,
 }
 
 struct Receiver {
@@ -67,11 +88,12 @@ impl EthernetHandler {
             ..Default::default()
         };
 
+        let mut senders = HashMap::new();
         let mut receivers = self
             .interfaces
             .iter()
             .map(|i| {
-                let (_tx, rx) = match pnet_datalink::channel(i, config) {
+                let (tx, rx) = match pnet_datalink::channel(i, config) {
                     Ok(pnet_datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
                     Ok(_) => panic!("Unhandled channel type"),
                     Err(e) => panic!(
@@ -79,6 +101,7 @@ impl EthernetHandler {
                         e
                     ),
                 };
+                senders.insert(i.index, tx);
 
                 Receiver {
                     interface_index: i.index,
@@ -137,13 +160,20 @@ impl EthernetHandler {
                                 // pnet::packet::ipv4::Ipv4Packet
                                 // https://docs.rs/pnet/latest/pnet/packet/ipv4/struct.Ipv4Packet.html
                                 if let Some(ipv4) =
-                                    Ipv4Packet::owned(received_packet.ethernet_packet.packet().to_vec())
+                                    Ipv4Packet::owned(received_packet.ethernet_packet.payload().to_vec())
                                 {
                                     debug!("Received an IP packet: {:?}", ipv4);
 
                                     if let Err(e) = self
                                         .sender_ipv4
-                                        .send(Ipv4HandlerEvent::ReceivedPacket(ipv4))
+                                        .send(Ipv4HandlerEvent::ReceivedPacket {
+                                            interface_index: received_packet.interface_index,
+                                            packet: ipv4,
+                                            link_broadcast: received_packet
+                                                .ethernet_packet
+                                                .get_destination()
+                                                .is_broadcast(),
+                                        })
                                     {
                                         error!("Failed to send the IP packet to Ipv4Handler: {}", e);
                                     }
@@ -155,11 +185,14 @@ impl EthernetHandler {
                                 // pnet::packet::arp::ArpPacket
                                 // https://docs.rs/pnet/latest/pnet/packet/arp/struct.ArpPacket.html
                                 if let Some(arp) =
-                                    ArpPacket::owned(received_packet.ethernet_packet.packet().to_vec())
+                                    ArpPacket::owned(received_packet.ethernet_packet.payload().to_vec())
                                 {
                                     debug!("Received an ARP packet: {:?}", arp);
 
-                                    if let Err(e) = self.sender_arp.send(ArpHandlerEvent::ReceivedPacket(arp))
+                                    if let Err(e) = self.sender_arp.send(ArpHandlerEvent::ReceivedPacket {
+                                        interface_index: received_packet.interface_index,
+                                        packet: arp,
+                                    })
                                     {
                                         error!("Failed to send the ARP packet to ArpHandler: {}", e);
                                     }
@@ -167,11 +200,35 @@ impl EthernetHandler {
                                     error!("Received a packet whose ETHERNET_TYPE is ARP but we couldn't encode it to ARP packet.");
                                 }
                             }
+                            ETHERNET_TYPE_IPV6 => {
+                                // pnet::packet::ipv6::Ipv6Packet
+                                // https://docs.rs/pnet/latest/pnet/packet/ipv6/struct.Ipv6Packet.html
+                                if let Some(ipv6) =
+                                    Ipv6Packet::owned(received_packet.ethernet_packet.payload().to_vec())
+                                {
+                                    debug!("Received an IPv6 packet: {:?}", ipv6);
+
+                                    if let Err(e) = self
+                                        .sender_ipv6
+                                        .send(Ipv6HandlerEvent::ReceivedPacket {
+                                            interface_index: received_packet.interface_index,
+                                            packet: ipv6,
+                                        })
+                                    {
+                                        error!("Failed to send the IPv6 packet to Ipv6Handler: {}", e);
+                                    }
+                                } else {
+                                    error!("Received a packet whose ETHERNET_TYPE is IPv6 but we couldn't encode it to IPv6 packet.");
+                                }
+                            }
                             _ => {}
                         }
                     }
                     Some(event) = self.receiver.recv() => {
                         match event {
+                            EthernetHandlerEvent::SendFrame(frame) => {
+                                self.send_frame(&mut senders, frame)
+                            }
                             EthernetHandlerEvent::Shutdown => return,
                         }
                     }
@@ -182,12 +239,50 @@ impl EthernetHandler {
         tokio::runtime::Handle::current().spawn(fut)
     }
 
-    /// Determine if we handle the packet.
+    fn send_frame(
+        &self,
+        senders: &mut HashMap<u32, Box<dyn DataLinkSender>>,
+        frame: OutgoingFrame,
+    ) {
+        let interface = match self
+            .interfaces
+            .iter()
+            .find(|&i| i.index == frame.interface_index)
+        {
+            Some(interface) => interface,
+            None => {
+                error!("Unknown interface index: {}", frame.interface_index);
+                return;
+            }
+        };
+
+        let mut buffer = vec![0u8; ETHERNET_HEADER_LENGTH + frame.payload.len()];
+        let mut ethernet_packet =
+            MutableEthernetPacket::new(&mut buffer).expect("buffer should be large enough");
+        ethernet_packet.set_destination(frame.destination);
+        ethernet_packet.set_source(interface.mac.expect("should have mac address"));
+        ethernet_packet.set_ethertype(EtherType(frame.ethertype));
+        ethernet_packet.set_payload(&frame.payload);
+
+        let tx = senders
+            .get_mut(&frame.interface_index)
+            .expect("should have the sender");
+        match tx.send_to(&buffer, None) {
+            Some(Ok(())) => debug!("Sent a frame via {}", interface.name),
+            Some(Err(e)) => error!("Failed to send a frame via {}: {}", interface.name, e),
+            None => error!("Failed to send a frame via {}", interface.name),
+        }
+    }
+
+    /// Determine if we handle the packet. IPv6 multicasts are left to Ipv6Handler to filter by
+    /// the groups.
     fn should_handle_packet(
         ethernet_packet: &pnet_packet::ethernet::EthernetPacket,
         interface: &NetworkInterface,
     ) -> bool {
-        ethernet_packet.get_destination() == interface.mac.expect("should have mac address")
-            || ethernet_packet.get_destination().is_broadcast()
+        let destination = ethernet_packet.get_destination();
+        destination == interface.mac.expect("should have mac address")
+            || destination.is_broadcast()
+            || (destination.0 == 0x33 && destination.1 == 0x33)
     }
 }
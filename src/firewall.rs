@@ -0,0 +1,78 @@
+use crate::acl::{AccessList, Verdict};
+use crate::config::{Config, InterfaceConfig, ZonePairPolicy};
+use pnet_packet::ipv4::Ipv4Packet;
+use std::collections::HashMap;
+
+enum Policy {
+    Permit,
+    Deny,
+    Acl(AccessList),
+}
+
+/// Zone-based policy of the new connections forwarded between interfaces. Connections between
+/// interfaces of the same zone are permitted unless the zone pair says otherwise, and the others
+/// are denied unless permitted by their zone pair. Interfaces without a zone are only
+/// inspected when forwarding to or from one with a zone, which is denied.
+pub(crate) struct Firewall {
+    /// The zones keyed by the interface index (operating system specific).
+    zones: HashMap<u32, String>,
+    policies: HashMap<(String, String), Policy>,
+}
+
+impl Firewall {
+    /// `interface_configs` is keyed by the interface index (operating system specific).
+    pub(crate) fn new(config: &Config, interface_configs: &HashMap<u32, InterfaceConfig>) -> Self {
+        let zones = interface_configs
+            .iter()
+            .filter_map(|(&index, c)| c.zone.clone().map(|zone| (index, zone)))
+            .collect();
+
+        let policies = config
+            .zone_pairs
+            .iter()
+            .map(|(pair, policy)| {
+                let policy = match policy {
+                    ZonePairPolicy::Permit => Policy::Permit,
+                    ZonePairPolicy::Deny => Policy::Deny,
+                    ZonePairPolicy::Acl(name) => {
+                        let rules = config.acls.get(name).expect("ACL names are validated");
+                        Policy::Acl(AccessList::new(name, rules.clone()))
+                    }
+                };
+                (pair.clone(), policy)
+            })
+            .collect();
+
+        Firewall { zones, policies }
+    }
+
+    /// Whether the packets forwarded between the interfaces are inspected.
+    pub(crate) fn inspects(&self, ingress: u32, egress: u32) -> bool {
+        self.zones.contains_key(&ingress) || self.zones.contains_key(&egress)
+    }
+
+    /// Decides whether a new connection may be forwarded between the interfaces.
+    pub(crate) fn check(&mut self, ingress: u32, egress: u32, packet: &Ipv4Packet) -> Verdict {
+        let (from, to) = match (self.zones.get(&ingress), self.zones.get(&egress)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Verdict::Deny,
+        };
+
+        match self.policies.get_mut(&(from.clone(), to.clone())) {
+            Some(Policy::Permit) => Verdict::Permit,
+            Some(Policy::Deny) => Verdict::Deny,
+            Some(Policy::Acl(acl)) => acl.evaluate(packet),
+            None if from == to => Verdict::Permit,
+            None => Verdict::Deny,
+        }
+    }
+
+    /// Logs the hit counters of the access-control lists of the zone pairs, e.g. on shutdown.
+    pub(crate) fn log(&self, handler: &str) {
+        for ((from, to), policy) in &self.policies {
+            if let Policy::Acl(acl) = policy {
+                acl.log(handler, &format!("zone pair {} {}", from, to));
+            }
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use crate::config::RateLimit;
+use crate::ipv4::{build_ipv4_packet, IPV4_HEADER_LENGTH};
+use crate::rate_limit::TokenBucket;
+use pnet_packet::icmp::{destination_unreachable, time_exceeded, IcmpCode, IcmpType, IcmpTypes};
+use pnet_packet::icmp::{IcmpPacket, MutableIcmpPacket};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::Packet;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// The length of the ICMP header, including the type specific second word.
+pub(crate) const ICMP_HEADER_LENGTH: usize = 8;
+
+/// ICMP error messages should not exceed the minimum IPv4 reassembly buffer size (RFC 1812
+/// 4.3.2.3).
+pub(crate) const ICMP_ERROR_MAX_LENGTH: usize = 576;
+
+/// An ICMP error to be sent back to the source of a packet.
+#[derive(Debug)]
+pub(crate) struct IcmpError {
+    pub(crate) icmp_type: IcmpType,
+    pub(crate) icmp_code: IcmpCode,
+    /// The second word of the ICMP header, whose meaning depends on the type.
+    pub(crate) rest_of_header: u32,
+}
+
+impl IcmpError {
+    pub(crate) fn destination_unreachable(icmp_code: IcmpCode) -> Self {
+        IcmpError {
+            icmp_type: IcmpTypes::DestinationUnreachable,
+            icmp_code,
+            rest_of_header: 0,
+        }
+    }
+
+    pub(crate) fn network_unreachable() -> Self {
+        Self::destination_unreachable(
+            destination_unreachable::IcmpCodes::DestinationNetworkUnreachable,
+        )
+    }
+
+    pub(crate) fn host_unreachable() -> Self {
+        Self::destination_unreachable(
+            destination_unreachable::IcmpCodes::DestinationHostUnreachable,
+        )
+    }
+
+    pub(crate) fn administratively_prohibited() -> Self {
+        Self::destination_unreachable(
+            destination_unreachable::IcmpCodes::CommunicationAdministrativelyProhibited,
+        )
+    }
+
+    /// `mtu` is the MTU of the next hop (RFC 1191 4).
+    pub(crate) fn fragmentation_needed(mtu: u16) -> Self {
+        IcmpError {
+            icmp_type: IcmpTypes::DestinationUnreachable,
+            icmp_code: destination_unreachable::IcmpCodes::FragmentationRequiredAndDFFlagSet,
+            rest_of_header: mtu as u32,
+        }
+    }
+
+    pub(crate) fn source_route_failed() -> Self {
+        Self::destination_unreachable(destination_unreachable::IcmpCodes::SourceRouteFailed)
+    }
+
+    /// `pointer` is the offset of the octet where the problem was detected.
+    pub(crate) fn parameter_problem(pointer: u8) -> Self {
+        IcmpError {
+            icmp_type: IcmpTypes::ParameterProblem,
+            icmp_code: IcmpCode(0),
+            rest_of_header: (pointer as u32) << 24,
+        }
+    }
+
+    /// Redirect datagrams for the host (RFC 1812 5.2.7.2).
+    pub(crate) fn redirect(gateway: Ipv4Addr) -> Self {
+        IcmpError {
+            icmp_type: IcmpTypes::RedirectMessage,
+            icmp_code: IcmpCode(1),
+            rest_of_header: u32::from(gateway),
+        }
+    }
+
+    pub(crate) fn time_exceeded() -> Self {
+        IcmpError {
+            icmp_type: IcmpTypes::TimeExceeded,
+            icmp_code: time_exceeded::IcmpCodes::TimeToLiveExceededInTransit,
+            rest_of_header: 0,
+        }
+    }
+}
+
+/// Builds an IPv4 packet carrying the ICMP error about the original packet. As much of the
+/// original packet is quoted as fits in the size limit.
+pub(crate) fn build_error_packet(
+    error: &IcmpError,
+    source: Ipv4Addr,
+    original: &Ipv4Packet,
+) -> Vec<u8> {
+    let original_length = (original.get_total_length() as usize).min(original.packet().len());
+    let quoted_length =
+        original_length.min(ICMP_ERROR_MAX_LENGTH - IPV4_HEADER_LENGTH - ICMP_HEADER_LENGTH);
+
+    let mut buffer = vec![0u8; ICMP_HEADER_LENGTH + quoted_length];
+    buffer[4..8].copy_from_slice(&error.rest_of_header.to_be_bytes());
+    buffer[ICMP_HEADER_LENGTH..].copy_from_slice(&original.packet()[..quoted_length]);
+
+    let mut icmp = MutableIcmpPacket::new(&mut buffer).expect("buffer should be large enough");
+    icmp.set_icmp_type(error.icmp_type);
+    icmp.set_icmp_code(error.icmp_code);
+    update_checksum(&mut icmp);
+
+    build_ipv4_packet(
+        source,
+        original.get_source(),
+        IpNextHeaderProtocols::Icmp,
+        &buffer,
+    )
+}
+
+/// Recomputes the checksum of the ICMP message.
+pub(crate) fn update_checksum(icmp: &mut MutableIcmpPacket) {
+    icmp.set_checksum(pnet_packet::icmp::checksum(&icmp.to_immutable()));
+}
+
+/// Whether an ICMP error may be sent about the packet (RFC 1812 4.3.2.7). Subnet-directed
+/// broadcasts and link-layer broadcasts are left to the caller, which knows the connected
+/// networks and how the packet arrived.
+pub(crate) fn may_send_error_about(original: &Ipv4Packet) -> bool {
+    let source = original.get_source();
+    let destination = original.get_destination();
+
+    // Non-initial fragments.
+    if original.get_fragment_offset() != 0 {
+        return false;
+    }
+
+    // Limited broadcasts and multicasts.
+    if destination.is_broadcast() || destination.is_multicast() {
+        return false;
+    }
+
+    // Sources which don't define a single host.
+    if source.is_unspecified()
+        || source.is_loopback()
+        || source.is_broadcast()
+        || source.is_multicast()
+        || source.octets()[0] >= 240
+    {
+        return false;
+    }
+
+    // ICMP errors. Query messages such as echo requests may be responded.
+    if original.get_next_level_protocol() == IpNextHeaderProtocols::Icmp {
+        return match IcmpPacket::new(original.payload()) {
+            Some(icmp) => !is_error(icmp.get_icmp_type()),
+            None => false,
+        };
+    }
+
+    true
+}
+
+pub(crate) fn is_error(icmp_type: IcmpType) -> bool {
+    matches!(
+        icmp_type,
+        IcmpTypes::DestinationUnreachable
+            | IcmpTypes::SourceQuench
+            | IcmpTypes::RedirectMessage
+            | IcmpTypes::TimeExceeded
+            | IcmpTypes::ParameterProblem
+    )
+}
+
+/// The default number of ICMP errors sent per second in total, and the burst size.
+const DEFAULT_GLOBAL_RATE_LIMIT: RateLimit = RateLimit {
+    rate: 1000,
+    burst: 50,
+};
+
+/// The default number of ICMP errors sent per second to a destination, and the burst size.
+const DEFAULT_PER_DESTINATION_RATE_LIMIT: RateLimit = RateLimit { rate: 1, burst: 6 };
+
+/// The maximum number of destinations tracked. Errors to new destinations are suppressed while
+/// the limiters of all of them are in use.
+const MAX_PER_DESTINATION_LIMITERS: usize = 1024;
+
+/// Per-destination limiters idle for longer than this are evicted.
+const PER_DESTINATION_LIMITER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often idle per-destination limiters may be looked for while the limiters are full.
+const PER_DESTINATION_EVICTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits the rate of ICMP or ICMPv6 errors we generate, both in total and per destination, so
+/// that a flood of bad traffic can't be amplified.
+pub(crate) struct IcmpErrorLimiter {
+    global: TokenBucket,
+    per_destination: HashMap<IpAddr, TokenBucket>,
+    per_destination_limit: RateLimit,
+    last_eviction: Instant,
+    suppressed_global: u64,
+    suppressed_per_destination: u64,
+}
+
+impl IcmpErrorLimiter {
+    pub(crate) fn new(global: Option<RateLimit>, per_destination: Option<RateLimit>) -> Self {
+        let global = global.unwrap_or(DEFAULT_GLOBAL_RATE_LIMIT);
+
+        IcmpErrorLimiter {
+            global: TokenBucket::new(global.rate, global.burst),
+            per_destination: HashMap::new(),
+            per_destination_limit: per_destination.unwrap_or(DEFAULT_PER_DESTINATION_RATE_LIMIT),
+            last_eviction: Instant::now(),
+            suppressed_global: 0,
+            suppressed_per_destination: 0,
+        }
+    }
+
+    /// Returns whether an ICMP error may be sent to the destination now. The per-destination limit
+    /// is checked first, so that the errors to a single destination can't use up the global one.
+    pub(crate) fn try_acquire(&mut self, destination: IpAddr) -> bool {
+        if !self.per_destination.contains_key(&destination)
+            && self.per_destination.len() >= MAX_PER_DESTINATION_LIMITERS
+        {
+            self.evict_idle();
+            if self.per_destination.len() >= MAX_PER_DESTINATION_LIMITERS {
+                self.suppressed_per_destination += 1;
+                return false;
+            }
+        }
+
+        let limit = &self.per_destination_limit;
+        if !self
+            .per_destination
+            .entry(destination)
+            .or_insert_with(|| TokenBucket::new(limit.rate, limit.burst))
+            .try_take()
+        {
+            self.suppressed_per_destination += 1;
+            return false;
+        }
+
+        if !self.global.try_take() {
+            self.suppressed_global += 1;
+            return false;
+        }
+
+        true
+    }
+
+    /// Evicts the idle per-destination limiters, at most once per eviction interval.
+    fn evict_idle(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_eviction) < PER_DESTINATION_EVICTION_INTERVAL {
+            return;
+        }
+        self.last_eviction = now;
+        self.per_destination
+            .retain(|_, bucket| bucket.idle_for() < PER_DESTINATION_LIMITER_IDLE_TIMEOUT);
+    }
+
+    /// Logs the counts of suppressed ICMP errors, e.g. on shutdown.
+    pub(crate) fn log(&self, handler: &str) {
+        info!(
+            "{} suppressed ICMP errors: {} by the global rate limit, {} by the per-destination rate limit",
+            handler, self.suppressed_global, self.suppressed_per_destination
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_errors_per_destination_before_globally() {
+        let mut limiter = IcmpErrorLimiter::new(
+            Some(RateLimit { rate: 1, burst: 3 }),
+            Some(RateLimit { rate: 1, burst: 2 }),
+        );
+        let flooding = IpAddr::from(Ipv4Addr::new(192, 168, 2, 2));
+        for _ in 0..2 {
+            assert!(limiter.try_acquire(flooding));
+        }
+        for _ in 0..100 {
+            assert!(!limiter.try_acquire(flooding));
+        }
+        assert_eq!(limiter.suppressed_per_destination, 100);
+
+        // The errors suppressed per destination haven't used up the global limit.
+        assert!(limiter.try_acquire(IpAddr::from(Ipv4Addr::new(192, 168, 1, 2))));
+        assert!(!limiter.try_acquire(IpAddr::from(Ipv4Addr::new(192, 168, 1, 3))));
+        assert_eq!(limiter.suppressed_global, 1);
+    }
+
+    #[test]
+    fn caps_destinations() {
+        let mut limiter = IcmpErrorLimiter::new(None, None);
+        limiter.global = TokenBucket::new(1, u32::MAX);
+        for i in 0..MAX_PER_DESTINATION_LIMITERS as u32 {
+            assert!(limiter.try_acquire(IpAddr::from(Ipv4Addr::from(i))));
+        }
+
+        // None of the limiters is idle yet.
+        assert!(!limiter.try_acquire(IpAddr::from(Ipv4Addr::new(192, 168, 1, 2))));
+        assert_eq!(limiter.per_destination.len(), MAX_PER_DESTINATION_LIMITERS);
+        assert!(limiter.try_acquire(IpAddr::from(Ipv4Addr::from(0))));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::ipv6::{build_ipv6_packet, DEFAULT_HOP_LIMIT, IPV6_HEADER_LENGTH};
+use crate::ipv6_extension_headers::{self, UNRECOGNIZED_OPTION};
+use pnet_packet::icmpv6::{Icmpv6Code, Icmpv6Packet, Icmpv6Type, Icmpv6Types, MutableIcmpv6Packet};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv6::Ipv6Packet;
+use pnet_packet::Packet;
+use std::net::Ipv6Addr;
+
+/// The length of the ICMPv6 header, including the type specific second word.
+pub(crate) const ICMPV6_HEADER_LENGTH: usize = 8;
+
+/// ICMPv6 error messages must not exceed the minimum IPv6 MTU (RFC 4443 2.4).
+pub(crate) const ICMPV6_ERROR_MAX_LENGTH: usize = 1280;
+
+/// An ICMPv6 error to be sent back to the source of a packet.
+#[derive(Debug)]
+pub(crate) struct Icmpv6Error {
+    pub(crate) icmpv6_type: Icmpv6Type,
+    pub(crate) icmpv6_code: Icmpv6Code,
+    /// The second word of the ICMPv6 header, whose meaning depends on the type.
+    pub(crate) rest_of_header: u32,
+}
+
+impl Icmpv6Error {
+    fn destination_unreachable(code: u8) -> Self {
+        Icmpv6Error {
+            icmpv6_type: Icmpv6Types::DestinationUnreachable,
+            icmpv6_code: Icmpv6Code(code),
+            rest_of_header: 0,
+        }
+    }
+
+    pub(crate) fn no_route() -> Self {
+        Self::destination_unreachable(0)
+    }
+
+    /// The destination is beyond the scope of the source address, e.g. a link-local source.
+    pub(crate) fn beyond_scope_of_source_address() -> Self {
+        Self::destination_unreachable(2)
+    }
+
+    /// No listener on the upper-layer protocol port.
+    pub(crate) fn port_unreachable() -> Self {
+        Self::destination_unreachable(4)
+    }
+
+    pub(crate) fn packet_too_big(mtu: u16) -> Self {
+        Icmpv6Error {
+            icmpv6_type: Icmpv6Types::PacketTooBig,
+            icmpv6_code: Icmpv6Code(0),
+            rest_of_header: mtu as u32,
+        }
+    }
+
+    pub(crate) fn time_exceeded() -> Self {
+        Icmpv6Error {
+            icmpv6_type: Icmpv6Types::TimeExceeded,
+            icmpv6_code: Icmpv6Code(0),
+            rest_of_header: 0,
+        }
+    }
+
+    /// `pointer` is the offset of the offending octet in the original packet.
+    pub(crate) fn parameter_problem(code: u8, pointer: u32) -> Self {
+        Icmpv6Error {
+            icmpv6_type: Icmpv6Types::ParameterProblem,
+            icmpv6_code: Icmpv6Code(code),
+            rest_of_header: pointer,
+        }
+    }
+}
+
+/// Builds an IPv6 packet carrying the ICMPv6 error about the original packet. As much of the
+/// original packet is quoted as fits in the size limit.
+pub(crate) fn build_error_packet(
+    error: &Icmpv6Error,
+    source: Ipv6Addr,
+    original: &Ipv6Packet,
+) -> Vec<u8> {
+    let original_length =
+        (IPV6_HEADER_LENGTH + original.get_payload_length() as usize).min(original.packet().len());
+    let quoted_length =
+        original_length.min(ICMPV6_ERROR_MAX_LENGTH - IPV6_HEADER_LENGTH - ICMPV6_HEADER_LENGTH);
+
+    let mut buffer = vec![0u8; ICMPV6_HEADER_LENGTH + quoted_length];
+    buffer[4..8].copy_from_slice(&error.rest_of_header.to_be_bytes());
+    buffer[ICMPV6_HEADER_LENGTH..].copy_from_slice(&original.packet()[..quoted_length]);
+
+    let destination = original.get_source();
+    let mut icmpv6 = MutableIcmpv6Packet::new(&mut buffer).expect("buffer should be large enough");
+    icmpv6.set_icmpv6_type(error.icmpv6_type);
+    icmpv6.set_icmpv6_code(error.icmpv6_code);
+    update_checksum(&mut icmpv6, &source, &destination);
+
+    build_ipv6_packet(
+        source,
+        destination,
+        IpNextHeaderProtocols::Icmpv6,
+        DEFAULT_HOP_LIMIT,
+        &buffer,
+    )
+}
+
+/// Recomputes the checksum of the ICMPv6 message, which covers the pseudo header.
+pub(crate) fn update_checksum(
+    icmpv6: &mut MutableIcmpv6Packet,
+    source: &Ipv6Addr,
+    destination: &Ipv6Addr,
+) {
+    icmpv6.set_checksum(pnet_packet::icmpv6::checksum(
+        &icmpv6.to_immutable(),
+        source,
+        destination,
+    ));
+}
+
+/// Whether an ICMPv6 error may be sent about the packet (RFC 4443 2.4).
+pub(crate) fn may_send_error_about(original: &Ipv6Packet, error: &Icmpv6Error) -> bool {
+    let source = original.get_source();
+
+    // Sources which don't define a single node.
+    if source.is_unspecified() || source.is_multicast() {
+        return false;
+    }
+
+    // Multicasts, except for Packet Too Big which lets the sender adjust to the path MTU, and
+    // unrecognized options which ask to be reported even to multicasts.
+    if original.get_destination().is_multicast()
+        && error.icmpv6_type != Icmpv6Types::PacketTooBig
+        && !(error.icmpv6_type == Icmpv6Types::ParameterProblem
+            && error.icmpv6_code == Icmpv6Code(UNRECOGNIZED_OPTION))
+    {
+        return false;
+    }
+
+    // ICMPv6 errors, whose types have the high-order bit clear.
+    if let Some(upper_layer) = ipv6_extension_headers::upper_layer(original.packet()) {
+        if upper_layer.protocol == IpNextHeaderProtocols::Icmpv6 {
+            return match Icmpv6Packet::new(&original.packet()[upper_layer.offset..]) {
+                Some(icmpv6) => icmpv6.get_icmpv6_type().0 >= 128,
+                None => false,
+            };
+        }
+    }
+
+    true
+}
@@ -1,86 +1,1226 @@
+use crate::acl::{AccessList, Verdict};
 use crate::arp::{ArpHandlerEvent, ArpRequest};
+use crate::config::{Config, InterfaceConfig};
+use crate::conntrack::{self, ConnectionTable, Tuple};
+use crate::control_plane::ControlPlaneEvent;
+use crate::counters::{DropCounters, DropReason};
+use crate::ethernet::{EthernetHandlerEvent, OutgoingFrame, ETHERNET_TYPE_IP};
+use crate::firewall::Firewall;
+use crate::icmp::{self, IcmpError, IcmpErrorLimiter};
+use crate::ipv4_options::{self, Ipv4Options, SourceRoute};
+use crate::martian::{self, BogonList};
+use crate::nat::{self, Nat};
+use crate::nat64::Nat64HandlerEvent;
+use crate::rate_limit::TokenBucket;
+use crate::routing::{NextHop, RouteType, RoutingPolicy};
+use crate::tcp_mss;
+use crate::vrf::VrfAssignments;
 use crate::ArpTable;
-use ipnetwork::IpNetwork;
+use ipnetwork::{IpNetwork, Ipv4Network};
 use pnet_datalink::{MacAddr, NetworkInterface};
-use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::{Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet_packet::Packet;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::net::Ipv4Addr;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::select;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::task::JoinHandle;
 use tracing::{debug, error};
 
 pub(crate) const IPV4_ADDRESS_LENGTH: u8 = 4;
 
+/// The length of an IPv4 header without options.
+pub(crate) const IPV4_HEADER_LENGTH: usize = 20;
+
+/// The TTL of packets originated by us.
+const DEFAULT_TTL: u8 = 64;
+
+/// How often expired connections are removed from the connection table.
+const CONNTRACK_GC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Builds an IPv4 packet without options originated by us.
+pub(crate) fn build_ipv4_packet(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut buffer = vec![0u8; IPV4_HEADER_LENGTH + payload.len()];
+    let mut packet = MutableIpv4Packet::new(&mut buffer).expect("buffer should be large enough");
+    packet.set_version(4);
+    packet.set_header_length((IPV4_HEADER_LENGTH / 4) as u8);
+    packet.set_total_length((IPV4_HEADER_LENGTH + payload.len()) as u16);
+    packet.set_ttl(DEFAULT_TTL);
+    packet.set_next_level_protocol(protocol);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    packet.set_payload(payload);
+    packet.set_checksum(pnet_packet::ipv4::checksum(&packet.to_immutable()));
+    buffer
+}
+
+/// Checks the header of a received packet (RFC 1812 5.2.2): the version, the header and total
+/// lengths, so that the options fit in the packet cut to its total length, and the checksum, so
+/// that a corrupted header isn't given a valid checksum when forwarded.
+fn validate_header(packet: &Ipv4Packet) -> Result<(), DropReason> {
+    let header_length = packet.get_header_length() as usize * 4;
+    let total_length = packet.get_total_length() as usize;
+    if packet.get_version() != 4
+        || header_length < IPV4_HEADER_LENGTH
+        || header_length > total_length
+        || total_length > packet.packet().len()
+    {
+        return Err(DropReason::MalformedHeader);
+    }
+
+    if packet.get_checksum() != pnet_packet::ipv4::checksum(packet) {
+        return Err(DropReason::HeaderChecksum);
+    }
+
+    Ok(())
+}
+
+/// Splits a packet without options into fragments fitting in the MTU (RFC 791 3.2).
+fn fragment(packet: &Ipv4Packet, mtu: u16) -> Vec<Vec<u8>> {
+    let header = &packet.packet()[..IPV4_HEADER_LENGTH];
+    let payload = &packet.packet()[IPV4_HEADER_LENGTH..packet.get_total_length() as usize];
+    // The fragment offset is counted in units of 8 octets.
+    let fragment_length = (mtu as usize - IPV4_HEADER_LENGTH) / 8 * 8;
+    let first_offset = packet.get_fragment_offset() as usize * 8;
+    let more_fragments = packet.get_flags() & Ipv4Flags::MoreFragments != 0;
+
+    let count = payload.len().div_ceil(fragment_length);
+    payload
+        .chunks(fragment_length)
+        .enumerate()
+        .map(|(index, data)| {
+            let mut buffer = [header, data].concat();
+            let mut fragment =
+                MutableIpv4Packet::new(&mut buffer).expect("buffer should be large enough");
+            fragment.set_total_length((IPV4_HEADER_LENGTH + data.len()) as u16);
+            fragment.set_fragment_offset(((first_offset + index * fragment_length) / 8) as u16);
+            if index + 1 < count || more_fragments {
+                fragment.set_flags(packet.get_flags() | Ipv4Flags::MoreFragments);
+            }
+            fragment.set_checksum(pnet_packet::ipv4::checksum(&fragment.to_immutable()));
+            buffer
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn spawn_ipv4_handler(
     interfaces: Vec<NetworkInterface>,
+    config: &Config,
+    vrfs: VrfAssignments,
     arp_table: Arc<RwLock<ArpTable>>,
     receiver: UnboundedReceiver<Ipv4HandlerEvent>,
     sender_arp: UnboundedSender<ArpHandlerEvent>,
+    sender_ethernet: UnboundedSender<EthernetHandlerEvent>,
+    sender_nat64: UnboundedSender<Nat64HandlerEvent>,
+    sender_control_plane: UnboundedSender<ControlPlaneEvent>,
 ) -> JoinHandle<()> {
-    Ipv4Handler::new(interfaces, arp_table, receiver, sender_arp).spawn()
+    Ipv4Handler::new(
+        interfaces,
+        config,
+        vrfs,
+        arp_table,
+        receiver,
+        sender_arp,
+        sender_ethernet,
+        sender_nat64,
+        sender_control_plane,
+    )
+    .spawn()
 }
 
 #[derive(Debug)]
 pub(crate) enum Ipv4HandlerEvent {
-    ReceivedPacket(Ipv4Packet<'static>),
+    ReceivedPacket {
+        /// The interface index (operating system specific) the packet arrived on.
+        interface_index: u32,
+        packet: Ipv4Packet<'static>,
+        /// Whether the frame was addressed to the link-layer broadcast address.
+        link_broadcast: bool,
+    },
+    /// An event let Ipv4Handler to route a packet translated from IPv6 by Nat64Handler.
+    RoutePacket {
+        vrf: String,
+        packet: Vec<u8>,
+    },
     Shutdown,
 }
 
 struct Ipv4Handler {
     interfaces: Vec<NetworkInterface>,
-    ipv4_addresses: Vec<Ipv4Addr>,
+    /// Per-interface settings keyed by the interface index (operating system specific).
+    interface_configs: HashMap<u32, InterfaceConfig>,
+    /// Rate limiters of ICMP redirects keyed by the interface index (operating system specific).
+    redirect_limiters: HashMap<u32, TokenBucket>,
+    icmp_error_limiter: IcmpErrorLimiter,
+    /// The access-control lists applied to the packets arriving on and leaving via the
+    /// interfaces, keyed by the interface index (operating system specific).
+    ingress_acls: HashMap<u32, AccessList>,
+    egress_acls: HashMap<u32, AccessList>,
+    firewall: Firewall,
+    /// Our addresses keyed by the VRF name.
+    ipv4_addresses: HashMap<String, Vec<Ipv4Addr>>,
+    /// The subnet-directed broadcast addresses of the connected networks and the interface index
+    /// (operating system specific) they are attached to, keyed by the VRF name.
+    directed_broadcasts: HashMap<String, Vec<(Ipv4Addr, u32)>>,
+    vrfs: VrfAssignments,
+    /// The routing tables and rules keyed by the VRF name.
+    routing_policies: HashMap<String, RoutingPolicy>,
     arp_table: Arc<RwLock<ArpTable>>,
     receiver: UnboundedReceiver<Ipv4HandlerEvent>,
     sender_arp: UnboundedSender<ArpHandlerEvent>,
+    sender_ethernet: UnboundedSender<EthernetHandlerEvent>,
+    sender_nat64: UnboundedSender<Nat64HandlerEvent>,
+    sender_control_plane: UnboundedSender<ControlPlaneEvent>,
+    /// Packets to the NAT64 pool are translated to IPv6.
+    nat64_pool: Option<Ipv4Network>,
+    conntrack: ConnectionTable,
+    nat: Nat,
+    bogons: BogonList,
+    /// Whether to follow source routes rather than dropping source routed packets.
+    source_route: bool,
+    drop_counters: DropCounters,
+}
+
+/// The fields identifying a flow. Ports are zero for protocols other than TCP/UDP and for
+/// non-initial fragments, which makes the key a 3-tuple.
+#[derive(Hash)]
+struct FlowKey {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    source_port: u16,
+    destination_port: u16,
+}
+
+/// A packet of a tracked flow.
+#[derive(Clone, Copy, Debug)]
+struct Flow {
+    /// The tuple of the packet as received.
+    tuple: Tuple,
+    /// The tuple the packet is to be rewritten to.
+    target: Tuple,
+    /// Whether the packet starts a new connection, which is tracked once the packet is
+    /// forwarded.
+    new: bool,
+}
+
+impl Flow {
+    fn is_translated(&self) -> bool {
+        self.target != self.tuple
+    }
+}
+
+impl FlowKey {
+    fn new(packet: &Ipv4Packet) -> Self {
+        let protocol = packet.get_next_level_protocol();
+        let (source_port, destination_port) = match protocol {
+            IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp
+                if packet.get_fragment_offset() == 0 && packet.payload().len() >= 4 =>
+            {
+                let payload = packet.payload();
+                (
+                    u16::from_be_bytes([payload[0], payload[1]]),
+                    u16::from_be_bytes([payload[2], payload[3]]),
+                )
+            }
+            _ => (0, 0),
+        };
+
+        FlowKey {
+            source: packet.get_source(),
+            destination: packet.get_destination(),
+            protocol: protocol.0,
+            source_port,
+            destination_port,
+        }
+    }
+
+    fn hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        Hash::hash(self, &mut hasher);
+        hasher.finish()
+    }
 }
 
 impl Ipv4Handler {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         interfaces: Vec<NetworkInterface>,
+        config: &Config,
+        vrfs: VrfAssignments,
         arp_table: Arc<RwLock<ArpTable>>,
         receiver: UnboundedReceiver<Ipv4HandlerEvent>,
         sender_arp: UnboundedSender<ArpHandlerEvent>,
+        sender_ethernet: UnboundedSender<EthernetHandlerEvent>,
+        sender_nat64: UnboundedSender<Nat64HandlerEvent>,
+        sender_control_plane: UnboundedSender<ControlPlaneEvent>,
     ) -> Self {
-        let ipv4_addresses = interfaces
+        let mut ipv4_addresses: HashMap<String, Vec<Ipv4Addr>> = HashMap::new();
+        let mut directed_broadcasts: HashMap<String, Vec<(Ipv4Addr, u32)>> = HashMap::new();
+        for i in &interfaces {
+            let ipv4_networks = i
+                .ips
+                .iter()
+                .filter_map(|ipn| match ipn {
+                    IpNetwork::V4(ipv4n) => Some(ipv4n),
+                    IpNetwork::V6(_) => None,
+                })
+                .collect::<Vec<_>>();
+
+            ipv4_addresses
+                .entry(vrfs.get(i.index).to_string())
+                .or_default()
+                .extend(ipv4_networks.iter().map(|ipv4n| ipv4n.ip()));
+            // /31 and /32 networks have no broadcast address (RFC 3021).
+            directed_broadcasts
+                .entry(vrfs.get(i.index).to_string())
+                .or_default()
+                .extend(
+                    ipv4_networks
+                        .iter()
+                        .filter(|ipv4n| ipv4n.prefix() < 31)
+                        .map(|ipv4n| (ipv4n.broadcast(), i.index)),
+                );
+        }
+
+        let interface_configs = interfaces
+            .iter()
+            .map(|i| {
+                let interface_config = config.interfaces.get(&i.name).cloned().unwrap_or_default();
+                (i.index, interface_config)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let redirect_limiters = interface_configs
             .iter()
-            .flat_map(|i| i.ips.iter().filter(|&ipn| ipn.is_ipv4()))
-            .filter_map(|ipn| match ipn {
-                IpNetwork::V4(ipv4n) => Some(ipv4n.ip()),
-                IpNetwork::V6(_) => None,
+            .map(|(&index, c)| {
+                (
+                    index,
+                    TokenBucket::new(c.redirect_rate, c.redirect_rate.max(1)),
+                )
             })
-            .collect::<Vec<_>>();
+            .collect();
+
+        let access_list = |name: &Option<String>| {
+            name.as_ref().map(|name| {
+                let rules = config.acls.get(name).expect("ACL names are validated");
+                AccessList::new(name, rules.clone())
+            })
+        };
+        let ingress_acls = interface_configs
+            .iter()
+            .filter_map(|(&index, c)| access_list(&c.acl_in).map(|acl| (index, acl)))
+            .collect();
+        let egress_acls = interface_configs
+            .iter()
+            .filter_map(|(&index, c)| access_list(&c.acl_out).map(|acl| (index, acl)))
+            .collect();
+
+        let firewall = Firewall::new(config, &interface_configs);
+
+        let routing_policies = vrfs
+            .names()
+            .into_iter()
+            .map(|vrf| {
+                let policy = RoutingPolicy::build(vrf, &vrfs.interfaces(vrf, &interfaces), config);
+                (vrf.to_string(), policy)
+            })
+            .collect();
 
         Ipv4Handler {
             interfaces,
+            interface_configs,
+            redirect_limiters,
+            ingress_acls,
+            egress_acls,
+            firewall,
+            icmp_error_limiter: IcmpErrorLimiter::new(
+                config.icmp_error_global_rate_limit,
+                config.icmp_error_per_destination_rate_limit,
+            ),
             ipv4_addresses,
+            directed_broadcasts,
+            vrfs,
+            routing_policies,
             arp_table,
             receiver,
             sender_arp,
+            sender_ethernet,
+            sender_nat64,
+            sender_control_plane,
+            nat64_pool: config.nat64.pool,
+            conntrack: ConnectionTable::new(config),
+            nat: Nat::new(config),
+            bogons: BogonList::new(config.bogons.clone()),
+            source_route: config.source_route,
+            drop_counters: DropCounters::default(),
         }
     }
 
-    fn handle_received_packet(&self, packet: Ipv4Packet) {
-        if self.determine_if_ours(&packet) {
+    fn handle_received_packet(
+        &mut self,
+        interface_index: u32,
+        packet: Ipv4Packet,
+        link_broadcast: bool,
+    ) {
+        // Packets are routed only within the VRF of the ingress interface.
+        let vrf = &self.vrfs.get(interface_index).to_string();
+
+        // RFC 1812 5.3.4. The remaining link-layer broadcasts are IP broadcasts or multicasts,
+        // which no ICMP errors are sent about.
+        let destination = packet.get_destination();
+        if link_broadcast
+            && !destination.is_broadcast()
+            && !destination.is_multicast()
+            && self
+                .directed_broadcast_interface(vrf, &destination)
+                .is_none()
+        {
+            debug!("Dropped a link-layer broadcast to {}", destination);
+            self.drop_counters.increment(DropReason::LinkLayerBroadcast);
+            return;
+        }
+
+        let martian = martian::check_received(packet.get_source(), packet.get_destination())
+            .or_else(|| {
+                if self.interface_configs[&interface_index].bogon_filter {
+                    self.bogons
+                        .check(packet.get_source(), packet.get_destination())
+                } else {
+                    None
+                }
+            });
+        if let Some(reason) = martian {
+            debug!(
+                "Dropped a martian packet from {} to {}: {:?}",
+                packet.get_source(),
+                packet.get_destination(),
+                reason
+            );
+            self.drop_counters.increment(DropReason::Martian(reason));
+            return;
+        }
+
+        if !self.routing_policies[vrf].check_reverse_path(
+            &packet,
+            interface_index,
+            self.interface_configs[&interface_index].rpf,
+        ) {
+            debug!(
+                "Dropped a packet from {} failing the reverse path check",
+                packet.get_source()
+            );
+            self.drop_counters.increment(DropReason::ReversePathCheck);
+            return;
+        }
+
+        if let Err(reason) = validate_header(&packet) {
+            debug!("Dropped a packet with an invalid header: {:?}", packet);
+            self.drop_counters.increment(reason);
+            return;
+        }
+
+        let header_length = packet.get_header_length() as usize * 4;
+
+        if !self.filter(vrf, interface_index, &packet, None) {
+            return;
+        }
+
+        let options = match ipv4_options::parse(&packet.packet()[..header_length]) {
+            Ok(options) => options,
+            Err(pointer) => {
+                debug!("Dropped a packet with malformed options: {:?}", packet);
+                self.drop_counters.increment(DropReason::MalformedOptions);
+                self.send_icmp_error(
+                    vrf,
+                    interface_index,
+                    &packet,
+                    IcmpError::parameter_problem(pointer),
+                );
+                return;
+            }
+        };
+
+        if let Some(source_route) = &options.source_route {
+            if !self.source_route {
+                debug!("Dropped a source routed packet: {:?}", packet);
+                self.drop_counters.increment(DropReason::SourceRoute);
+                self.send_icmp_error(
+                    vrf,
+                    interface_index,
+                    &packet,
+                    IcmpError::parameter_problem(source_route.offset as u8),
+                );
+                return;
+            }
+        }
+
+        // The control plane gets a copy of the packets with the router alert option. As it
+        // implements no protocol needing to intercept them, they are forwarded or delivered like
+        // any other (RFC 2113).
+        if options.router_alert {
+            let length = packet.get_total_length() as usize;
+            if let Err(e) = self.sender_control_plane.send(ControlPlaneEvent::Punted {
+                interface_index,
+                packet: packet.packet()[..length].to_vec(),
+            }) {
+                error!("Failed to punt the packet to the control plane: {}", e);
+            }
+        }
+
+        // A source routed packet addressed to us is forwarded to the next address of the route.
+        let packet = match &options.source_route {
+            Some(source_route) if self.is_our_address(vrf, &packet.get_destination()) => {
+                match self.follow_source_route(vrf, interface_index, &packet, source_route) {
+                    Some(packet) => packet,
+                    None => return,
+                }
+            }
+            _ => packet,
+        };
+
+        let packet = match self.translate_icmp_error(packet.packet()) {
+            Some(translated) => Ipv4Packet::owned(translated).expect("valid IPv4 packet"),
+            None => packet,
+        };
+
+        if self.conntrack.is_dropped_fragment(&packet) {
+            debug!(
+                "Dropped a fragment of a translated connection: {:?}",
+                packet
+            );
+            self.drop_counters.increment(DropReason::TranslatedFragment);
+            return;
+        }
+
+        // The destination of replies to translated connections and of new connections to
+        // forwarded ports is rewritten before routing.
+        let flow = Tuple::new(&packet).map(|tuple| self.track(tuple));
+        let packet = match flow {
+            Some(flow) if flow.target.destination != flow.tuple.destination => {
+                let length = (packet.get_total_length() as usize).min(packet.packet().len());
+                let mut buffer = packet.packet()[..length].to_vec();
+                nat::rewrite_destination(&mut buffer, flow.target.destination);
+                Ipv4Packet::owned(buffer).expect("valid IPv4 packet")
+            }
+            _ => packet,
+        };
+
+        if self.determine_if_ours(vrf, &packet) {
+            if let Some(target) = self.directed_broadcast_interface(vrf, &packet.get_destination())
+            {
+                if target != interface_index {
+                    self.handle_directed_broadcast(vrf, &packet, &options, target);
+                }
+            }
+
             // TODO
             return;
         }
 
-        if let Some(_mac_addr) = self
+        if let Some(reason) = martian::check_forwarded(packet.get_destination()) {
+            debug!(
+                "Dropped a packet not to be forwarded to {}: {:?}",
+                packet.get_destination(),
+                reason
+            );
+            self.drop_counters.increment(DropReason::Martian(reason));
+            return;
+        }
+
+        if packet.get_ttl() <= 1 {
+            debug!("Dropped a packet whose TTL has expired: {:?}", packet);
+            self.drop_counters.increment(DropReason::TtlExceeded);
+            self.send_icmp_error(vrf, interface_index, &packet, IcmpError::time_exceeded());
+            return;
+        }
+
+        if self
+            .nat64_pool
+            .is_some_and(|pool| pool.contains(packet.get_destination()))
+        {
+            let length = (packet.get_total_length() as usize).min(packet.packet().len());
+            if let Err(e) = self.sender_nat64.send(Nat64HandlerEvent::TranslateIpv4 {
+                vrf: vrf.to_string(),
+                packet: packet.packet()[..length].to_vec(),
+            }) {
+                error!("Failed to send the packet to Nat64Handler: {}", e);
+            }
+            return;
+        }
+
+        let next_hop = match self.next_hop(vrf, Some(interface_index), &packet) {
+            Ok(next_hop) => next_hop,
+            Err(error) => {
+                if let Some(error) = error {
+                    self.send_icmp_error(vrf, interface_index, &packet, error);
+                }
+                return;
+            }
+        };
+
+        if !self.filter(
+            vrf,
+            interface_index,
+            &packet,
+            Some(next_hop.interface_index),
+        ) {
+            return;
+        }
+
+        if !self.inspect(
+            vrf,
+            interface_index,
+            next_hop.interface_index,
+            &packet,
+            flow,
+        ) {
+            return;
+        }
+
+        // A new connection to a forwarded port from the segment of the internal host is also
+        // source translated to our address on the segment (hairpinning), as the replies would
+        // otherwise go straight back to the client.
+        let flow = match flow {
+            Some(mut flow)
+                if flow.new
+                    && flow.is_translated()
+                    && next_hop.interface_index == interface_index
+                    && self.is_on_segment(interface_index, flow.tuple.source.0) =>
+            {
+                let address =
+                    Self::source_address_for(self.interface(interface_index), &flow.tuple.source.0);
+                match self.nat.hairpin(&self.conntrack, &flow.target, address) {
+                    Ok(target) => flow.target = target,
+                    Err(reason) => {
+                        debug!("Failed to hairpin {:?}", flow.tuple);
+                        self.drop_counters.increment(reason);
+                        return;
+                    }
+                }
+                Some(flow)
+            }
+            flow => flow,
+        };
+
+        // RFC 1812 5.2.7.2. A redirect about a translated destination would mislead the source.
+        if next_hop.interface_index == interface_index
+            && options.source_route.is_none()
+            && !flow.is_some_and(|flow| flow.is_translated())
+        {
+            self.send_redirect(vrf, interface_index, &packet, &next_hop);
+        }
+
+        self.forward(vrf, interface_index, packet, &options, &next_hop, flow);
+    }
+
+    /// Looks up the next hop of a packet to be forwarded, dropping the packet if there is no
+    /// route or the route isn't unicast. Returns the ICMP error to be sent back, if any.
+    fn next_hop(
+        &mut self,
+        vrf: &str,
+        ingress: Option<u32>,
+        packet: &Ipv4Packet,
+    ) -> Result<NextHop, Option<IcmpError>> {
+        let route = match self.routing_policies[vrf].lookup(packet, ingress) {
+            Some(route) => route,
+            None => {
+                debug!("No route to {}", packet.get_destination());
+                self.drop_counters.increment(DropReason::NoRoute);
+                return Err(Some(IcmpError::network_unreachable()));
+            }
+        };
+
+        let error = match route.route_type {
+            RouteType::Unicast => None,
+            RouteType::Blackhole => {
+                debug!(
+                    "Dropped a packet to {} by a blackhole route",
+                    route.destination
+                );
+                self.drop_counters.increment(DropReason::BlackholeRoute);
+                return Err(None);
+            }
+            RouteType::Unreachable => {
+                Some((DropReason::UnreachableRoute, IcmpError::host_unreachable()))
+            }
+            RouteType::Prohibit => Some((
+                DropReason::ProhibitRoute,
+                IcmpError::administratively_prohibited(),
+            )),
+        };
+        if let Some((reason, error)) = error {
+            debug!(
+                "Rejected a packet to {} by a {:?} route",
+                route.destination, route.route_type
+            );
+            self.drop_counters.increment(reason);
+            return Err(Some(error));
+        }
+
+        Ok(route
+            .select_next_hop(FlowKey::new(packet).hash())
+            .expect("unicast route should have next hops")
+            .clone())
+    }
+
+    /// Applies the access-control list of the ingress interface, or of the egress interface if
+    /// given. Returns `false` if the packet has been dropped.
+    fn filter(
+        &mut self,
+        vrf: &str,
+        interface_index: u32,
+        packet: &Ipv4Packet,
+        egress: Option<u32>,
+    ) -> bool {
+        let acl = match egress {
+            Some(egress) => self.egress_acls.get_mut(&egress),
+            None => self.ingress_acls.get_mut(&interface_index),
+        };
+        let verdict = acl.map_or(Verdict::Permit, |acl| acl.evaluate(packet));
+        if verdict == Verdict::Permit {
+            return true;
+        }
+
+        debug!(
+            "Denied a packet from {} to {} by an ACL",
+            packet.get_source(),
+            packet.get_destination()
+        );
+        self.drop_counters.increment(DropReason::AccessList);
+        if verdict == Verdict::Reject {
+            self.send_icmp_error(
+                vrf,
+                interface_index,
+                packet,
+                IcmpError::administratively_prohibited(),
+            );
+        }
+        false
+    }
+
+    /// Applies the zone policy to a packet forwarded between zones. Packets of tracked
+    /// connections and ICMP errors about them pass, while new connections are subject to the
+    /// policy of their zone pair. Returns `false` if the packet has been dropped.
+    fn inspect(
+        &mut self,
+        vrf: &str,
+        ingress: u32,
+        egress: u32,
+        packet: &Ipv4Packet,
+        flow: Option<Flow>,
+    ) -> bool {
+        if !self.firewall.inspects(ingress, egress) {
+            return true;
+        }
+
+        let valid = match flow {
+            Some(flow) if !flow.new => return true,
+            Some(flow) => conntrack::is_valid_new(&flow.tuple, conntrack::tcp_flags(packet)),
+            None => match nat::embedded_tuple(packet) {
+                Some(embedded) if self.is_related(&embedded) => return true,
+                Some(_) => false,
+                // Other protocols and non-initial fragments aren't tracked.
+                None => true,
+            },
+        };
+        if !valid {
+            debug!(
+                "Dropped a packet from {} to {} in an invalid state",
+                packet.get_source(),
+                packet.get_destination()
+            );
+            self.drop_counters.increment(DropReason::InvalidState);
+            return false;
+        }
+
+        match self.firewall.check(ingress, egress, packet) {
+            Verdict::Permit => true,
+            verdict => {
+                debug!(
+                    "Denied a connection from {} to {} by the zone policy",
+                    packet.get_source(),
+                    packet.get_destination()
+                );
+                self.drop_counters.increment(DropReason::ZonePolicy);
+                if verdict == Verdict::Reject {
+                    self.send_icmp_error(
+                        vrf,
+                        ingress,
+                        packet,
+                        IcmpError::administratively_prohibited(),
+                    );
+                }
+                false
+            }
+        }
+    }
+
+    /// Whether the packet embedded in an ICMP error, translated back already if it belongs to a
+    /// translated connection, belongs to a tracked connection.
+    fn is_related(&self, embedded: &Tuple) -> bool {
+        self.conntrack.lookup(embedded).is_some()
+            || self.conntrack.lookup(&embedded.reverse()).is_some()
+    }
+
+    /// Tells the source a better first hop if it is on the same subnet as the next hop, as the
+    /// packet goes back out the interface it arrived on.
+    fn send_redirect(
+        &mut self,
+        vrf: &str,
+        interface_index: u32,
+        packet: &Ipv4Packet,
+        next_hop: &NextHop,
+    ) {
+        if !self.interface_configs[&interface_index].redirects {
+            return;
+        }
+
+        let source = packet.get_source();
+        let gateway = next_hop.address(packet.get_destination());
+        let on_same_subnet = self
+            .interface(interface_index)
+            .ips
+            .iter()
+            .any(|ipn| match ipn {
+                IpNetwork::V4(ipv4n) => ipv4n.contains(source) && ipv4n.contains(gateway),
+                IpNetwork::V6(_) => false,
+            });
+        if !on_same_subnet {
+            return;
+        }
+
+        let allowed = self
+            .redirect_limiters
+            .get_mut(&interface_index)
+            .expect("should have the rate limiter")
+            .try_take();
+        if !allowed {
+            debug!("Suppressed a redirect to {} by the rate limit", source);
+            return;
+        }
+
+        debug!(
+            "Sending a redirect to {} for {}",
+            source,
+            packet.get_destination()
+        );
+        self.send_icmp_error(vrf, interface_index, packet, IcmpError::redirect(gateway));
+    }
+
+    /// Processes the source route option of a packet addressed to us (RFC 1812 5.2.4.1). Returns
+    /// the packet rewritten to be forwarded to the next address of the route, as is if the route
+    /// is exhausted, or `None` if the packet has been dropped.
+    fn follow_source_route(
+        &mut self,
+        vrf: &str,
+        interface_index: u32,
+        packet: &Ipv4Packet,
+        source_route: &SourceRoute,
+    ) -> Option<Ipv4Packet<'static>> {
+        let length = (packet.get_total_length() as usize).min(packet.packet().len());
+        let mut buffer = packet.packet()[..length].to_vec();
+
+        let next = match ipv4_options::next_source_route_hop(&buffer, source_route) {
+            Some(next) => next,
+            None => return Ipv4Packet::owned(buffer),
+        };
+
+        let mut rewritten = MutableIpv4Packet::new(&mut buffer).expect("valid IPv4 packet");
+        rewritten.set_destination(next);
+        let next_hop = self.routing_policies[vrf]
+            .lookup(&rewritten.to_immutable(), Some(interface_index))
+            .and_then(|route| route.select_next_hop(FlowKey::new(&rewritten.to_immutable()).hash()))
+            .cloned();
+
+        // A strict source route requires the next address to be directly connected.
+        let next_hop = match next_hop {
+            Some(next_hop) if !source_route.strict || next_hop.gateway.is_none() => next_hop,
+            _ => {
+                debug!("Failed to follow the source route to {}", next);
+                self.drop_counters.increment(DropReason::SourceRoute);
+                self.send_icmp_error(
+                    vrf,
+                    interface_index,
+                    packet,
+                    IcmpError::source_route_failed(),
+                );
+                return None;
+            }
+        };
+
+        let address = Self::source_address_for(self.interface(next_hop.interface_index), &next);
+        ipv4_options::advance_source_route(&mut buffer, source_route, address);
+
+        let mut rewritten = MutableIpv4Packet::owned(buffer).expect("valid IPv4 packet");
+        rewritten.set_checksum(pnet_packet::ipv4::checksum(&rewritten.to_immutable()));
+        Some(rewritten.consume_to_immutable())
+    }
+
+    /// Handles a subnet-directed broadcast which arrived from another network than the target
+    /// one. It is forwarded only if enabled on the target interface (RFC 2644).
+    fn handle_directed_broadcast(
+        &mut self,
+        vrf: &str,
+        packet: &Ipv4Packet,
+        options: &Ipv4Options,
+        target: u32,
+    ) {
+        if !self.interface_configs[&target].directed_broadcast {
+            debug!(
+                "Dropped a directed broadcast to {}",
+                packet.get_destination()
+            );
+            self.drop_counters.increment(DropReason::DirectedBroadcast);
+            return;
+        }
+
+        if packet.get_ttl() <= 1 {
+            debug!("Dropped a packet whose TTL has expired: {:?}", packet);
+            self.drop_counters.increment(DropReason::TtlExceeded);
+            return;
+        }
+
+        let address = Self::source_address_for(self.interface(target), &packet.get_destination());
+        if let Some(forwarding) = self.prepare_forwarding(vrf, target, packet, options, address) {
+            self.send_frame(target, MacAddr::broadcast(), forwarding);
+        }
+    }
+
+    /// Sends the packet to the next hop. `flow` is given for packets of tracked flows.
+    fn forward(
+        &mut self,
+        vrf: &str,
+        interface_index: u32,
+        packet: Ipv4Packet,
+        options: &Ipv4Options,
+        next_hop: &NextHop,
+        flow: Option<Flow>,
+    ) {
+        let destination = packet.get_destination();
+        let address = Self::source_address_for(
+            self.interface(next_hop.interface_index),
+            &next_hop.address(destination),
+        );
+
+        if let Some(mut forwarding) =
+            self.prepare_forwarding(vrf, interface_index, &packet, options, address)
+        {
+            if let Some(limit) = self.mss_limit(interface_index, next_hop.interface_index) {
+                tcp_mss::clamp(&mut forwarding, limit);
+            }
+            let forwarded = match flow {
+                Some(flow) => {
+                    self.translate_source(&mut forwarding, flow, next_hop.interface_index, address)
+                }
+                None => true,
+            };
+            if forwarded {
+                self.transmit(vrf, next_hop, destination, forwarding);
+            }
+        }
+    }
+
+    /// Looks up the connection of a packet, or translates the destination of a packet starting a
+    /// new connection to a forwarded port. The connection is updated once the packet is
+    /// forwarded.
+    fn track(&self, tuple: Tuple) -> Flow {
+        match self.conntrack.lookup(&tuple) {
+            Some(target) => Flow {
+                tuple,
+                target,
+                new: false,
+            },
+            None => Flow {
+                tuple,
+                target: self.nat.destination_nat(&tuple).unwrap_or(tuple),
+                new: true,
+            },
+        }
+    }
+
+    /// Returns a copy of the packet translated if it is an ICMP error about a packet of a
+    /// translated connection.
+    fn translate_icmp_error(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let ipv4 = Ipv4Packet::new(packet)?;
+        let embedded = nat::embedded_tuple(&ipv4)?;
+        // The embedded packet went the opposite way.
+        let translated = self.conntrack.lookup(&embedded.reverse())?.reverse();
+        if translated == embedded {
+            return None;
+        }
+
+        let length = (ipv4.get_total_length() as usize).min(packet.len());
+        let mut buffer = packet[..length].to_vec();
+        nat::rewrite_icmp_error(&mut buffer, &embedded, &translated);
+        debug!("Translated an ICMP error about {:?}", embedded);
+        Some(buffer)
+    }
+
+    /// Rewrites the source of a packet of a translated connection, and updates its connection. A
+    /// new connection is source translated if it matches a source NAT rule of the egress
+    /// interface, and tracked. `address` is the address of the egress interface. Returns `false`
+    /// if the packet has been dropped.
+    fn translate_source(
+        &mut self,
+        packet: &mut [u8],
+        mut flow: Flow,
+        egress: u32,
+        address: Ipv4Addr,
+    ) -> bool {
+        let (tcp_flags, length, identification, more_fragments) = match Ipv4Packet::new(packet) {
+            Some(ipv4) => (
+                conntrack::tcp_flags(&ipv4),
+                packet.len(),
+                ipv4.get_identification(),
+                ipv4.get_flags() & Ipv4Flags::MoreFragments != 0,
+            ),
+            None => return true,
+        };
+
+        if flow.new && flow.target.source == flow.tuple.source {
+            let interface = self.interface(egress).name.clone();
+            match self
+                .nat
+                .source_nat(&self.conntrack, &flow.target, &interface, address)
+            {
+                Ok(Some(target)) => flow.target = target,
+                Ok(None) => {}
+                Err(reason) => {
+                    debug!("Failed to translate the source of {:?}", flow.tuple);
+                    self.drop_counters.increment(reason);
+                    return false;
+                }
+            }
+        }
+
+        if more_fragments && flow.is_translated() {
+            debug!("Dropped a fragment of {:?}", flow.tuple);
+            self.conntrack
+                .drop_fragments(&flow.tuple, identification, Instant::now());
+            self.drop_counters.increment(DropReason::TranslatedFragment);
+            return false;
+        }
+
+        if flow.new {
+            if let Err(reason) = self.conntrack.insert(
+                flow.tuple,
+                flow.target.reverse(),
+                tcp_flags,
+                length,
+                Instant::now(),
+            ) {
+                debug!("Failed to track {:?}: {:?}", flow.tuple, reason);
+                self.drop_counters.increment(reason);
+                return false;
+            }
+        } else {
+            self.conntrack
+                .update(&flow.tuple, tcp_flags, length, Instant::now());
+        }
+
+        if flow.target.source != flow.tuple.source {
+            nat::rewrite_source(packet, flow.target.source);
+        }
+        true
+    }
+
+    /// The MSS TCP SYNs forwarded between the interfaces are clamped to, if either interface
+    /// clamps them.
+    fn mss_limit(&self, ingress: u32, egress: u32) -> Option<u16> {
+        [ingress, egress]
+            .iter()
+            .filter_map(|index| {
+                let config = &self.interface_configs[index];
+                config.tcp_mss.limit(config.mtu)
+            })
+            .min()
+    }
+
+    /// Returns a copy of the packet to be forwarded, stripped of any link layer padding, with the
+    /// options updated with the address of the outgoing interface and TTL decremented. Returns
+    /// `None` if the packet has been dropped.
+    fn prepare_forwarding(
+        &mut self,
+        vrf: &str,
+        interface_index: u32,
+        packet: &Ipv4Packet,
+        options: &Ipv4Options,
+        address: Ipv4Addr,
+    ) -> Option<Vec<u8>> {
+        let length = (packet.get_total_length() as usize).min(packet.packet().len());
+        let mut buffer = packet.packet()[..length].to_vec();
+
+        if let Err(pointer) = ipv4_options::update_for_forwarding(&mut buffer, options, address) {
+            debug!(
+                "Dropped a packet whose timestamp option overflowed: {:?}",
+                packet
+            );
+            self.drop_counters.increment(DropReason::MalformedOptions);
+            self.send_icmp_error(
+                vrf,
+                interface_index,
+                packet,
+                IcmpError::parameter_problem(pointer),
+            );
+            return None;
+        }
+
+        let mut forwarding = MutableIpv4Packet::owned(buffer).expect("valid IPv4 packet");
+        forwarding.set_ttl(packet.get_ttl() - 1);
+        forwarding.set_checksum(pnet_packet::ipv4::checksum(&forwarding.to_immutable()));
+        Some(forwarding.packet().to_vec())
+    }
+
+    /// Sends an ICMP error about the packet back to its source.
+    fn send_icmp_error(
+        &mut self,
+        vrf: &str,
+        interface_index: u32,
+        original: &Ipv4Packet,
+        error: IcmpError,
+    ) {
+        if !icmp::may_send_error_about(original)
+            || self
+                .directed_broadcast_interface(vrf, &original.get_destination())
+                .is_some()
+        {
+            return;
+        }
+        if !self
+            .icmp_error_limiter
+            .try_acquire(original.get_source().into())
+        {
+            debug!(
+                "Suppressed an ICMP error to {} by the rate limit",
+                original.get_source()
+            );
+            return;
+        }
+
+        let source =
+            Self::source_address_for(self.interface(interface_index), &original.get_source());
+        self.send(vrf, icmp::build_error_packet(&error, source, original));
+    }
+
+    /// Routes and sends a packet translated from IPv6 by NAT64. Like forwarded packets, it is
+    /// subject to the route type and the ACL of the egress interface. The zone firewall doesn't
+    /// inspect it, as the translator only lets in the packets of sessions started by IPv6 hosts.
+    ///
+    /// ICMP errors are sent to the pool address of the source, which hands them back to the
+    /// translator, from the interface the pool address is routed via, as if the packet had
+    /// arrived from there, or else the egress interface.
+    fn route_translated(&mut self, vrf: &str, packet: Vec<u8>) {
+        let ipv4 = Ipv4Packet::new(&packet).expect("should be a valid IPv4 packet");
+        let reverse = self.routing_policies[vrf]
+            .lookup_reverse(&ipv4)
+            .and_then(|route| route.next_hops.first())
+            .map(|next_hop| next_hop.interface_index);
+        let next_hop = match self.next_hop(vrf, None, &ipv4) {
+            Ok(next_hop) => next_hop,
+            Err(error) => {
+                if let (Some(error), Some(reverse)) = (error, reverse) {
+                    self.send_icmp_error(vrf, reverse, &ipv4, error);
+                }
+                return;
+            }
+        };
+        let egress = next_hop.interface_index;
+        let ingress = reverse.unwrap_or(egress);
+        if !self.filter(vrf, ingress, &ipv4, Some(egress)) {
+            return;
+        }
+
+        // The translator sets DF on the packets larger than the IPv6 minimum MTU allows, so the
+        // IPv6 host learns the path MTU from the Packet Too Big the error is translated to. The
+        // smaller ones are fragmented (RFC 7915 5.1).
+        let mtu = self.interface_configs[&egress].mtu;
+        let destination = ipv4.get_destination();
+        if packet.len() > mtu as usize {
+            if ipv4.get_flags() & Ipv4Flags::DontFragment != 0 {
+                debug!("Dropped a packet larger than the MTU {}: {:?}", mtu, ipv4);
+                self.drop_counters.increment(DropReason::PacketTooBig);
+                self.send_icmp_error(vrf, ingress, &ipv4, IcmpError::fragmentation_needed(mtu));
+                return;
+            }
+            for fragment in fragment(&ipv4, mtu) {
+                self.transmit(vrf, &next_hop, destination, fragment);
+            }
+            return;
+        }
+
+        self.transmit(vrf, &next_hop, destination, packet);
+    }
+
+    /// Routes and sends a packet originated by us. Packets to the NAT64 pool, e.g. ICMP errors
+    /// about translated packets, are handed to the translator.
+    fn send(&self, vrf: &str, packet: Vec<u8>) {
+        // Our ICMP errors about translated packets.
+        let packet = self.translate_icmp_error(&packet).unwrap_or(packet);
+        let ipv4 = Ipv4Packet::new(&packet).expect("should be a valid IPv4 packet");
+        let destination = ipv4.get_destination();
+
+        if self
+            .nat64_pool
+            .is_some_and(|pool| pool.contains(destination))
+        {
+            if let Err(e) = self.sender_nat64.send(Nat64HandlerEvent::TranslateIpv4 {
+                vrf: vrf.to_string(),
+                packet,
+            }) {
+                error!("Failed to send the packet to Nat64Handler: {}", e);
+            }
+            return;
+        }
+
+        let next_hop = match self.routing_policies[vrf]
+            .lookup(&ipv4, None)
+            .and_then(|route| route.select_next_hop(FlowKey::new(&ipv4).hash()))
+        {
+            Some(next_hop) => next_hop,
+            None => {
+                debug!("No route to {}", destination);
+                return;
+            }
+        };
+
+        self.transmit(vrf, next_hop, destination, packet);
+    }
+
+    /// Resolves the MAC address of the next hop and sends the packet to it.
+    fn transmit(&self, vrf: &str, next_hop: &NextHop, destination: Ipv4Addr, packet: Vec<u8>) {
+        let next_hop_address = next_hop.address(destination);
+
+        let mac_addr = self
             .arp_table
             .read()
             .expect("read guard")
-            .get(&packet.get_destination())
-        {
-            // TODO: Transfer the packet to an appropriate next hop.
+            .get(vrf, &next_hop_address)
+            .cloned();
+
+        if let Some(mac_addr) = mac_addr {
+            self.send_frame(next_hop.interface_index, mac_addr, packet);
         } else {
-            // TODO: Fill the sender mac/ipv4 address with an actual one. Maybe routing table is
-            // required to do that.
+            let interface = self.interface(next_hop.interface_index);
+
+            // TODO: Queue the packet until the ARP reply arrives.
             if let Err(e) = self
                 .sender_arp
                 .send(ArpHandlerEvent::SendArpRequest(ArpRequest {
-                    sender_mac_address: MacAddr::zero(),      // TODO
-                    sender_ipv4_address: Ipv4Addr::BROADCAST, // TODO
-                    target_ipv4_address: packet.get_destination(),
+                    sender_mac_address: interface.mac.expect("should have mac address"),
+                    sender_ipv4_address: Self::source_address_for(interface, &next_hop_address),
+                    target_ipv4_address: next_hop_address,
                 }))
             {
                 error!("Failed to send the ArpRequest to ArpHandler: {:?}", e);
@@ -88,22 +1228,112 @@ impl Ipv4Handler {
         }
     }
 
-    fn determine_if_ours(&self, packet: &Ipv4Packet) -> bool {
+    fn send_frame(&self, interface_index: u32, destination: MacAddr, packet: Vec<u8>) {
+        if let Err(e) = self
+            .sender_ethernet
+            .send(EthernetHandlerEvent::SendFrame(OutgoingFrame {
+                interface_index,
+                destination,
+                ethertype: ETHERNET_TYPE_IP,
+                payload: packet,
+            }))
+        {
+            error!("Failed to send the frame to EthernetHandler: {:?}", e);
+        }
+    }
+
+    fn interface(&self, interface_index: u32) -> &NetworkInterface {
+        self.interfaces
+            .iter()
+            .find(|i| i.index == interface_index)
+            .expect("should have the network interface")
+    }
+
+    /// Picks the address of the interface on the same subnet as the target, falling back to the
+    /// first IPv4 address of the interface.
+    fn source_address_for(interface: &NetworkInterface, target: &Ipv4Addr) -> Ipv4Addr {
+        let addresses = interface.ips.iter().filter_map(|ipn| match ipn {
+            IpNetwork::V4(ipv4n) => Some(ipv4n),
+            IpNetwork::V6(_) => None,
+        });
+
+        addresses
+            .clone()
+            .find(|ipv4n| ipv4n.contains(*target))
+            .or_else(|| addresses.clone().next())
+            .map(|ipv4n| ipv4n.ip())
+            .unwrap_or(Ipv4Addr::UNSPECIFIED)
+    }
+
+    /// Whether the address is on a subnet of the interface.
+    fn is_on_segment(&self, interface_index: u32, address: Ipv4Addr) -> bool {
+        self.interface(interface_index)
+            .ips
+            .iter()
+            .any(|ipn| match ipn {
+                IpNetwork::V4(ipv4n) => ipv4n.contains(address),
+                IpNetwork::V6(_) => false,
+            })
+    }
+
+    fn determine_if_ours(&self, vrf: &str, packet: &Ipv4Packet) -> bool {
         let dest = packet.get_destination();
-        self.ipv4_addresses.contains(&dest) || dest.is_broadcast()
+        self.is_our_address(vrf, &dest)
+            || dest.is_broadcast()
+            || self.directed_broadcast_interface(vrf, &dest).is_some()
+    }
+
+    fn is_our_address(&self, vrf: &str, address: &Ipv4Addr) -> bool {
+        self.ipv4_addresses
+            .get(vrf)
+            .into_iter()
+            .flatten()
+            .any(|a| a == address)
+    }
+
+    /// Returns the interface attached to the network whose subnet-directed broadcast address is
+    /// the destination.
+    fn directed_broadcast_interface(&self, vrf: &str, destination: &Ipv4Addr) -> Option<u32> {
+        self.directed_broadcasts
+            .get(vrf)
+            .into_iter()
+            .flatten()
+            .find(|(broadcast, _)| broadcast == destination)
+            .map(|(_, interface_index)| *interface_index)
     }
 
     fn spawn(mut self) -> JoinHandle<()> {
         let fut = async move {
             debug!("Started Ipv4Handler");
 
+            let mut timer = tokio::time::interval(CONNTRACK_GC_INTERVAL);
             loop {
-                if let Some(event) = self.receiver.recv().await {
-                    match event {
-                        Ipv4HandlerEvent::ReceivedPacket(ipv4_packet) => {
-                            self.handle_received_packet(ipv4_packet)
+                select! {
+                    _ = timer.tick() => self.conntrack.expire(Instant::now()),
+                    Some(event) = self.receiver.recv() => {
+                        match event {
+                            Ipv4HandlerEvent::ReceivedPacket {
+                                interface_index,
+                                packet,
+                                link_broadcast,
+                            } => self.handle_received_packet(interface_index, packet, link_broadcast),
+                            Ipv4HandlerEvent::RoutePacket { vrf, packet } => {
+                                self.route_translated(&vrf, packet)
+                            }
+                            Ipv4HandlerEvent::Shutdown => {
+                                self.drop_counters.log("Ipv4Handler");
+                                self.icmp_error_limiter.log("Ipv4Handler");
+                                for (index, acl) in &self.ingress_acls {
+                                    acl.log("Ipv4Handler", &format!("{} in", self.interface(*index).name));
+                                }
+                                for (index, acl) in &self.egress_acls {
+                                    acl.log("Ipv4Handler", &format!("{} out", self.interface(*index).name));
+                                }
+                                self.firewall.log("Ipv4Handler");
+                                self.conntrack.log("Ipv4Handler");
+                                return;
+                            }
                         }
-                        Ipv4HandlerEvent::Shutdown => return,
                     }
                 }
             }
@@ -112,3 +1342,93 @@ impl Ipv4Handler {
         tokio::runtime::Handle::current().spawn(fut)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(payload_length: usize) -> Vec<u8> {
+        build_ipv4_packet(
+            Ipv4Addr::new(192, 168, 1, 2),
+            Ipv4Addr::new(192, 168, 2, 2),
+            IpNextHeaderProtocols::Udp,
+            &vec![0u8; payload_length],
+        )
+    }
+
+    fn set_total_length(buffer: &mut [u8], total_length: u16) {
+        let mut packet = MutableIpv4Packet::new(buffer).unwrap();
+        packet.set_total_length(total_length);
+        packet.set_checksum(pnet_packet::ipv4::checksum(&packet.to_immutable()));
+    }
+
+    fn validate(buffer: &[u8]) -> Result<(), DropReason> {
+        validate_header(&Ipv4Packet::new(buffer).unwrap())
+    }
+
+    #[test]
+    fn validates_total_length() {
+        let mut buffer = packet(8);
+        assert_eq!(validate(&buffer), Ok(()));
+
+        // Link-layer padding after the packet.
+        buffer.extend_from_slice(&[0u8; 4]);
+        assert_eq!(validate(&buffer), Ok(()));
+
+        set_total_length(&mut buffer, 19);
+        assert_eq!(validate(&buffer), Err(DropReason::MalformedHeader));
+        set_total_length(&mut buffer, 0);
+        assert_eq!(validate(&buffer), Err(DropReason::MalformedHeader));
+        set_total_length(&mut buffer, 33);
+        assert_eq!(validate(&buffer), Err(DropReason::MalformedHeader));
+    }
+
+    #[test]
+    fn validates_header_length() {
+        // Options up to the end of the packet.
+        let mut buffer = packet(20);
+        buffer[0] = 0x48;
+        set_total_length(&mut buffer, 32);
+        assert_eq!(validate(&buffer), Ok(()));
+
+        for version_and_ihl in [0x44, 0x4f] {
+            buffer[0] = version_and_ihl;
+            set_total_length(&mut buffer, 24);
+            assert_eq!(validate(&buffer), Err(DropReason::MalformedHeader));
+        }
+    }
+
+    #[test]
+    fn fragments_packets() {
+        let buffer = packet(1000);
+        let fragments = fragment(&Ipv4Packet::new(&buffer).unwrap(), 576);
+        assert_eq!(fragments.len(), 2);
+
+        let mut reassembled = vec![];
+        for (buffer, (offset, length, more_fragments)) in
+            fragments.iter().zip([(0, 572, true), (69, 468, false)])
+        {
+            assert_eq!(validate(buffer), Ok(()));
+            let fragment = Ipv4Packet::new(buffer).unwrap();
+            assert_eq!(fragment.get_fragment_offset(), offset);
+            assert_eq!(fragment.get_total_length(), length);
+            assert_eq!(
+                fragment.get_flags() & Ipv4Flags::MoreFragments != 0,
+                more_fragments
+            );
+            reassembled.extend_from_slice(fragment.payload());
+        }
+        assert_eq!(reassembled, buffer[IPV4_HEADER_LENGTH..]);
+    }
+
+    #[test]
+    fn validates_version_and_checksum() {
+        let mut buffer = packet(8);
+        buffer[0] = 0x65;
+        assert_eq!(validate(&buffer), Err(DropReason::MalformedHeader));
+
+        let mut buffer = packet(8);
+        buffer[8] -= 1;
+        assert_eq!(validate(&buffer), Err(DropReason::HeaderChecksum));
+    }
+}
@@ -0,0 +1,383 @@
+use crate::ipv4::IPV4_HEADER_LENGTH;
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const OPTION_END_OF_LIST: u8 = 0;
+const OPTION_NO_OPERATION: u8 = 1;
+const OPTION_RECORD_ROUTE: u8 = 7;
+const OPTION_TIMESTAMP: u8 = 68;
+const OPTION_LOOSE_SOURCE_ROUTE: u8 = 131;
+const OPTION_STRICT_SOURCE_ROUTE: u8 = 137;
+const OPTION_ROUTER_ALERT: u8 = 148;
+
+/// Timestamp option flag: timestamps only.
+const TIMESTAMP_FLAG_TIMESTAMPS_ONLY: u8 = 0;
+/// Timestamp option flag: each timestamp is preceded by the address of the recording router.
+const TIMESTAMP_FLAG_WITH_ADDRESSES: u8 = 1;
+/// Timestamp option flag: only the routers whose address is prespecified record a timestamp.
+const TIMESTAMP_FLAG_PRESPECIFIED: u8 = 3;
+
+/// The options of an IPv4 packet we process. Offsets are counted from the start of the header.
+#[derive(Debug, Default)]
+pub(crate) struct Ipv4Options {
+    record_route: Option<usize>,
+    timestamp: Option<usize>,
+    pub(crate) router_alert: bool,
+    pub(crate) source_route: Option<SourceRoute>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SourceRoute {
+    /// The offset of the option from the start of the header.
+    pub(crate) offset: usize,
+    pub(crate) strict: bool,
+}
+
+/// Parses the options in the header. On a malformed option, returns the offset of the offending
+/// octet to be reported by ICMP parameter problem.
+pub(crate) fn parse(header: &[u8]) -> Result<Ipv4Options, u8> {
+    let mut options = Ipv4Options::default();
+    let mut offset = IPV4_HEADER_LENGTH;
+
+    while offset < header.len() {
+        let option_type = header[offset];
+        match option_type {
+            OPTION_END_OF_LIST => break,
+            OPTION_NO_OPERATION => {
+                offset += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        // The length octet includes the type and length octets.
+        let length = match header.get(offset + 1) {
+            Some(&length) if length >= 2 && offset + length as usize <= header.len() => {
+                length as usize
+            }
+            _ => return Err((offset + 1) as u8),
+        };
+        let pointer = header.get(offset + 2).copied().unwrap_or_default();
+
+        match option_type {
+            OPTION_RECORD_ROUTE => {
+                validate_route_data(offset, length, pointer)?;
+                options.record_route = Some(offset);
+            }
+            OPTION_LOOSE_SOURCE_ROUTE | OPTION_STRICT_SOURCE_ROUTE => {
+                validate_route_data(offset, length, pointer)?;
+                options.source_route = Some(SourceRoute {
+                    offset,
+                    strict: option_type == OPTION_STRICT_SOURCE_ROUTE,
+                });
+            }
+            OPTION_TIMESTAMP => {
+                if length < 4 {
+                    return Err((offset + 1) as u8);
+                }
+                if pointer < 5 {
+                    return Err((offset + 2) as u8);
+                }
+                match header[offset + 3] & 0x0f {
+                    TIMESTAMP_FLAG_TIMESTAMPS_ONLY
+                    | TIMESTAMP_FLAG_WITH_ADDRESSES
+                    | TIMESTAMP_FLAG_PRESPECIFIED => {}
+                    _ => return Err((offset + 3) as u8),
+                }
+                options.timestamp = Some(offset);
+            }
+            OPTION_ROUTER_ALERT => {
+                if length != 4 {
+                    return Err((offset + 1) as u8);
+                }
+                options.router_alert = true;
+            }
+            _ => {}
+        }
+
+        offset += length;
+    }
+
+    Ok(options)
+}
+
+/// Validates the route data of the record route and source route options (RFC 791).
+fn validate_route_data(offset: usize, length: usize, pointer: u8) -> Result<(), u8> {
+    if length < 3 || length % 4 != 3 {
+        return Err((offset + 1) as u8);
+    }
+    if pointer < 4 {
+        return Err((offset + 2) as u8);
+    }
+    Ok(())
+}
+
+/// Updates the options of a packet being forwarded out the interface with the address. On a
+/// timestamp overflow counter overflowing, returns the offset to be reported by ICMP parameter
+/// problem.
+pub(crate) fn update_for_forwarding(
+    header: &mut [u8],
+    options: &Ipv4Options,
+    address: Ipv4Addr,
+) -> Result<(), u8> {
+    if let Some(offset) = options.record_route {
+        record_address(header, offset, address);
+    }
+
+    if let Some(offset) = options.timestamp {
+        record_timestamp(header, offset, address)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the next address of the source route, or `None` if the route is exhausted.
+pub(crate) fn next_source_route_hop(header: &[u8], source_route: &SourceRoute) -> Option<Ipv4Addr> {
+    let length = header[source_route.offset + 1] as usize;
+    let pointer = header[source_route.offset + 2] as usize;
+    if pointer + 3 > length {
+        return None;
+    }
+
+    let slot = source_route.offset + pointer - 1;
+    Some(Ipv4Addr::new(
+        header[slot],
+        header[slot + 1],
+        header[slot + 2],
+        header[slot + 3],
+    ))
+}
+
+/// Replaces the next address of the source route with the address of the outgoing interface
+/// and advances the pointer (RFC 1812 5.2.4.1).
+pub(crate) fn advance_source_route(
+    header: &mut [u8],
+    source_route: &SourceRoute,
+    address: Ipv4Addr,
+) {
+    record_address(header, source_route.offset, address);
+}
+
+/// Writes the address at the pointer of a route data option and advances the pointer. Nothing is
+/// recorded if the option is full.
+fn record_address(header: &mut [u8], offset: usize, address: Ipv4Addr) {
+    let length = header[offset + 1] as usize;
+    let pointer = header[offset + 2] as usize;
+    if pointer + 3 > length {
+        return;
+    }
+
+    let slot = offset + pointer - 1;
+    header[slot..slot + 4].copy_from_slice(&address.octets());
+    header[offset + 2] += 4;
+}
+
+/// Records a timestamp (RFC 791). If there is no room, the overflow counter is incremented.
+fn record_timestamp(header: &mut [u8], offset: usize, address: Ipv4Addr) -> Result<(), u8> {
+    let length = header[offset + 1] as usize;
+    let pointer = header[offset + 2] as usize;
+    let flag = header[offset + 3] & 0x0f;
+    let entry_length = match flag {
+        TIMESTAMP_FLAG_TIMESTAMPS_ONLY => 4,
+        _ => 8,
+    };
+
+    if pointer + entry_length - 1 > length {
+        let overflow = header[offset + 3] >> 4;
+        if overflow == 0x0f {
+            return Err((offset + 3) as u8);
+        }
+        header[offset + 3] = ((overflow + 1) << 4) | flag;
+        return Ok(());
+    }
+
+    let slot = offset + pointer - 1;
+    let timestamp = milliseconds_since_midnight().to_be_bytes();
+    match flag {
+        TIMESTAMP_FLAG_TIMESTAMPS_ONLY => header[slot..slot + 4].copy_from_slice(&timestamp),
+        TIMESTAMP_FLAG_WITH_ADDRESSES => {
+            header[slot..slot + 4].copy_from_slice(&address.octets());
+            header[slot + 4..slot + 8].copy_from_slice(&timestamp);
+        }
+        _ => {
+            // Only the router whose address is next in the list records a timestamp.
+            if header[slot..slot + 4] != address.octets() {
+                return Ok(());
+            }
+            header[slot + 4..slot + 8].copy_from_slice(&timestamp);
+        }
+    }
+    header[offset + 2] += entry_length as u8;
+
+    Ok(())
+}
+
+/// Milliseconds since midnight UT, the standard value of the timestamp option.
+fn milliseconds_since_midnight() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_millis() % 86_400_000) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+
+    /// A header with the options, padded with End of Option List to a multiple of 4 octets.
+    fn header(options: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; IPV4_HEADER_LENGTH];
+        header.extend_from_slice(options);
+        header.resize(IPV4_HEADER_LENGTH + options.len().div_ceil(4) * 4, 0);
+        header
+    }
+
+    #[test]
+    fn reports_malformed_options() {
+        let cases: [(&[u8], u8); 9] = [
+            // Missing or short length.
+            (&[OPTION_RECORD_ROUTE], 21),
+            (&[OPTION_RECORD_ROUTE, 1, 0, 0], 21),
+            // Longer than the header.
+            (&[OPTION_RECORD_ROUTE, 8, 4, 0], 21),
+            // Route data not a multiple of the address length.
+            (&[OPTION_LOOSE_SOURCE_ROUTE, 5, 4, 0, 0, 0, 0, 0], 21),
+            // Pointer before the route data.
+            (&[OPTION_STRICT_SOURCE_ROUTE, 7, 3, 0, 0, 0, 0, 0], 22),
+            (&[OPTION_TIMESTAMP, 3, 5, 0], 21),
+            (&[OPTION_TIMESTAMP, 8, 4, 0, 0, 0, 0, 0], 22),
+            // Unknown timestamp flag.
+            (&[OPTION_TIMESTAMP, 8, 5, 2, 0, 0, 0, 0], 23),
+            (&[OPTION_ROUTER_ALERT, 6, 0, 0, 0, 0, 0, 0], 21),
+        ];
+        for (options, pointer) in cases {
+            assert_eq!(
+                parse(&header(options)).map(|_| ()),
+                Err(pointer),
+                "{:?}",
+                options
+            );
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = parse(&header(&[
+            OPTION_NO_OPERATION,
+            OPTION_ROUTER_ALERT,
+            4,
+            0,
+            0,
+            OPTION_RECORD_ROUTE,
+            7,
+            4,
+            0,
+            0,
+            0,
+            0,
+            OPTION_END_OF_LIST,
+            // Ignored after the end of the list.
+            OPTION_ROUTER_ALERT,
+        ]))
+        .unwrap();
+        assert!(options.router_alert);
+        assert_eq!(options.record_route, Some(25));
+        assert!(options.source_route.is_none());
+    }
+
+    #[test]
+    fn records_route() {
+        let mut header = header(&[OPTION_RECORD_ROUTE, 11, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let options = parse(&header).unwrap();
+
+        update_for_forwarding(&mut header, &options, ADDRESS).unwrap();
+        assert_eq!(header[22], 8);
+        assert_eq!(header[23..27], ADDRESS.octets());
+
+        update_for_forwarding(&mut header, &options, Ipv4Addr::new(192, 168, 1, 1)).unwrap();
+        assert_eq!(header[22], 12);
+        assert_eq!(header[27..31], [192, 168, 1, 1]);
+
+        // Full.
+        let full = header.clone();
+        update_for_forwarding(&mut header, &options, ADDRESS).unwrap();
+        assert_eq!(header, full);
+    }
+
+    #[test]
+    fn follows_source_route() {
+        let mut header = header(&[OPTION_LOOSE_SOURCE_ROUTE, 7, 4, 192, 168, 2, 2]);
+        let source_route = parse(&header).unwrap().source_route.unwrap();
+        assert!(!source_route.strict);
+        assert_eq!(
+            next_source_route_hop(&header, &source_route),
+            Some(Ipv4Addr::new(192, 168, 2, 2))
+        );
+
+        advance_source_route(&mut header, &source_route, ADDRESS);
+        assert_eq!(header[23..27], ADDRESS.octets());
+        assert_eq!(next_source_route_hop(&header, &source_route), None);
+    }
+
+    #[test]
+    fn records_timestamps() {
+        let mut header = header(&[
+            OPTION_TIMESTAMP,
+            12,
+            5,
+            TIMESTAMP_FLAG_WITH_ADDRESSES,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]);
+        let options = parse(&header).unwrap();
+
+        update_for_forwarding(&mut header, &options, ADDRESS).unwrap();
+        assert_eq!(header[22], 13);
+        assert_eq!(header[24..28], ADDRESS.octets());
+
+        // No room for another entry: the overflow counter is incremented up to its maximum.
+        for overflow in 1..16 {
+            update_for_forwarding(&mut header, &options, ADDRESS).unwrap();
+            assert_eq!(header[23], (overflow << 4) | TIMESTAMP_FLAG_WITH_ADDRESSES);
+        }
+        assert_eq!(
+            update_for_forwarding(&mut header, &options, ADDRESS),
+            Err(23)
+        );
+    }
+
+    #[test]
+    fn records_prespecified_timestamps() {
+        let mut header = header(&[
+            OPTION_TIMESTAMP,
+            12,
+            5,
+            TIMESTAMP_FLAG_PRESPECIFIED,
+            192,
+            168,
+            1,
+            1,
+            0,
+            0,
+            0,
+            0,
+        ]);
+        let options = parse(&header).unwrap();
+
+        // Not our turn.
+        let before = header.clone();
+        update_for_forwarding(&mut header, &options, ADDRESS).unwrap();
+        assert_eq!(header, before);
+
+        update_for_forwarding(&mut header, &options, Ipv4Addr::new(192, 168, 1, 1)).unwrap();
+        assert_eq!(header[22], 13);
+    }
+}
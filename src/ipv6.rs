@@ -0,0 +1,740 @@
+use crate::config::{Config, InterfaceConfig};
+use crate::counters::{DropCounters, DropReason};
+use crate::ethernet::{EthernetHandlerEvent, OutgoingFrame, ETHERNET_TYPE_IPV6};
+use crate::icmp::IcmpErrorLimiter;
+use crate::icmpv6::{self, Icmpv6Error};
+use crate::ipv6_extension_headers::{self, ExtensionHeaderError, UNRECOGNIZED_NEXT_HEADER};
+use crate::ipv6_routing::Ipv6RoutingTable;
+use crate::nat64::Nat64HandlerEvent;
+use crate::ndp::{self, NdpHandlerEvent, NeighborCache, NeighborState};
+use crate::vrf::VrfAssignments;
+use ipnetwork::{IpNetwork, Ipv6Network};
+use pnet_datalink::{MacAddr, NetworkInterface};
+use pnet_packet::icmpv6::{Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet};
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet_packet::Packet;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::{debug, error};
+
+/// The length of the fixed IPv6 header.
+pub(crate) const IPV6_HEADER_LENGTH: usize = 40;
+
+/// The hop limit of packets originated by us.
+pub(crate) const DEFAULT_HOP_LIMIT: u8 = 64;
+
+/// The multicast groups every router joins on each interface (RFC 4291 2.8).
+pub(crate) const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+pub(crate) const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+/// Builds an IPv6 packet without extension headers originated by us.
+pub(crate) fn build_ipv6_packet(
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+    next_header: IpNextHeaderProtocol,
+    hop_limit: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut buffer = vec![0u8; IPV6_HEADER_LENGTH + payload.len()];
+    let mut packet = MutableIpv6Packet::new(&mut buffer).expect("buffer should be large enough");
+    packet.set_version(6);
+    packet.set_payload_length(payload.len() as u16);
+    packet.set_next_header(next_header);
+    packet.set_hop_limit(hop_limit);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    packet.set_payload(payload);
+    buffer
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn spawn_ipv6_handler(
+    interfaces: Vec<NetworkInterface>,
+    config: &Config,
+    vrfs: VrfAssignments,
+    neighbor_cache: Arc<RwLock<NeighborCache>>,
+    receiver: UnboundedReceiver<Ipv6HandlerEvent>,
+    sender_ndp: UnboundedSender<NdpHandlerEvent>,
+    sender_ethernet: UnboundedSender<EthernetHandlerEvent>,
+    sender_nat64: UnboundedSender<Nat64HandlerEvent>,
+) -> JoinHandle<()> {
+    Ipv6Handler::new(
+        interfaces,
+        config,
+        vrfs,
+        neighbor_cache,
+        receiver,
+        sender_ndp,
+        sender_ethernet,
+        sender_nat64,
+    )
+    .spawn()
+}
+
+#[derive(Debug)]
+pub(crate) enum Ipv6HandlerEvent {
+    ReceivedPacket {
+        /// The interface index (operating system specific) the packet arrived on.
+        interface_index: u32,
+        packet: Ipv6Packet<'static>,
+    },
+    /// An event let Ipv6Handler to send a packet originated by another handler to a neighbor or
+    /// multicast group on the link.
+    SendPacket {
+        interface_index: u32,
+        packet: Vec<u8>,
+    },
+    /// An event let Ipv6Handler to route a packet translated from IPv4 by Nat64Handler.
+    RoutePacket {
+        vrf: String,
+        packet: Vec<u8>,
+    },
+    Shutdown,
+}
+
+struct Ipv6Handler {
+    interfaces: Vec<NetworkInterface>,
+    /// Per-interface settings keyed by the interface index (operating system specific).
+    interface_configs: HashMap<u32, InterfaceConfig>,
+    icmp_error_limiter: IcmpErrorLimiter,
+    /// Our addresses keyed by the VRF name.
+    ipv6_addresses: HashMap<String, Vec<Ipv6Addr>>,
+    vrfs: VrfAssignments,
+    /// The routing tables keyed by the VRF name.
+    routing_tables: HashMap<String, Ipv6RoutingTable>,
+    neighbor_cache: Arc<RwLock<NeighborCache>>,
+    receiver: UnboundedReceiver<Ipv6HandlerEvent>,
+    sender_ndp: UnboundedSender<NdpHandlerEvent>,
+    sender_ethernet: UnboundedSender<EthernetHandlerEvent>,
+    sender_nat64: UnboundedSender<Nat64HandlerEvent>,
+    /// Packets to the NAT64 prefix are translated to IPv4, if NAT64 is enabled.
+    nat64_prefix: Option<Ipv6Network>,
+    drop_counters: DropCounters,
+}
+
+impl Ipv6Handler {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        interfaces: Vec<NetworkInterface>,
+        config: &Config,
+        vrfs: VrfAssignments,
+        neighbor_cache: Arc<RwLock<NeighborCache>>,
+        receiver: UnboundedReceiver<Ipv6HandlerEvent>,
+        sender_ndp: UnboundedSender<NdpHandlerEvent>,
+        sender_ethernet: UnboundedSender<EthernetHandlerEvent>,
+        sender_nat64: UnboundedSender<Nat64HandlerEvent>,
+    ) -> Self {
+        let mut ipv6_addresses: HashMap<String, Vec<Ipv6Addr>> = HashMap::new();
+        for i in &interfaces {
+            ipv6_addresses
+                .entry(vrfs.get(i.index).to_string())
+                .or_default()
+                .extend(Self::addresses(i));
+        }
+
+        let interface_configs = interfaces
+            .iter()
+            .map(|i| {
+                let interface_config = config.interfaces.get(&i.name).cloned().unwrap_or_default();
+                (i.index, interface_config)
+            })
+            .collect();
+
+        let routing_tables = vrfs
+            .names()
+            .into_iter()
+            .map(|vrf| {
+                let table =
+                    Ipv6RoutingTable::build(vrf, &vrfs.interfaces(vrf, &interfaces), config);
+                (vrf.to_string(), table)
+            })
+            .collect();
+
+        Ipv6Handler {
+            interfaces,
+            interface_configs,
+            icmp_error_limiter: IcmpErrorLimiter::new(
+                config.icmp_error_global_rate_limit,
+                config.icmp_error_per_destination_rate_limit,
+            ),
+            ipv6_addresses,
+            vrfs,
+            routing_tables,
+            neighbor_cache,
+            receiver,
+            sender_ndp,
+            sender_ethernet,
+            sender_nat64,
+            nat64_prefix: config.nat64.pool.map(|_| config.nat64.prefix),
+            drop_counters: DropCounters::default(),
+        }
+    }
+
+    fn handle_received_packet(&mut self, interface_index: u32, packet: Ipv6Packet<'static>) {
+        debug!(
+            "Received an IPv6 packet via {}: {:?}",
+            self.interface(interface_index).name,
+            packet
+        );
+
+        // Packets are routed only within the VRF of the ingress interface.
+        let vrf = &self.vrfs.get(interface_index).to_string();
+
+        let length = IPV6_HEADER_LENGTH + packet.get_payload_length() as usize;
+        if length > packet.packet().len() {
+            debug!(
+                "Dropped a packet whose payload length exceeds the frame: {:?}",
+                packet
+            );
+            self.drop_counters.increment(DropReason::MalformedHeader);
+            return;
+        }
+
+        let source = packet.get_source();
+        let destination = packet.get_destination();
+        if source.is_multicast()
+            || source.is_loopback()
+            || destination.is_unspecified()
+            || destination.is_loopback()
+        {
+            debug!("Dropped a packet with an invalid address: {:?}", packet);
+            self.drop_counters.increment(DropReason::InvalidAddress);
+            return;
+        }
+
+        if Self::is_neighbor_discovery(&packet) {
+            if let Err(e) = self.sender_ndp.send(NdpHandlerEvent::ReceivedPacket {
+                interface_index,
+                packet,
+            }) {
+                error!("Failed to send the NDP message to NdpHandler: {}", e);
+            }
+            return;
+        }
+
+        // We don't route multicasts.
+        if destination.is_multicast() || self.is_our_address(vrf, &destination) {
+            self.deliver_locally(vrf, interface_index, &packet, length);
+            return;
+        }
+
+        // The unspecified source is only used before a node has an address (RFC 4291 2.5.2).
+        if source.is_unspecified() {
+            debug!(
+                "Dropped a packet from the unspecified address: {:?}",
+                packet
+            );
+            self.drop_counters.increment(DropReason::InvalidAddress);
+            return;
+        }
+
+        // Link-local packets must not be forwarded (RFC 4291 2.5.6).
+        if ndp::is_link_local(&destination) || ndp::is_link_local(&source) {
+            debug!("Dropped a link-local packet to be forwarded: {:?}", packet);
+            self.drop_counters.increment(DropReason::LinkLocalScope);
+            if !ndp::is_link_local(&destination) {
+                self.send_icmpv6_error(
+                    vrf,
+                    interface_index,
+                    &packet,
+                    Icmpv6Error::beyond_scope_of_source_address(),
+                );
+            }
+            return;
+        }
+
+        if packet.get_hop_limit() <= 1 {
+            debug!("Dropped a packet whose hop limit is exceeded: {:?}", packet);
+            self.drop_counters.increment(DropReason::HopLimitExceeded);
+            self.send_icmpv6_error(vrf, interface_index, &packet, Icmpv6Error::time_exceeded());
+            return;
+        }
+
+        if self
+            .nat64_prefix
+            .is_some_and(|prefix| prefix.contains(destination))
+        {
+            if let Err(e) = self.sender_nat64.send(Nat64HandlerEvent::TranslateIpv6 {
+                vrf: vrf.to_string(),
+                packet: packet.packet()[..length].to_vec(),
+            }) {
+                error!("Failed to send the packet to Nat64Handler: {}", e);
+            }
+            return;
+        }
+
+        let next_hop = match self
+            .routing_tables
+            .get(vrf)
+            .and_then(|table| table.lookup(&destination))
+        {
+            Some(route) => route.next_hop.clone(),
+            None => {
+                debug!("No route to {}. Dropped the packet.", destination);
+                self.drop_counters.increment(DropReason::NoRoute);
+                self.send_icmpv6_error(vrf, interface_index, &packet, Icmpv6Error::no_route());
+                return;
+            }
+        };
+
+        // IPv6 routers never fragment (RFC 8200 5).
+        let mtu = self.interface_configs[&next_hop.interface_index].mtu;
+        if length > mtu as usize {
+            debug!("Dropped a packet larger than the MTU {}: {:?}", mtu, packet);
+            self.drop_counters.increment(DropReason::PacketTooBig);
+            self.send_icmpv6_error(
+                vrf,
+                interface_index,
+                &packet,
+                Icmpv6Error::packet_too_big(mtu),
+            );
+            return;
+        }
+
+        // Strip any link layer padding.
+        let mut buffer = packet.packet()[..length].to_vec();
+        MutableIpv6Packet::new(&mut buffer)
+            .expect("valid IPv6 packet")
+            .set_hop_limit(packet.get_hop_limit() - 1);
+        self.transmit(next_hop.interface_index, next_hop.gateway, buffer);
+    }
+
+    /// Delivers a packet addressed to us or to a multicast group on the link.
+    fn deliver_locally(
+        &mut self,
+        vrf: &str,
+        interface_index: u32,
+        packet: &Ipv6Packet,
+        length: usize,
+    ) {
+        let destination = packet.get_destination();
+        if destination.is_multicast() && !self.has_joined(interface_index, &destination) {
+            debug!(
+                "Ignored a packet to a multicast group we haven't joined: {:?}",
+                packet
+            );
+            return;
+        }
+
+        let buffer = &packet.packet()[..length];
+        let upper_layer = match ipv6_extension_headers::process(buffer, destination.is_multicast())
+        {
+            Ok(upper_layer) => upper_layer,
+            Err(ExtensionHeaderError::Fragmented) => {
+                // TODO: Reassemble the fragments.
+                debug!("Dropped a fragment addressed to us: {:?}", packet);
+                self.drop_counters.increment(DropReason::Fragmented);
+                return;
+            }
+            Err(ExtensionHeaderError::Discard) => {
+                debug!(
+                    "Discarded a packet while processing its extension headers: {:?}",
+                    packet
+                );
+                self.drop_counters.increment(DropReason::ExtensionHeader);
+                return;
+            }
+            Err(ExtensionHeaderError::ParameterProblem { code, pointer }) => {
+                debug!(
+                    "Dropped a packet with a problem in its extension headers at {}: {:?}",
+                    pointer, packet
+                );
+                self.drop_counters.increment(DropReason::ExtensionHeader);
+                self.send_icmpv6_error(
+                    vrf,
+                    interface_index,
+                    packet,
+                    Icmpv6Error::parameter_problem(code, pointer),
+                );
+                return;
+            }
+        };
+
+        match upper_layer.protocol {
+            IpNextHeaderProtocols::Icmpv6 => {
+                self.handle_icmpv6(vrf, interface_index, packet, &buffer[upper_layer.offset..])
+            }
+            IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp => {
+                debug!("No listener for the packet addressed to us: {:?}", packet);
+                self.drop_counters
+                    .increment(DropReason::UnsupportedProtocol);
+                self.send_icmpv6_error(
+                    vrf,
+                    interface_index,
+                    packet,
+                    Icmpv6Error::port_unreachable(),
+                );
+            }
+            protocol => {
+                debug!(
+                    "Dropped a packet addressed to us for the unsupported protocol {}: {:?}",
+                    protocol, packet
+                );
+                self.drop_counters
+                    .increment(DropReason::UnsupportedProtocol);
+                self.send_icmpv6_error(
+                    vrf,
+                    interface_index,
+                    packet,
+                    Icmpv6Error::parameter_problem(
+                        UNRECOGNIZED_NEXT_HEADER,
+                        upper_layer.next_header_field as u32,
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Handles an ICMPv6 message addressed to us, other than the Neighbor Discovery messages
+    /// handled by NdpHandler.
+    fn handle_icmpv6(
+        &mut self,
+        vrf: &str,
+        interface_index: u32,
+        packet: &Ipv6Packet,
+        message: &[u8],
+    ) {
+        let icmpv6 = match Icmpv6Packet::new(message) {
+            Some(icmpv6) => icmpv6,
+            None => {
+                debug!("Dropped a truncated ICMPv6 message: {:?}", packet);
+                self.drop_counters.increment(DropReason::MalformedHeader);
+                return;
+            }
+        };
+        let checksum =
+            pnet_packet::icmpv6::checksum(&icmpv6, &packet.get_source(), &packet.get_destination());
+        if checksum != icmpv6.get_checksum() {
+            debug!(
+                "Dropped an ICMPv6 message with a bad checksum: {:?}",
+                packet
+            );
+            self.drop_counters.increment(DropReason::MalformedHeader);
+            return;
+        }
+
+        match icmpv6.get_icmpv6_type() {
+            Icmpv6Types::EchoRequest => self.send_echo_reply(vrf, interface_index, packet, message),
+            // Errors, and informational messages we have no use for.
+            icmpv6_type => debug!(
+                "Ignored an ICMPv6 message of type {:?} addressed to us",
+                icmpv6_type
+            ),
+        }
+    }
+
+    /// Answers an Echo Request with the same identifier, sequence number and data (RFC 4443 4.2).
+    fn send_echo_reply(
+        &self,
+        vrf: &str,
+        interface_index: u32,
+        packet: &Ipv6Packet,
+        request: &[u8],
+    ) {
+        let requester = packet.get_source();
+        if requester.is_unspecified() {
+            return;
+        }
+
+        // Replies to multicast requests come from a unicast address of the interface.
+        let destination = packet.get_destination();
+        let source = if destination.is_multicast() {
+            match Self::source_address_for(self.interface(interface_index), &requester) {
+                Some(source) => source,
+                None => {
+                    debug!(
+                        "No IPv6 address to send an Echo Reply via {}",
+                        self.interface(interface_index).name
+                    );
+                    return;
+                }
+            }
+        } else {
+            destination
+        };
+
+        let mut message = request.to_vec();
+        let mut reply = MutableIcmpv6Packet::new(&mut message).expect("valid ICMPv6 message");
+        reply.set_icmpv6_type(Icmpv6Types::EchoReply);
+        icmpv6::update_checksum(&mut reply, &source, &requester);
+
+        let reply = build_ipv6_packet(
+            source,
+            requester,
+            IpNextHeaderProtocols::Icmpv6,
+            DEFAULT_HOP_LIMIT,
+            &message,
+        );
+        self.reply(vrf, interface_index, reply);
+    }
+
+    /// Sends an ICMPv6 error about the packet back to its source.
+    fn send_icmpv6_error(
+        &mut self,
+        vrf: &str,
+        interface_index: u32,
+        original: &Ipv6Packet,
+        error: Icmpv6Error,
+    ) {
+        if !icmpv6::may_send_error_about(original, &error) {
+            return;
+        }
+        let destination = original.get_source();
+        if !self.icmp_error_limiter.try_acquire(destination.into()) {
+            debug!(
+                "Suppressed an ICMPv6 error to {} by the rate limit",
+                destination
+            );
+            return;
+        }
+
+        let source = match Self::source_address_for(self.interface(interface_index), &destination) {
+            Some(source) => source,
+            None => {
+                debug!(
+                    "No IPv6 address to send an ICMPv6 error via {}",
+                    self.interface(interface_index).name
+                );
+                return;
+            }
+        };
+
+        let packet = icmpv6::build_error_packet(&error, source, original);
+        self.reply(vrf, interface_index, packet);
+    }
+
+    /// Sends our reply to a packet which arrived on the interface. Link-local destinations are
+    /// only reachable via that interface, others are routed.
+    fn reply(&self, vrf: &str, interface_index: u32, packet: Vec<u8>) {
+        let destination = Ipv6Packet::new(&packet)
+            .expect("should be a valid IPv6 packet")
+            .get_destination();
+        if ndp::is_link_local(&destination) {
+            self.transmit(interface_index, None, packet);
+        } else {
+            self.send(vrf, packet);
+        }
+    }
+
+    /// Routes and sends a packet translated from IPv4 by NAT64. Packets larger than the MTU of
+    /// the egress interface are answered with Packet Too Big, which the translator turns into
+    /// Fragmentation Needed for the IPv4 source (RFC 7915 4.1).
+    fn route_translated(&mut self, vrf: &str, packet: Vec<u8>) {
+        let ipv6 = Ipv6Packet::new(&packet).expect("should be a valid IPv6 packet");
+        let destination = ipv6.get_destination();
+        let next_hop = match self
+            .routing_tables
+            .get(vrf)
+            .and_then(|table| table.lookup(&destination))
+        {
+            Some(route) => route.next_hop.clone(),
+            None => {
+                debug!(
+                    "No route to {}. Dropped the translated packet.",
+                    destination
+                );
+                self.drop_counters.increment(DropReason::NoRoute);
+                return;
+            }
+        };
+
+        let mtu = self.interface_configs[&next_hop.interface_index].mtu;
+        if packet.len() > mtu as usize {
+            debug!("Dropped a packet larger than the MTU {}: {:?}", mtu, ipv6);
+            self.drop_counters.increment(DropReason::PacketTooBig);
+            self.send_icmpv6_error(
+                vrf,
+                next_hop.interface_index,
+                &ipv6,
+                Icmpv6Error::packet_too_big(mtu),
+            );
+            return;
+        }
+
+        self.transmit(next_hop.interface_index, next_hop.gateway, packet);
+    }
+
+    /// Routes and sends a packet originated by us. Packets to the NAT64 prefix, e.g. ICMPv6
+    /// errors about translated packets, are handed to the translator.
+    fn send(&self, vrf: &str, packet: Vec<u8>) {
+        let destination = Ipv6Packet::new(&packet)
+            .expect("should be a valid IPv6 packet")
+            .get_destination();
+        if self
+            .nat64_prefix
+            .is_some_and(|prefix| prefix.contains(destination))
+        {
+            if let Err(e) = self.sender_nat64.send(Nat64HandlerEvent::TranslateIpv6 {
+                vrf: vrf.to_string(),
+                packet,
+            }) {
+                error!("Failed to send the packet to Nat64Handler: {}", e);
+            }
+            return;
+        }
+
+        match self
+            .routing_tables
+            .get(vrf)
+            .and_then(|table| table.lookup(&destination))
+        {
+            Some(route) => {
+                let next_hop = route.next_hop.clone();
+                self.transmit(next_hop.interface_index, next_hop.gateway, packet);
+            }
+            None => debug!("No route to {}. Dropped our packet.", destination),
+        }
+    }
+
+    fn is_neighbor_discovery(packet: &Ipv6Packet) -> bool {
+        packet.get_next_header() == IpNextHeaderProtocols::Icmpv6
+            && Icmpv6Packet::new(packet.payload()).is_some_and(|icmpv6| {
+                matches!(
+                    icmpv6.get_icmpv6_type(),
+                    Icmpv6Types::NeighborSolicit
+                        | Icmpv6Types::NeighborAdvert
+                        | Icmpv6Types::RouterSolicit
+                )
+            })
+    }
+
+    /// Sends the packet to the neighbor on the link of the interface, which is the destination
+    /// unless a gateway is given. Packets to multicast destinations are sent to the mapped
+    /// Ethernet address.
+    fn transmit(&self, interface_index: u32, gateway: Option<Ipv6Addr>, packet: Vec<u8>) {
+        let destination = Ipv6Packet::new(&packet)
+            .expect("should be a valid IPv6 packet")
+            .get_destination();
+        if destination.is_multicast() {
+            self.send_frame(interface_index, ndp::multicast_mac(&destination), packet);
+            return;
+        }
+
+        let neighbor_address = gateway.unwrap_or(destination);
+        let (mac, state) = match self
+            .neighbor_cache
+            .read()
+            .expect("read guard")
+            .get(interface_index, &neighbor_address)
+        {
+            Some(neighbor) => (neighbor.mac, Some(neighbor.state)),
+            None => (None, None),
+        };
+
+        // Resolve unknown neighbors, and verify stale ones.
+        if matches!(state, None | Some(NeighborState::Stale)) {
+            if let Err(e) = self.sender_ndp.send(NdpHandlerEvent::Resolve {
+                interface_index,
+                address: neighbor_address,
+            }) {
+                error!("Failed to send the resolve event to NdpHandler: {}", e);
+            }
+        }
+
+        match mac {
+            Some(mac) => self.send_frame(interface_index, mac, packet),
+            // TODO: Queue the packet until the neighbor is resolved.
+            None => debug!(
+                "Dropped a packet to the unresolved neighbor: {}",
+                neighbor_address
+            ),
+        }
+    }
+
+    fn send_frame(&self, interface_index: u32, destination: MacAddr, packet: Vec<u8>) {
+        if let Err(e) = self
+            .sender_ethernet
+            .send(EthernetHandlerEvent::SendFrame(OutgoingFrame {
+                interface_index,
+                destination,
+                ethertype: ETHERNET_TYPE_IPV6,
+                payload: packet,
+            }))
+        {
+            error!("Failed to send the frame to EthernetHandler: {}", e);
+        }
+    }
+
+    fn interface(&self, interface_index: u32) -> &NetworkInterface {
+        self.interfaces
+            .iter()
+            .find(|i| i.index == interface_index)
+            .expect("should have the network interface")
+    }
+
+    fn addresses(interface: &NetworkInterface) -> impl Iterator<Item = Ipv6Addr> + Clone + '_ {
+        interface.ips.iter().filter_map(|ipn| match ipn {
+            IpNetwork::V4(_) => None,
+            IpNetwork::V6(ipv6n) => Some(ipv6n.ip()),
+        })
+    }
+
+    /// Picks the address of the interface with the same scope as the target, preferring one on
+    /// the same subnet.
+    fn source_address_for(interface: &NetworkInterface, target: &Ipv6Addr) -> Option<Ipv6Addr> {
+        if ndp::is_link_local(target) {
+            return Self::addresses(interface).find(ndp::is_link_local);
+        }
+
+        interface
+            .ips
+            .iter()
+            .find_map(|ipn| match ipn {
+                IpNetwork::V6(ipv6n) if ipv6n.contains(*target) => Some(ipv6n.ip()),
+                _ => None,
+            })
+            .or_else(|| Self::addresses(interface).find(|a| !ndp::is_link_local(a)))
+    }
+
+    /// Whether we have joined the multicast group on the interface: the all-nodes and all-routers
+    /// groups, and the solicited-node groups of our addresses.
+    fn has_joined(&self, interface_index: u32, group: &Ipv6Addr) -> bool {
+        *group == ALL_NODES
+            || *group == ALL_ROUTERS
+            || Self::addresses(self.interface(interface_index))
+                .any(|a| ndp::solicited_node_multicast(&a) == *group)
+    }
+
+    fn is_our_address(&self, vrf: &str, address: &Ipv6Addr) -> bool {
+        self.ipv6_addresses
+            .get(vrf)
+            .into_iter()
+            .flatten()
+            .any(|a| a == address)
+    }
+
+    fn spawn(mut self) -> JoinHandle<()> {
+        let fut = async move {
+            debug!("Started Ipv6Handler");
+
+            loop {
+                if let Some(event) = self.receiver.recv().await {
+                    match event {
+                        Ipv6HandlerEvent::ReceivedPacket {
+                            interface_index,
+                            packet,
+                        } => self.handle_received_packet(interface_index, packet),
+                        Ipv6HandlerEvent::SendPacket {
+                            interface_index,
+                            packet,
+                        } => self.transmit(interface_index, None, packet),
+                        Ipv6HandlerEvent::RoutePacket { vrf, packet } => {
+                            self.route_translated(&vrf, packet)
+                        }
+                        Ipv6HandlerEvent::Shutdown => {
+                            self.drop_counters.log("Ipv6Handler");
+                            self.icmp_error_limiter.log("Ipv6Handler");
+                            return;
+                        }
+                    }
+                }
+            }
+        };
+
+        tokio::runtime::Handle::current().spawn(fut)
+    }
+}
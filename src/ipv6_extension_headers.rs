@@ -0,0 +1,184 @@
+use crate::ipv6::IPV6_HEADER_LENGTH;
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+/// The offset of the Next Header field in the fixed header.
+const NEXT_HEADER_OFFSET: usize = 6;
+
+/// The length of the Fragment header, which has no length field.
+const FRAGMENT_HEADER_LENGTH: usize = 8;
+
+const OPTION_PAD1: u8 = 0;
+const OPTION_PADN: u8 = 1;
+const OPTION_ROUTER_ALERT: u8 = 5;
+
+/// Parameter Problem codes (RFC 4443 3.4).
+pub(crate) const ERRONEOUS_HEADER_FIELD: u8 = 0;
+pub(crate) const UNRECOGNIZED_NEXT_HEADER: u8 = 1;
+pub(crate) const UNRECOGNIZED_OPTION: u8 = 2;
+
+/// The upper-layer header found at the end of the extension header chain.
+#[derive(Debug)]
+pub(crate) struct UpperLayer {
+    pub(crate) protocol: IpNextHeaderProtocol,
+    /// The offset of the upper-layer header from the start of the packet.
+    pub(crate) offset: usize,
+    /// The offset of the Next Header field naming the protocol, to be reported by ICMPv6
+    /// Parameter Problem if we don't support the protocol.
+    pub(crate) next_header_field: usize,
+    /// Whether the packet is the first fragment of a larger one.
+    pub(crate) fragmented: bool,
+}
+
+/// Why a packet addressed to us is discarded while processing its extension headers.
+#[derive(Debug)]
+pub(crate) enum ExtensionHeaderError {
+    /// Truncated, or an option asks to discard the packet silently.
+    Discard,
+    /// Report by ICMPv6 Parameter Problem. `pointer` is the offset of the offending octet.
+    ParameterProblem { code: u8, pointer: u32 },
+    /// We don't reassemble fragments.
+    Fragmented,
+}
+
+/// Processes the extension headers of a packet addressed to us (RFC 8200 4) and returns the
+/// upper-layer header. `multicast` tells whether the destination is a multicast address, which
+/// changes how some unrecognized options are handled.
+pub(crate) fn process(packet: &[u8], multicast: bool) -> Result<UpperLayer, ExtensionHeaderError> {
+    walk(packet, Some(multicast))
+}
+
+/// Skips the extension headers to find the upper-layer header, without processing them.
+/// Returns `None` if the chain is truncated or the packet is a non-initial fragment.
+pub(crate) fn upper_layer(packet: &[u8]) -> Option<UpperLayer> {
+    walk(packet, None).ok()
+}
+
+/// Walks the chain, processing the options and the routing header unless `multicast` is `None`.
+fn walk(packet: &[u8], multicast: Option<bool>) -> Result<UpperLayer, ExtensionHeaderError> {
+    let mut next_header_field = NEXT_HEADER_OFFSET;
+    let mut offset = IPV6_HEADER_LENGTH;
+    let mut fragmented = false;
+
+    loop {
+        let next_header = IpNextHeaderProtocol(
+            *packet
+                .get(next_header_field)
+                .ok_or(ExtensionHeaderError::Discard)?,
+        );
+
+        let length = match next_header {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Opts
+            | IpNextHeaderProtocols::Ipv6Route => {
+                let length = (*packet
+                    .get(offset + 1)
+                    .ok_or(ExtensionHeaderError::Discard)? as usize
+                    + 1)
+                    * 8;
+                if offset + length > packet.len() {
+                    return Err(ExtensionHeaderError::Discard);
+                }
+                length
+            }
+            IpNextHeaderProtocols::Ipv6Frag if multicast.is_some() => {
+                return Err(ExtensionHeaderError::Fragmented)
+            }
+            // Only the first fragment carries the upper-layer header.
+            IpNextHeaderProtocols::Ipv6Frag => match packet.get(offset + 2..offset + 4) {
+                Some(&[high, low]) if u16::from_be_bytes([high, low]) >> 3 == 0 => {
+                    fragmented = true;
+                    FRAGMENT_HEADER_LENGTH
+                }
+                _ => return Err(ExtensionHeaderError::Discard),
+            },
+            IpNextHeaderProtocols::Ipv6NoNxt => return Err(ExtensionHeaderError::Discard),
+            protocol => {
+                return Ok(UpperLayer {
+                    protocol,
+                    offset,
+                    next_header_field,
+                    fragmented,
+                })
+            }
+        };
+
+        if let Some(multicast) = multicast {
+            match next_header {
+                // The Hop-by-Hop Options header is only allowed right after the fixed header.
+                IpNextHeaderProtocols::Hopopt if offset != IPV6_HEADER_LENGTH => {
+                    return Err(ExtensionHeaderError::ParameterProblem {
+                        code: UNRECOGNIZED_NEXT_HEADER,
+                        pointer: next_header_field as u32,
+                    });
+                }
+                IpNextHeaderProtocols::Hopopt | IpNextHeaderProtocols::Ipv6Opts => {
+                    process_options(packet, offset, length, multicast)?
+                }
+                _ => process_routing_header(packet, offset)?,
+            }
+        }
+
+        next_header_field = offset;
+        offset += length;
+    }
+}
+
+/// Processes the options of a Hop-by-Hop or Destination Options header.
+fn process_options(
+    packet: &[u8],
+    offset: usize,
+    length: usize,
+    multicast: bool,
+) -> Result<(), ExtensionHeaderError> {
+    let end = offset + length;
+    let mut option = offset + 2;
+
+    while option < end {
+        let option_type = packet[option];
+        if option_type == OPTION_PAD1 {
+            option += 1;
+            continue;
+        }
+
+        let option_length = match packet.get(option + 1) {
+            Some(&l) if option + 2 + l as usize <= end => l as usize,
+            _ => return Err(ExtensionHeaderError::Discard),
+        };
+
+        match option_type {
+            OPTION_PADN | OPTION_ROUTER_ALERT => {}
+            // The two high-order bits of the type tell what to do with an unrecognized option.
+            _ => match option_type >> 6 {
+                0 => {}
+                1 => return Err(ExtensionHeaderError::Discard),
+                3 if multicast => return Err(ExtensionHeaderError::Discard),
+                _ => {
+                    return Err(ExtensionHeaderError::ParameterProblem {
+                        code: UNRECOGNIZED_OPTION,
+                        pointer: option as u32,
+                    })
+                }
+            },
+        }
+
+        option += 2 + option_length;
+    }
+
+    Ok(())
+}
+
+/// We support no routing types, so a Routing header with segments left must be reported
+/// (RFC 8200 4.4). Type 0 is deprecated (RFC 5095).
+fn process_routing_header(packet: &[u8], offset: usize) -> Result<(), ExtensionHeaderError> {
+    let segments_left = *packet
+        .get(offset + 3)
+        .ok_or(ExtensionHeaderError::Discard)?;
+    if segments_left == 0 {
+        return Ok(());
+    }
+
+    Err(ExtensionHeaderError::ParameterProblem {
+        code: ERRONEOUS_HEADER_FIELD,
+        pointer: (offset + 2) as u32,
+    })
+}
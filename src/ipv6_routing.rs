@@ -0,0 +1,103 @@
+use crate::config::Config;
+use crate::ndp;
+use ipnetwork::{IpNetwork, Ipv6Network};
+use pnet_datalink::NetworkInterface;
+use std::net::Ipv6Addr;
+use tracing::{debug, error};
+
+/// IPv6 routing table. Lookups are longest-prefix-match.
+pub(crate) struct Ipv6RoutingTable {
+    /// Routes sorted by prefix length in descending order so that the first match is the longest.
+    routes: Vec<Ipv6Route>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Ipv6Route {
+    pub(crate) destination: Ipv6Network,
+    pub(crate) next_hop: Ipv6NextHop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Ipv6NextHop {
+    /// `None` if the destination is directly connected to the interface.
+    pub(crate) gateway: Option<Ipv6Addr>,
+    /// The interface index (operating system specific).
+    pub(crate) interface_index: u32,
+}
+
+impl Ipv6RoutingTable {
+    /// Builds the routing table of the VRF from the connected routes of its interfaces and the
+    /// static routes. Link-local prefixes are left out as they are never forwarded.
+    pub(crate) fn build(vrf: &str, interfaces: &[NetworkInterface], config: &Config) -> Self {
+        let mut table = Ipv6RoutingTable { routes: vec![] };
+
+        for i in interfaces {
+            for ipn in &i.ips {
+                if let IpNetwork::V6(ipv6n) = ipn {
+                    if ndp::is_link_local(&ipv6n.ip()) {
+                        continue;
+                    }
+                    table.add(Ipv6Route {
+                        destination: Ipv6Network::new(ipv6n.network(), ipv6n.prefix())
+                            .expect("valid prefix"),
+                        next_hop: Ipv6NextHop {
+                            gateway: None,
+                            interface_index: i.index,
+                        },
+                    });
+                }
+            }
+        }
+
+        for static_route in config.ipv6_routes.iter().filter(|r| r.vrf == vrf) {
+            let interface_index = match &static_route.interface {
+                Some(name) => interfaces.iter().find(|i| &i.name == name).map(|i| i.index),
+                None => table.resolve_interface(&static_route.gateway),
+            };
+
+            match interface_index {
+                Some(interface_index) => table.add(Ipv6Route {
+                    destination: static_route.destination,
+                    next_hop: Ipv6NextHop {
+                        gateway: Some(static_route.gateway),
+                        interface_index,
+                    },
+                }),
+                None => error!(
+                    "Could not resolve the interface of the next hop {} for the route {}",
+                    static_route.gateway, static_route.destination
+                ),
+            }
+        }
+
+        table
+    }
+
+    fn add(&mut self, route: Ipv6Route) {
+        debug!("Added an IPv6 route: {:?}", route);
+
+        // Insert after any route with the same or a longer prefix, so that earlier routes win ties.
+        let position = self
+            .routes
+            .iter()
+            .position(|r| r.destination.prefix() < route.destination.prefix())
+            .unwrap_or(self.routes.len());
+        self.routes.insert(position, route);
+    }
+
+    pub(crate) fn lookup(&self, destination: &Ipv6Addr) -> Option<&Ipv6Route> {
+        self.routes
+            .iter()
+            .find(|r| r.destination.contains(*destination))
+    }
+
+    /// Returns the interface of the connected route covering the address.
+    fn resolve_interface(&self, address: &Ipv6Addr) -> Option<u32> {
+        self.routes
+            .iter()
+            .filter(|r| r.destination.contains(*address))
+            .map(|r| &r.next_hop)
+            .find(|nh| nh.gateway.is_none())
+            .map(|nh| nh.interface_index)
+    }
+}
@@ -1,10 +1,38 @@
+mod acl;
 mod arp;
+mod checksum;
+mod config;
+mod conntrack;
+mod control_plane;
+mod counters;
 mod ethernet;
+mod firewall;
+mod icmp;
+mod icmpv6;
 mod ipv4;
+mod ipv4_options;
+mod ipv6;
+mod ipv6_extension_headers;
+mod ipv6_routing;
+mod martian;
+mod nat;
+mod nat64;
+mod ndp;
+mod rate_limit;
+mod router_advertisement;
+mod routing;
+mod tcp_mss;
+mod vrf;
 
 use crate::arp::{spawn_arp_handler, ArpHandlerEvent, ArpTable};
+use crate::config::Config;
+use crate::control_plane::{spawn_control_plane_handler, ControlPlaneEvent};
 use crate::ethernet::{spawn_ethernet_handler, EthernetHandlerEvent};
 use crate::ipv4::{spawn_ipv4_handler, Ipv4HandlerEvent};
+use crate::ipv6::{spawn_ipv6_handler, Ipv6HandlerEvent};
+use crate::nat64::{spawn_nat64_handler, Nat64HandlerEvent};
+use crate::ndp::{spawn_ndp_handler, NdpHandlerEvent, NeighborCache};
+use crate::vrf::VrfAssignments;
 use pnet_datalink::NetworkInterface;
 use std::future::Future;
 use std::pin::Pin;
@@ -47,10 +75,29 @@ async fn main() {
         info!("* {:?}", i);
     }
 
+    // The path to the config file can be given as the first argument.
+    let config = match std::env::args().nth(1) {
+        Some(path) => match Config::load(&path) {
+            Ok(config) => config,
+            Err(e) => panic!("An error occurred when loading the config: {}", e),
+        },
+        None => Config::default(),
+    };
+    if let Err(e) = config.check_interfaces(&interfaces) {
+        panic!("An error occurred when loading the config: {}", e);
+    }
+
+    let vrfs = VrfAssignments::new(&interfaces, &config.interfaces);
+
     let arp_table = Arc::new(RwLock::new(ArpTable::new()));
+    let neighbor_cache = Arc::new(RwLock::new(NeighborCache::new()));
     let (sender_ethernet, receiver_ethernet) = tokio::sync::mpsc::unbounded_channel();
     let (sender_arp, receiver_arp) = tokio::sync::mpsc::unbounded_channel();
     let (sender_ipv4, receiver_ipv4) = tokio::sync::mpsc::unbounded_channel();
+    let (sender_ipv6, receiver_ipv6) = tokio::sync::mpsc::unbounded_channel();
+    let (sender_ndp, receiver_ndp) = tokio::sync::mpsc::unbounded_channel();
+    let (sender_nat64, receiver_nat64) = tokio::sync::mpsc::unbounded_channel();
+    let (sender_control_plane, receiver_control_plane) = tokio::sync::mpsc::unbounded_channel();
 
     // Spawn packet handlers.
     let jh_ethernet = spawn_ethernet_handler(
@@ -58,16 +105,50 @@ async fn main() {
         receiver_ethernet,
         sender_arp.clone(),
         sender_ipv4.clone(),
+        sender_ipv6.clone(),
     )
     .await;
-    let jh_arp = spawn_arp_handler(&interfaces, arp_table.clone(), receiver_arp).await;
+    let jh_arp =
+        spawn_arp_handler(&interfaces, vrfs.clone(), arp_table.clone(), receiver_arp).await;
     let jh_ipv4 = spawn_ipv4_handler(
         interfaces.clone(),
+        &config,
+        vrfs.clone(),
         arp_table.clone(),
         receiver_ipv4,
         sender_arp.clone(),
+        sender_ethernet.clone(),
+        sender_nat64.clone(),
+        sender_control_plane.clone(),
+    )
+    .await;
+    let jh_ipv6 = spawn_ipv6_handler(
+        interfaces.clone(),
+        &config,
+        vrfs,
+        neighbor_cache.clone(),
+        receiver_ipv6,
+        sender_ndp.clone(),
+        sender_ethernet.clone(),
+        sender_nat64.clone(),
+    )
+    .await;
+    let jh_ndp = spawn_ndp_handler(
+        &interfaces,
+        &config,
+        neighbor_cache.clone(),
+        receiver_ndp,
+        sender_ipv6.clone(),
     )
     .await;
+    let jh_nat64 = spawn_nat64_handler(
+        &config,
+        receiver_nat64,
+        sender_ipv4.clone(),
+        sender_ipv6.clone(),
+    )
+    .await;
+    let jh_control_plane = spawn_control_plane_handler(receiver_control_plane).await;
 
     // Block the current thread until a shutdown signal is received.
     let message = tokio::runtime::Handle::current()
@@ -90,7 +171,7 @@ async fn main() {
                 Err(e) => error!("Could not register SIGINT handler: {}", e),
             }
 
-            futures_util::future::select_all(handles.into_iter()).await
+            futures_util::future::select_all(handles).await
         })
         .await
         .unwrap();
@@ -105,6 +186,12 @@ async fn main() {
         .unwrap();
     sender_arp.send(ArpHandlerEvent::Shutdown).unwrap();
     sender_ipv4.send(Ipv4HandlerEvent::Shutdown).unwrap();
+    sender_ipv6.send(Ipv6HandlerEvent::Shutdown).unwrap();
+    sender_ndp.send(NdpHandlerEvent::Shutdown).unwrap();
+    sender_nat64.send(Nat64HandlerEvent::Shutdown).unwrap();
+    sender_control_plane
+        .send(ControlPlaneEvent::Shutdown)
+        .unwrap();
 
     macro_rules! log_if_error {
         ($result:expr) => {
@@ -113,10 +200,22 @@ async fn main() {
             }
         };
     }
-    let (eth, arp, ipv4) = futures_util::join!(jh_ethernet, jh_arp, jh_ipv4);
+    let (eth, arp, ipv4, ipv6, ndp, nat64, control_plane) = futures_util::join!(
+        jh_ethernet,
+        jh_arp,
+        jh_ipv4,
+        jh_ipv6,
+        jh_ndp,
+        jh_nat64,
+        jh_control_plane
+    );
     log_if_error!(eth);
     log_if_error!(arp);
     log_if_error!(ipv4);
+    log_if_error!(ipv6);
+    log_if_error!(ndp);
+    log_if_error!(nat64);
+    log_if_error!(control_plane);
 
     info!("Done.");
 }
@@ -0,0 +1,93 @@
+use ipnetwork::Ipv4Network;
+use std::net::Ipv4Addr;
+
+/// Reasons an address is considered martian (RFC 1812 5.3.7) or bogon.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum MartianReason {
+    /// 127.0.0.0/8 as the source.
+    LoopbackSource,
+    /// 127.0.0.0/8 as the destination.
+    LoopbackDestination,
+    /// 0.0.0.0/8 as the source.
+    ThisNetworkSource,
+    /// 0.0.0.0/8 as the destination.
+    ThisNetworkDestination,
+    /// 224.0.0.0/4 as the source.
+    MulticastSource,
+    /// 240.0.0.0/4 as the source.
+    ClassESource,
+    /// 240.0.0.0/4, other than the limited broadcast, as the destination.
+    ClassEDestination,
+    /// 255.255.255.255 as the source.
+    LimitedBroadcastSource,
+    /// 169.254.0.0/16 as the destination of a packet to be forwarded (RFC 3927 7).
+    LinkLocalDestination,
+    /// The source is in the bogon list.
+    BogonSource,
+    /// The destination is in the bogon list.
+    BogonDestination,
+}
+
+/// Checks the addresses of a received packet against the martian addresses, except for the
+/// checks which apply only to forwarded packets.
+pub(crate) fn check_received(source: Ipv4Addr, destination: Ipv4Addr) -> Option<MartianReason> {
+    if source.is_loopback() {
+        Some(MartianReason::LoopbackSource)
+    } else if is_this_network(&source) && !(source.is_unspecified() && destination.is_broadcast()) {
+        // 0.0.0.0 is allowed as the source of an initialization procedure such as DHCP.
+        Some(MartianReason::ThisNetworkSource)
+    } else if source.is_multicast() {
+        Some(MartianReason::MulticastSource)
+    } else if source.is_broadcast() {
+        Some(MartianReason::LimitedBroadcastSource)
+    } else if is_class_e(&source) {
+        Some(MartianReason::ClassESource)
+    } else if destination.is_loopback() {
+        Some(MartianReason::LoopbackDestination)
+    } else if is_this_network(&destination) {
+        Some(MartianReason::ThisNetworkDestination)
+    } else if is_class_e(&destination) && !destination.is_broadcast() {
+        Some(MartianReason::ClassEDestination)
+    } else {
+        None
+    }
+}
+
+/// Checks the destination of a packet to be forwarded.
+pub(crate) fn check_forwarded(destination: Ipv4Addr) -> Option<MartianReason> {
+    if destination.is_link_local() {
+        Some(MartianReason::LinkLocalDestination)
+    } else {
+        None
+    }
+}
+
+/// A configurable list of prefixes which shouldn't appear on the selected interfaces.
+#[derive(Debug, Default)]
+pub(crate) struct BogonList {
+    prefixes: Vec<Ipv4Network>,
+}
+
+impl BogonList {
+    pub(crate) fn new(prefixes: Vec<Ipv4Network>) -> Self {
+        BogonList { prefixes }
+    }
+
+    pub(crate) fn check(&self, source: Ipv4Addr, destination: Ipv4Addr) -> Option<MartianReason> {
+        if self.prefixes.iter().any(|p| p.contains(source)) {
+            Some(MartianReason::BogonSource)
+        } else if self.prefixes.iter().any(|p| p.contains(destination)) {
+            Some(MartianReason::BogonDestination)
+        } else {
+            None
+        }
+    }
+}
+
+fn is_this_network(address: &Ipv4Addr) -> bool {
+    address.octets()[0] == 0
+}
+
+fn is_class_e(address: &Ipv4Addr) -> bool {
+    address.octets()[0] >= 240
+}
@@ -0,0 +1,434 @@
+use crate::checksum;
+use crate::config::{Config, DestinationNatRule, SourceNatRule};
+use crate::conntrack::{ConnectionTable, Endpoint, Tuple};
+use crate::counters::DropReason;
+use crate::icmp::{self, ICMP_HEADER_LENGTH};
+use pnet_packet::icmp::{IcmpPacket, MutableIcmpPacket};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::Packet;
+use std::net::Ipv4Addr;
+
+/// Ports and ICMP identifiers are allocated above the well-known ports when the original one is
+/// taken.
+const FIRST_PORT: u16 = 1024;
+
+const IPV4_CHECKSUM_OFFSET: usize = 10;
+const IPV4_SOURCE_OFFSET: usize = 12;
+const IPV4_DESTINATION_OFFSET: usize = 16;
+const TCP_CHECKSUM_OFFSET: usize = 16;
+const UDP_CHECKSUM_OFFSET: usize = 6;
+const ICMP_CHECKSUM_OFFSET: usize = 2;
+const ICMP_IDENTIFIER_OFFSET: usize = 4;
+
+/// Network address translation rules of IPv4. The translations are kept by the connections
+/// of the connection table, so that replies are translated back.
+pub(crate) struct Nat {
+    source_rules: Vec<SourceNatRule>,
+    destination_rules: Vec<DestinationNatRule>,
+}
+
+impl Nat {
+    pub(crate) fn new(config: &Config) -> Self {
+        Nat {
+            source_rules: config.source_nat.clone(),
+            destination_rules: config.destination_nat.clone(),
+        }
+    }
+
+    /// Translates the destination of a new connection to a forwarded port. Returns the tuple to
+    /// rewrite the packet to.
+    pub(crate) fn destination_nat(&self, tuple: &Tuple) -> Option<Tuple> {
+        let rule = self.destination_rules.iter().find(|r| {
+            r.protocol == tuple.protocol.0
+                && *r.destination.ip() == tuple.destination.0
+                && r.destination.port() == tuple.destination.1
+        })?;
+
+        Some(Tuple {
+            protocol: tuple.protocol,
+            source: tuple.source,
+            destination: (*rule.target.ip(), rule.target.port()),
+        })
+    }
+
+    /// Translates the source of a new connection leaving via the interface if a rule matches.
+    /// `interface_address` is the address masqueraded behind. Returns the tuple to rewrite the
+    /// packet to.
+    pub(crate) fn source_nat(
+        &self,
+        conntrack: &ConnectionTable,
+        tuple: &Tuple,
+        interface: &str,
+        interface_address: Ipv4Addr,
+    ) -> Result<Option<Tuple>, DropReason> {
+        let rule = match self
+            .source_rules
+            .iter()
+            .find(|r| r.interface == interface && r.source.contains(tuple.source.0))
+        {
+            Some(rule) => rule,
+            None => return Ok(None),
+        };
+
+        let address = rule.address.unwrap_or(interface_address);
+        allocate(conntrack, tuple, address)
+            .map(Some)
+            .ok_or(DropReason::PoolExhausted)
+    }
+
+    /// Translates the source of a new connection whose destination has been translated as well,
+    /// to the address. Returns the tuple to rewrite the packet to.
+    pub(crate) fn hairpin(
+        &self,
+        conntrack: &ConnectionTable,
+        tuple: &Tuple,
+        address: Ipv4Addr,
+    ) -> Result<Tuple, DropReason> {
+        allocate(conntrack, tuple, address).ok_or(DropReason::PoolExhausted)
+    }
+}
+
+/// Picks the source port of the translated tuple, keeping the original one if the reply tuple
+/// is free.
+fn allocate(conntrack: &ConnectionTable, tuple: &Tuple, address: Ipv4Addr) -> Option<Tuple> {
+    let is_free = |port: u16| !conntrack.is_in_use(&translated(tuple, (address, port)).reverse());
+
+    let start = tuple.source.1.max(FIRST_PORT);
+    let port = if is_free(tuple.source.1) {
+        tuple.source.1
+    } else {
+        (start..=u16::MAX)
+            .chain(FIRST_PORT..start)
+            .find(|port| is_free(*port))?
+    };
+    Some(translated(tuple, (address, port)))
+}
+
+/// The tuple with the source rewritten. ICMP queries have the identifier as both ports.
+fn translated(tuple: &Tuple, source: Endpoint) -> Tuple {
+    let destination = match tuple.protocol {
+        IpNextHeaderProtocols::Icmp => (tuple.destination.0, source.1),
+        _ => tuple.destination,
+    };
+    Tuple {
+        protocol: tuple.protocol,
+        source,
+        destination,
+    }
+}
+
+/// Rewrites the source address and port of the packet, updating the checksums incrementally.
+pub(crate) fn rewrite_source(packet: &mut [u8], endpoint: Endpoint) {
+    rewrite(packet, endpoint, IPV4_SOURCE_OFFSET, 0);
+}
+
+/// Rewrites the destination address and port of the packet, updating the checksums
+/// incrementally.
+pub(crate) fn rewrite_destination(packet: &mut [u8], endpoint: Endpoint) {
+    rewrite(packet, endpoint, IPV4_DESTINATION_OFFSET, 2);
+}
+
+/// Rewrites the address at the offset of the IPv4 header, and the port at the offset of the
+/// TCP/UDP header or the ICMP query identifier.
+fn rewrite(packet: &mut [u8], endpoint: Endpoint, address_offset: usize, port_offset: usize) {
+    let (header_length, protocol) = match Ipv4Packet::new(packet) {
+        Some(ipv4) => (
+            ipv4.get_header_length() as usize * 4,
+            ipv4.get_next_level_protocol(),
+        ),
+        None => return,
+    };
+
+    let old_address = packet[address_offset..address_offset + 4].to_vec();
+    let new_address = endpoint.0.octets();
+    packet[address_offset..address_offset + 4].copy_from_slice(&new_address);
+    checksum::update_field(packet, IPV4_CHECKSUM_OFFSET, &old_address, &new_address);
+
+    let (port_offset, checksum_offset, pseudo_header) = match protocol {
+        IpNextHeaderProtocols::Tcp => (port_offset, TCP_CHECKSUM_OFFSET, true),
+        IpNextHeaderProtocols::Udp => (port_offset, UDP_CHECKSUM_OFFSET, true),
+        IpNextHeaderProtocols::Icmp => (ICMP_IDENTIFIER_OFFSET, ICMP_CHECKSUM_OFFSET, false),
+        _ => return,
+    };
+    let transport = match packet.get_mut(header_length..) {
+        Some(transport) if transport.len() >= port_offset + 2 => transport,
+        _ => return,
+    };
+
+    let old_port = [transport[port_offset], transport[port_offset + 1]];
+    let new_port = endpoint.1.to_be_bytes();
+    transport[port_offset..port_offset + 2].copy_from_slice(&new_port);
+
+    // The TCP checksum isn't within the part of the packet embedded in ICMP errors.
+    if transport.len() < checksum_offset + 2 {
+        return;
+    }
+    // A zero UDP checksum means none was computed (RFC 768).
+    if protocol == IpNextHeaderProtocols::Udp
+        && transport[checksum_offset..checksum_offset + 2] == [0, 0]
+    {
+        return;
+    }
+    // TCP and UDP checksums cover the addresses in the pseudo header.
+    let (old, new) = if pseudo_header {
+        (
+            [&old_address[..], &old_port].concat(),
+            [&new_address[..], &new_port].concat(),
+        )
+    } else {
+        (old_port.to_vec(), new_port.to_vec())
+    };
+    checksum::update_field(transport, checksum_offset, &old, &new);
+}
+
+/// The tuple of the packet embedded in an ICMP error, i.e. of the packet the error is about.
+pub(crate) fn embedded_tuple(packet: &Ipv4Packet) -> Option<Tuple> {
+    if packet.get_next_level_protocol() != IpNextHeaderProtocols::Icmp
+        || packet.get_fragment_offset() != 0
+    {
+        return None;
+    }
+    let icmp = IcmpPacket::new(packet.payload())?;
+    if !icmp::is_error(icmp.get_icmp_type()) {
+        return None;
+    }
+    Tuple::new(&Ipv4Packet::new(
+        packet.payload().get(ICMP_HEADER_LENGTH..)?,
+    )?)
+}
+
+/// Rewrites an ICMP error whose embedded packet has the tuple `embedded` to be about the packet
+/// with the tuple `translated` instead (RFC 5508 4.1). The error is sent to the source of the
+/// translated packet, and its source is translated too if it has been sent by the destination
+/// of the embedded packet. The embedded checksums are updated incrementally, and the ICMP
+/// checksum is recomputed.
+pub(crate) fn rewrite_icmp_error(packet: &mut [u8], embedded: &Tuple, translated: &Tuple) {
+    let (header_length, source) = match Ipv4Packet::new(packet) {
+        Some(ipv4) => (ipv4.get_header_length() as usize * 4, ipv4.get_source()),
+        None => return,
+    };
+    if source == embedded.destination.0 {
+        rewrite_address(packet, IPV4_SOURCE_OFFSET, translated.destination.0);
+    }
+    rewrite_address(packet, IPV4_DESTINATION_OFFSET, translated.source.0);
+
+    let icmp = match packet.get_mut(header_length..) {
+        Some(icmp) => icmp,
+        None => return,
+    };
+    if let Some(inner) = icmp.get_mut(ICMP_HEADER_LENGTH..) {
+        rewrite_source(inner, translated.source);
+        rewrite_destination(inner, translated.destination);
+    }
+    if let Some(mut icmp) = MutableIcmpPacket::new(icmp) {
+        icmp::update_checksum(&mut icmp);
+    }
+}
+
+/// Rewrites an address of the IPv4 header, updating the header checksum incrementally.
+fn rewrite_address(packet: &mut [u8], offset: usize, address: Ipv4Addr) {
+    let old = packet[offset..offset + 4].to_vec();
+    let new = address.octets();
+    packet[offset..offset + 4].copy_from_slice(&new);
+    checksum::update_field(packet, IPV4_CHECKSUM_OFFSET, &old, &new);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipv4::{build_ipv4_packet, IPV4_HEADER_LENGTH};
+    use pnet_packet::ip::IpNextHeaderProtocol;
+
+    const CLIENT: Endpoint = (Ipv4Addr::new(192, 168, 1, 2), 49152);
+    const SERVER: Endpoint = (Ipv4Addr::new(198, 51, 100, 1), 80);
+    const POOL: Endpoint = (Ipv4Addr::new(203, 0, 113, 1), 1024);
+
+    fn checksum_offset(protocol: IpNextHeaderProtocol) -> usize {
+        match protocol {
+            IpNextHeaderProtocols::Tcp => TCP_CHECKSUM_OFFSET,
+            IpNextHeaderProtocols::Udp => UDP_CHECKSUM_OFFSET,
+            _ => ICMP_CHECKSUM_OFFSET,
+        }
+    }
+
+    /// The checksum of the TCP/UDP segment or ICMP message computed from scratch.
+    fn full_checksum(
+        protocol: IpNextHeaderProtocol,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        payload: &[u8],
+    ) -> u16 {
+        let offset = checksum_offset(protocol);
+        match protocol {
+            IpNextHeaderProtocols::Icmp => pnet_packet::util::checksum(payload, offset / 2),
+            _ => pnet_packet::util::ipv4_checksum(
+                payload,
+                offset / 2,
+                &[],
+                &source,
+                &destination,
+                protocol,
+            ),
+        }
+    }
+
+    /// A TCP segment, UDP datagram or ICMP echo request with a few bytes of data and valid
+    /// checksums. The ICMP identifier is the source port.
+    fn packet(protocol: IpNextHeaderProtocol, source: Endpoint, destination: Endpoint) -> Vec<u8> {
+        let mut payload = match protocol {
+            IpNextHeaderProtocols::Tcp => {
+                let mut segment = vec![0u8; 20];
+                segment[0..2].copy_from_slice(&source.1.to_be_bytes());
+                segment[2..4].copy_from_slice(&destination.1.to_be_bytes());
+                segment[12] = 5 << 4;
+                segment
+            }
+            IpNextHeaderProtocols::Udp => {
+                let mut datagram = vec![0u8; 8];
+                datagram[0..2].copy_from_slice(&source.1.to_be_bytes());
+                datagram[2..4].copy_from_slice(&destination.1.to_be_bytes());
+                datagram[4..6].copy_from_slice(&12u16.to_be_bytes());
+                datagram
+            }
+            _ => {
+                let mut message = vec![8, 0, 0, 0, 0, 0, 0, 1];
+                message[ICMP_IDENTIFIER_OFFSET..][..2].copy_from_slice(&source.1.to_be_bytes());
+                message
+            }
+        };
+        payload.extend_from_slice(b"data");
+        let checksum = full_checksum(protocol, source.0, destination.0, &payload);
+        let offset = checksum_offset(protocol);
+        payload[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+        build_ipv4_packet(source.0, destination.0, protocol, &payload)
+    }
+
+    /// Whether the header checksum and the upper-layer checksum of the packet are valid.
+    fn has_valid_checksums(packet: &[u8]) -> bool {
+        let ipv4 = Ipv4Packet::new(packet).unwrap();
+        let protocol = ipv4.get_next_level_protocol();
+        let offset = checksum_offset(protocol);
+        let payload = ipv4.payload();
+        let checksum = u16::from_be_bytes([payload[offset], payload[offset + 1]]);
+        pnet_packet::ipv4::checksum(&ipv4) == ipv4.get_checksum()
+            && checksum
+                == full_checksum(protocol, ipv4.get_source(), ipv4.get_destination(), payload)
+    }
+
+    fn tuple(packet: &[u8]) -> Tuple {
+        Tuple::new(&Ipv4Packet::new(packet).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn rewrites_sources() {
+        for protocol in [
+            IpNextHeaderProtocols::Tcp,
+            IpNextHeaderProtocols::Udp,
+            IpNextHeaderProtocols::Icmp,
+        ] {
+            let mut packet = packet(protocol, CLIENT, SERVER);
+            rewrite_source(&mut packet, POOL);
+            assert_eq!(tuple(&packet).source, POOL, "{}", protocol);
+            assert!(has_valid_checksums(&packet), "{}", protocol);
+        }
+    }
+
+    #[test]
+    fn rewrites_destinations() {
+        // The identifier of ICMP queries is rewritten with the destination too.
+        for protocol in [
+            IpNextHeaderProtocols::Tcp,
+            IpNextHeaderProtocols::Udp,
+            IpNextHeaderProtocols::Icmp,
+        ] {
+            let mut packet = packet(protocol, SERVER, POOL);
+            rewrite_destination(&mut packet, CLIENT);
+            assert_eq!(tuple(&packet).destination, CLIENT, "{}", protocol);
+            assert!(has_valid_checksums(&packet), "{}", protocol);
+        }
+    }
+
+    #[test]
+    fn keeps_zero_udp_checksums() {
+        let mut packet = packet(IpNextHeaderProtocols::Udp, CLIENT, SERVER);
+        packet[IPV4_HEADER_LENGTH + UDP_CHECKSUM_OFFSET..][..2].copy_from_slice(&[0, 0]);
+        rewrite_source(&mut packet, POOL);
+        assert_eq!(tuple(&packet).source, POOL);
+        assert_eq!(
+            packet[IPV4_HEADER_LENGTH + UDP_CHECKSUM_OFFSET..][..2],
+            [0, 0]
+        );
+        let ipv4 = Ipv4Packet::new(&packet).unwrap();
+        assert_eq!(pnet_packet::ipv4::checksum(&ipv4), ipv4.get_checksum());
+    }
+
+    /// An ICMP destination unreachable error from the source about the first bytes of the
+    /// packet.
+    fn unreachable(source: Ipv4Addr, packet: &[u8], length: usize) -> Vec<u8> {
+        let original = Ipv4Packet::new(packet).unwrap();
+        let mut message = vec![3, 1, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&packet[..length]);
+        let checksum = pnet_packet::util::checksum(&message, ICMP_CHECKSUM_OFFSET / 2);
+        message[ICMP_CHECKSUM_OFFSET..][..2].copy_from_slice(&checksum.to_be_bytes());
+        build_ipv4_packet(
+            source,
+            original.get_source(),
+            IpNextHeaderProtocols::Icmp,
+            &message,
+        )
+    }
+
+    fn inner(error: &[u8]) -> Ipv4Packet<'_> {
+        Ipv4Packet::new(&error[IPV4_HEADER_LENGTH + ICMP_HEADER_LENGTH..]).unwrap()
+    }
+
+    #[test]
+    fn rewrites_icmp_errors_from_routers() {
+        let router = Ipv4Addr::new(192, 0, 2, 254);
+        // The TCP checksum isn't embedded.
+        let original = packet(IpNextHeaderProtocols::Tcp, POOL, SERVER);
+        let mut error = unreachable(router, &original, IPV4_HEADER_LENGTH + 8);
+        let embedded = embedded_tuple(&Ipv4Packet::new(&error).unwrap()).unwrap();
+        assert_eq!(embedded, tuple(&original));
+
+        let translated = Tuple {
+            protocol: IpNextHeaderProtocols::Tcp,
+            source: CLIENT,
+            destination: SERVER,
+        };
+        rewrite_icmp_error(&mut error, &embedded, &translated);
+        let ipv4 = Ipv4Packet::new(&error).unwrap();
+        assert_eq!(ipv4.get_source(), router);
+        assert_eq!(ipv4.get_destination(), CLIENT.0);
+        assert!(has_valid_checksums(&error));
+
+        let inner = inner(&error);
+        assert_eq!(pnet_packet::ipv4::checksum(&inner), inner.get_checksum());
+        assert_eq!(Tuple::new(&inner), Some(translated));
+    }
+
+    #[test]
+    fn rewrites_icmp_errors_from_destinations() {
+        // The whole datagram is embedded.
+        let original = packet(IpNextHeaderProtocols::Udp, POOL, SERVER);
+        let mut error = unreachable(SERVER.0, &original, original.len());
+        let embedded = embedded_tuple(&Ipv4Packet::new(&error).unwrap()).unwrap();
+
+        // The destination has been translated too.
+        let translated = Tuple {
+            protocol: IpNextHeaderProtocols::Udp,
+            source: CLIENT,
+            destination: (Ipv4Addr::new(203, 0, 113, 2), 8080),
+        };
+        rewrite_icmp_error(&mut error, &embedded, &translated);
+        let ipv4 = Ipv4Packet::new(&error).unwrap();
+        assert_eq!(ipv4.get_source(), translated.destination.0);
+        assert_eq!(ipv4.get_destination(), CLIENT.0);
+        assert!(has_valid_checksums(&error));
+
+        let inner = inner(&error);
+        assert!(has_valid_checksums(inner.packet()));
+        assert_eq!(Tuple::new(&inner), Some(translated));
+    }
+}
@@ -0,0 +1,1632 @@
+use crate::config::Config;
+use crate::counters::{DropCounters, DropReason};
+use crate::icmp::{ICMP_ERROR_MAX_LENGTH, ICMP_HEADER_LENGTH};
+use crate::icmpv6::{ICMPV6_ERROR_MAX_LENGTH, ICMPV6_HEADER_LENGTH};
+use crate::ipv4::{Ipv4HandlerEvent, IPV4_HEADER_LENGTH};
+use crate::ipv6::{Ipv6HandlerEvent, IPV6_HEADER_LENGTH};
+use crate::{checksum, ipv6_extension_headers};
+use ipnetwork::{Ipv4Network, Ipv6Network};
+use pnet_packet::icmp::IcmpTypes;
+use pnet_packet::icmpv6::Icmpv6Types;
+use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet_packet::ipv4::{Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet_packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet_packet::tcp::TcpFlags;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+
+/// Session lifetimes (RFC 6146 4).
+const UDP_TIMEOUT: Duration = Duration::from_secs(300);
+const ICMP_TIMEOUT: Duration = Duration::from_secs(60);
+const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(7440);
+const TCP_TRANSITORY_TIMEOUT: Duration = Duration::from_secs(240);
+
+/// How often expired sessions are removed.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Ports and ICMP identifiers are allocated above the well-known ports.
+const FIRST_PORT: u16 = 1024;
+
+/// Translated packets up to this length have DF cleared, so that IPv4 links with a smaller MTU
+/// fragment them, as the IPv6 host never sends packets below the IPv6 minimum MTU of 1280 bytes
+/// whatever Packet Too Big reports (RFC 7915 5.1).
+const MAX_FRAGMENTABLE_LENGTH: usize = 1260;
+
+const TCP_MINIMUM_HEADER_LENGTH: usize = 20;
+const UDP_HEADER_LENGTH: usize = 8;
+
+pub(crate) async fn spawn_nat64_handler(
+    config: &Config,
+    receiver: UnboundedReceiver<Nat64HandlerEvent>,
+    sender_ipv4: UnboundedSender<Ipv4HandlerEvent>,
+    sender_ipv6: UnboundedSender<Ipv6HandlerEvent>,
+) -> JoinHandle<()> {
+    Nat64Handler {
+        prefix: config.nat64.prefix,
+        table: SessionTable::new(config.nat64.pool),
+        identification: 0,
+        receiver,
+        sender_ipv4,
+        sender_ipv6,
+        drop_counters: DropCounters::default(),
+    }
+    .spawn()
+}
+
+#[derive(Debug)]
+pub(crate) enum Nat64HandlerEvent {
+    /// An IPv6 packet to the NAT64 prefix, to be translated and routed in the VRF.
+    TranslateIpv6 {
+        vrf: String,
+        packet: Vec<u8>,
+    },
+    /// An IPv4 packet to the pool, to be translated and routed in the VRF.
+    TranslateIpv4 {
+        vrf: String,
+        packet: Vec<u8>,
+    },
+    Shutdown,
+}
+
+/// Translates between IPv6-only hosts and IPv4 servers (RFC 6146). IPv4 servers are addressed by
+/// embedding their addresses in the prefix, and IPv6 hosts are mapped to the pool addresses and
+/// ports. Only the IPv6 hosts can initiate sessions.
+struct Nat64Handler {
+    prefix: Ipv6Network,
+    table: SessionTable,
+    /// The Identification of the next translated packet with DF cleared.
+    identification: u16,
+    receiver: UnboundedReceiver<Nat64HandlerEvent>,
+    sender_ipv4: UnboundedSender<Ipv4HandlerEvent>,
+    sender_ipv6: UnboundedSender<Ipv6HandlerEvent>,
+    drop_counters: DropCounters,
+}
+
+/// An address and a TCP/UDP port or an ICMP query identifier.
+type Endpoint<A> = (A, u16);
+
+/// A session from an IPv6 host to an IPv4 server. The remote port is zero for ICMP queries.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct SessionKey {
+    protocol: IpNextHeaderProtocol,
+    host: Endpoint<Ipv6Addr>,
+    remote: Endpoint<Ipv4Addr>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TcpState {
+    /// The SYN from the IPv6 host hasn't been answered yet.
+    Opening,
+    Established,
+    /// A FIN or RST has been seen.
+    Closing,
+}
+
+#[derive(Debug)]
+struct Session {
+    mapped: Endpoint<Ipv4Addr>,
+    /// Only meaningful for TCP.
+    state: TcpState,
+    expires: Instant,
+}
+
+/// The pool endpoint an IPv6 host endpoint is mapped to, shared by all its sessions so that the
+/// mapping is endpoint-independent (RFC 6146 3.5).
+#[derive(Debug)]
+struct Binding {
+    mapped: Endpoint<Ipv4Addr>,
+    sessions: usize,
+}
+
+/// The Binding Information Base and the session table (RFC 6146 3.1).
+struct SessionTable {
+    pool: Option<Ipv4Network>,
+    bindings: HashMap<(IpNextHeaderProtocol, Endpoint<Ipv6Addr>), Binding>,
+    /// The host endpoints keyed by the pool endpoints they are mapped to.
+    reverse_bindings: HashMap<(IpNextHeaderProtocol, Endpoint<Ipv4Addr>), Endpoint<Ipv6Addr>>,
+    sessions: HashMap<SessionKey, Session>,
+}
+
+impl SessionTable {
+    fn new(pool: Option<Ipv4Network>) -> Self {
+        SessionTable {
+            pool,
+            bindings: HashMap::new(),
+            reverse_bindings: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Returns the pool endpoint of an outgoing packet, creating the session if allowed. TCP
+    /// sessions are only created by SYNs.
+    fn outbound(
+        &mut self,
+        key: SessionKey,
+        tcp_flags: u16,
+        now: Instant,
+    ) -> Result<Endpoint<Ipv4Addr>, DropReason> {
+        if let Some(session) = self.sessions.get_mut(&key) {
+            refresh(session, key.protocol, tcp_flags, false, now);
+            return Ok(session.mapped);
+        }
+
+        if key.protocol == IpNextHeaderProtocols::Tcp
+            && tcp_flags & (TcpFlags::SYN | TcpFlags::ACK) != TcpFlags::SYN
+        {
+            return Err(DropReason::NoTranslation);
+        }
+
+        let mapped = match self.bindings.get_mut(&(key.protocol, key.host)) {
+            Some(binding) => {
+                binding.sessions += 1;
+                binding.mapped
+            }
+            None => {
+                let mapped = self
+                    .allocate(key.protocol, key.host)
+                    .ok_or(DropReason::PoolExhausted)?;
+                debug!("Created a NAT64 binding of {:?} to {:?}", key.host, mapped);
+                self.bindings.insert(
+                    (key.protocol, key.host),
+                    Binding {
+                        mapped,
+                        sessions: 1,
+                    },
+                );
+                self.reverse_bindings
+                    .insert((key.protocol, mapped), key.host);
+                mapped
+            }
+        };
+
+        let mut session = Session {
+            mapped,
+            state: TcpState::Opening,
+            expires: now,
+        };
+        refresh(&mut session, key.protocol, tcp_flags, false, now);
+        self.sessions.insert(key, session);
+        Ok(mapped)
+    }
+
+    /// Returns the host endpoint of an incoming packet from the remote endpoint, if a session
+    /// exists.
+    fn inbound(
+        &mut self,
+        protocol: IpNextHeaderProtocol,
+        mapped: Endpoint<Ipv4Addr>,
+        remote: Endpoint<Ipv4Addr>,
+        tcp_flags: u16,
+        now: Instant,
+    ) -> Option<Endpoint<Ipv6Addr>> {
+        let host = *self.reverse_bindings.get(&(protocol, mapped))?;
+        let session = self.sessions.get_mut(&SessionKey {
+            protocol,
+            host,
+            remote,
+        })?;
+        refresh(session, protocol, tcp_flags, true, now);
+        Some(host)
+    }
+
+    /// Looks up the session of a packet embedded in an ICMP error, without refreshing it.
+    fn lookup(&self, key: &SessionKey) -> Option<Endpoint<Ipv4Addr>> {
+        self.sessions.get(key).map(|session| session.mapped)
+    }
+
+    /// Looks up the host endpoint of a packet embedded in an ICMP error, without refreshing the
+    /// session.
+    fn lookup_host(
+        &self,
+        protocol: IpNextHeaderProtocol,
+        mapped: Endpoint<Ipv4Addr>,
+        remote: Endpoint<Ipv4Addr>,
+    ) -> Option<Endpoint<Ipv6Addr>> {
+        let host = *self.reverse_bindings.get(&(protocol, mapped))?;
+        let key = SessionKey {
+            protocol,
+            host,
+            remote,
+        };
+        self.sessions.get(&key).map(|_| host)
+    }
+
+    /// Picks a free pool endpoint. A host gets the same address for all its bindings where
+    /// possible (RFC 6146 3.5.1.1), and keeps its port if it is free.
+    fn allocate(
+        &self,
+        protocol: IpNextHeaderProtocol,
+        host: Endpoint<Ipv6Addr>,
+    ) -> Option<Endpoint<Ipv4Addr>> {
+        let pool = self.pool?;
+        let size = pool.size();
+        let first = (u128::from(host.0) % size as u128) as u32;
+        let start = host.1.max(FIRST_PORT);
+
+        (0..size)
+            .filter_map(|i| pool.nth((first + i) % size))
+            .find_map(|address| {
+                (start..=u16::MAX)
+                    .chain(FIRST_PORT..start)
+                    .find(|port| {
+                        !self
+                            .reverse_bindings
+                            .contains_key(&(protocol, (address, *port)))
+                    })
+                    .map(|port| (address, port))
+            })
+    }
+
+    /// Removes the expired sessions, and the bindings left without sessions.
+    fn expire(&mut self, now: Instant) {
+        let expired = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.expires <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            debug!("NAT64 session expired: {:?}", key);
+            self.sessions.remove(&key);
+
+            let binding_key = (key.protocol, key.host);
+            if let Some(binding) = self.bindings.get_mut(&binding_key) {
+                binding.sessions -= 1;
+                if binding.sessions == 0 {
+                    let mapped = binding.mapped;
+                    self.bindings.remove(&binding_key);
+                    self.reverse_bindings.remove(&(key.protocol, mapped));
+                }
+            }
+        }
+    }
+}
+
+/// Advances the TCP state by the flags of a packet, and extends the session lifetime.
+fn refresh(
+    session: &mut Session,
+    protocol: IpNextHeaderProtocol,
+    tcp_flags: u16,
+    inbound: bool,
+    now: Instant,
+) {
+    if tcp_flags & (TcpFlags::FIN | TcpFlags::RST) != 0 {
+        session.state = TcpState::Closing;
+    } else if inbound && session.state == TcpState::Opening {
+        session.state = TcpState::Established;
+    }
+
+    let timeout = match protocol {
+        IpNextHeaderProtocols::Udp => UDP_TIMEOUT,
+        IpNextHeaderProtocols::Icmp => ICMP_TIMEOUT,
+        _ if session.state == TcpState::Established => TCP_ESTABLISHED_TIMEOUT,
+        _ => TCP_TRANSITORY_TIMEOUT,
+    };
+    session.expires = now + timeout;
+}
+
+impl Nat64Handler {
+    fn handle_ipv6(&mut self, vrf: String, packet: Vec<u8>) {
+        match self.translate_to_ipv4(&packet) {
+            Ok(packet) => {
+                if let Err(e) = self
+                    .sender_ipv4
+                    .send(Ipv4HandlerEvent::RoutePacket { vrf, packet })
+                {
+                    error!("Failed to send the packet to Ipv4Handler: {}", e);
+                }
+            }
+            Err(reason) => {
+                debug!(
+                    "Dropped an IPv6 packet not to be translated ({:?}): {:?}",
+                    reason,
+                    Ipv6Packet::new(&packet)
+                );
+                self.drop_counters.increment(reason);
+            }
+        }
+    }
+
+    fn handle_ipv4(&mut self, vrf: String, packet: Vec<u8>) {
+        match self.translate_to_ipv6(&packet) {
+            Ok(packet) => {
+                if let Err(e) = self
+                    .sender_ipv6
+                    .send(Ipv6HandlerEvent::RoutePacket { vrf, packet })
+                {
+                    error!("Failed to send the packet to Ipv6Handler: {}", e);
+                }
+            }
+            Err(reason) => {
+                debug!(
+                    "Dropped an IPv4 packet not to be translated ({:?}): {:?}",
+                    reason,
+                    Ipv4Packet::new(&packet)
+                );
+                self.drop_counters.increment(reason);
+            }
+        }
+    }
+
+    /// Translates a packet from an IPv6 host (RFC 7915 5).
+    fn translate_to_ipv4(&mut self, packet: &[u8]) -> Result<Vec<u8>, DropReason> {
+        let ipv6 = Ipv6Packet::new(packet).ok_or(DropReason::MalformedHeader)?;
+        let upper_layer = match ipv6_extension_headers::upper_layer(packet) {
+            // TODO: Translate fragments.
+            Some(upper_layer) if !upper_layer.fragmented => upper_layer,
+            _ => return Err(DropReason::Untranslatable),
+        };
+        let payload = &packet[upper_layer.offset..];
+        let remote = extract_ipv4(&ipv6.get_destination());
+        let now = Instant::now();
+
+        let (source, protocol, payload) = match upper_layer.protocol {
+            protocol @ (IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp) => {
+                let (source_port, destination_port, tcp_flags) =
+                    ports(protocol, payload).ok_or(DropReason::MalformedHeader)?;
+                let mapped = self.table.outbound(
+                    SessionKey {
+                        protocol,
+                        host: (ipv6.get_source(), source_port),
+                        remote: (remote, destination_port),
+                    },
+                    tcp_flags,
+                    now,
+                )?;
+
+                let mut segment = payload.to_vec();
+                segment[0..2].copy_from_slice(&mapped.1.to_be_bytes());
+                set_ipv4_checksum(&mut segment, protocol, &mapped.0, &remote);
+                (mapped.0, protocol, segment)
+            }
+            IpNextHeaderProtocols::Icmpv6 => {
+                let (source, message) = self.translate_icmpv6(&ipv6, payload, remote, now)?;
+                (source, IpNextHeaderProtocols::Icmp, message)
+            }
+            _ => return Err(DropReason::Untranslatable),
+        };
+
+        let mut translated = ipv4_header(
+            &ipv6,
+            source,
+            remote,
+            protocol,
+            ipv6.get_hop_limit() - 1,
+            payload.len(),
+            self.identification,
+        );
+        self.identification = self.identification.wrapping_add(1);
+        translated.extend_from_slice(&payload);
+        Ok(translated)
+    }
+
+    /// Translates an ICMPv6 message to ICMP (RFC 7915 5.2), returning the IPv4 source as well.
+    fn translate_icmpv6(
+        &mut self,
+        ipv6: &Ipv6Packet,
+        message: &[u8],
+        remote: Ipv4Addr,
+        now: Instant,
+    ) -> Result<(Ipv4Addr, Vec<u8>), DropReason> {
+        if message.len() < ICMPV6_HEADER_LENGTH {
+            return Err(DropReason::MalformedHeader);
+        }
+
+        match message[0] {
+            t if t == Icmpv6Types::EchoRequest.0 => {
+                let identifier = u16::from_be_bytes([message[4], message[5]]);
+                let mapped = self.table.outbound(
+                    SessionKey {
+                        protocol: IpNextHeaderProtocols::Icmp,
+                        host: (ipv6.get_source(), identifier),
+                        remote: (remote, 0),
+                    },
+                    0,
+                    now,
+                )?;
+
+                let mut translated = message.to_vec();
+                translated[0] = IcmpTypes::EchoRequest.0;
+                translated[4..6].copy_from_slice(&mapped.1.to_be_bytes());
+                set_icmp_checksum(&mut translated);
+                Ok((mapped.0, translated))
+            }
+            1..=4 => self.translate_icmpv6_error(ipv6, message),
+            _ => Err(DropReason::Untranslatable),
+        }
+    }
+
+    /// Translates an ICMPv6 error about a packet we translated from IPv4, along with the
+    /// embedded packet.
+    fn translate_icmpv6_error(
+        &self,
+        ipv6: &Ipv6Packet,
+        message: &[u8],
+    ) -> Result<(Ipv4Addr, Vec<u8>), DropReason> {
+        let rest_of_header = u32::from_be_bytes([message[4], message[5], message[6], message[7]]);
+        let (icmp_type, icmp_code, rest_of_header) =
+            icmpv6_error_to_icmp(message[0], message[1], rest_of_header)
+                .ok_or(DropReason::Untranslatable)?;
+
+        let embedded = &message[ICMPV6_HEADER_LENGTH..];
+        let inner = Ipv6Packet::new(embedded).ok_or(DropReason::MalformedHeader)?;
+        if !self.prefix.contains(inner.get_source()) {
+            return Err(DropReason::NoTranslation);
+        }
+        let upper_layer = match ipv6_extension_headers::upper_layer(embedded) {
+            Some(upper_layer) if !upper_layer.fragmented => upper_layer,
+            _ => return Err(DropReason::Untranslatable),
+        };
+        let mut inner_payload = embedded[upper_layer.offset..].to_vec();
+        let inner_payload_length = (inner.get_payload_length() as usize)
+            .saturating_sub(upper_layer.offset - IPV6_HEADER_LENGTH);
+
+        // The embedded packet was sent from the remote to the host.
+        let remote = extract_ipv4(&inner.get_source());
+        let protocol = match upper_layer.protocol {
+            IpNextHeaderProtocols::Icmpv6 => IpNextHeaderProtocols::Icmp,
+            protocol => protocol,
+        };
+        let (remote_port, host_port) =
+            match embedded_ports(protocol, &inner_payload, Icmpv6Types::EchoReply.0)? {
+                // The identifier of an echo reply is that of the host's session.
+                (identifier, zero) if protocol == IpNextHeaderProtocols::Icmp => (zero, identifier),
+                ports => ports,
+            };
+        let mapped = self
+            .table
+            .lookup(&SessionKey {
+                protocol,
+                host: (inner.get_destination(), host_port),
+                remote: (remote, remote_port),
+            })
+            .ok_or(DropReason::NoTranslation)?;
+
+        // Rewrite the host port and update the checksum incrementally, as the embedded packet
+        // may be truncated.
+        let old_pseudo_header = [
+            &inner.get_source().octets()[..],
+            &inner.get_destination().octets(),
+        ]
+        .concat();
+        let new_pseudo_header = [&remote.octets()[..], &mapped.0.octets()].concat();
+        if protocol == IpNextHeaderProtocols::Icmp {
+            let old = [
+                &old_pseudo_header[..],
+                &(inner_payload_length as u32).to_be_bytes(),
+                &[0, 0, 0, IpNextHeaderProtocols::Icmpv6.0],
+                &[inner_payload[0], inner_payload[1]],
+                &host_port.to_be_bytes(),
+            ]
+            .concat();
+            inner_payload[0] = IcmpTypes::EchoReply.0;
+            inner_payload[4..6].copy_from_slice(&mapped.1.to_be_bytes());
+            let new = [&inner_payload[..2], &mapped.1.to_be_bytes()].concat();
+            update_embedded_checksum(&mut inner_payload, protocol, &old, &new);
+        } else {
+            inner_payload[2..4].copy_from_slice(&mapped.1.to_be_bytes());
+            let old = [&old_pseudo_header[..], &host_port.to_be_bytes()].concat();
+            let new = [&new_pseudo_header[..], &mapped.1.to_be_bytes()].concat();
+            update_embedded_checksum(&mut inner_payload, protocol, &old, &new);
+        }
+
+        let inner_header = ipv4_header(
+            &inner,
+            remote,
+            mapped.0,
+            protocol,
+            inner.get_hop_limit(),
+            inner_payload_length,
+            0,
+        );
+
+        let mut translated = vec![icmp_type, icmp_code, 0, 0];
+        translated.extend_from_slice(&rest_of_header.to_be_bytes());
+        translated.extend_from_slice(&inner_header);
+        translated.extend_from_slice(&inner_payload);
+        translated.truncate(ICMP_ERROR_MAX_LENGTH - IPV4_HEADER_LENGTH);
+        set_icmp_checksum(&mut translated);
+
+        // Errors from native IPv6 nodes appear to come from the pool address of the host.
+        let source = if self.prefix.contains(ipv6.get_source()) {
+            extract_ipv4(&ipv6.get_source())
+        } else {
+            mapped.0
+        };
+        Ok((source, translated))
+    }
+
+    /// Translates a packet to an IPv6 host (RFC 7915 4).
+    fn translate_to_ipv6(&mut self, packet: &[u8]) -> Result<Vec<u8>, DropReason> {
+        let ipv4 = Ipv4Packet::new(packet).ok_or(DropReason::MalformedHeader)?;
+        let header_length = ipv4.get_header_length() as usize * 4;
+        let total_length = (ipv4.get_total_length() as usize).min(packet.len());
+        if header_length < IPV4_HEADER_LENGTH || header_length > total_length {
+            return Err(DropReason::MalformedHeader);
+        }
+        // TODO: Translate fragments.
+        if ipv4.get_flags() & Ipv4Flags::MoreFragments != 0 || ipv4.get_fragment_offset() != 0 {
+            return Err(DropReason::Untranslatable);
+        }
+        let payload = &packet[header_length..total_length];
+        let source = self.embed_ipv4(&ipv4.get_source());
+        let now = Instant::now();
+
+        let (destination, next_header, payload) = match ipv4.get_next_level_protocol() {
+            protocol @ (IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp) => {
+                let (source_port, destination_port, tcp_flags) =
+                    ports(protocol, payload).ok_or(DropReason::MalformedHeader)?;
+                let host = self
+                    .table
+                    .inbound(
+                        protocol,
+                        (ipv4.get_destination(), destination_port),
+                        (ipv4.get_source(), source_port),
+                        tcp_flags,
+                        now,
+                    )
+                    .ok_or(DropReason::NoTranslation)?;
+
+                let mut segment = payload.to_vec();
+                segment[2..4].copy_from_slice(&host.1.to_be_bytes());
+                set_ipv6_checksum(&mut segment, protocol, &source, &host.0);
+                (host.0, protocol, segment)
+            }
+            IpNextHeaderProtocols::Icmp => {
+                let (destination, message) = self.translate_icmp(&ipv4, payload, &source, now)?;
+                (destination, IpNextHeaderProtocols::Icmpv6, message)
+            }
+            _ => return Err(DropReason::Untranslatable),
+        };
+
+        let mut translated = ipv6_header(
+            &ipv4,
+            source,
+            destination,
+            next_header,
+            ipv4.get_ttl() - 1,
+            payload.len(),
+        );
+        translated.extend_from_slice(&payload);
+        Ok(translated)
+    }
+
+    /// Translates an ICMP message to ICMPv6 (RFC 7915 4.2), returning the IPv6 destination as
+    /// well.
+    fn translate_icmp(
+        &mut self,
+        ipv4: &Ipv4Packet,
+        message: &[u8],
+        source: &Ipv6Addr,
+        now: Instant,
+    ) -> Result<(Ipv6Addr, Vec<u8>), DropReason> {
+        if message.len() < ICMP_HEADER_LENGTH {
+            return Err(DropReason::MalformedHeader);
+        }
+
+        match message[0] {
+            t if t == IcmpTypes::EchoReply.0 => {
+                let identifier = u16::from_be_bytes([message[4], message[5]]);
+                let host = self
+                    .table
+                    .inbound(
+                        IpNextHeaderProtocols::Icmp,
+                        (ipv4.get_destination(), identifier),
+                        (ipv4.get_source(), 0),
+                        0,
+                        now,
+                    )
+                    .ok_or(DropReason::NoTranslation)?;
+
+                let mut translated = message.to_vec();
+                translated[0] = Icmpv6Types::EchoReply.0;
+                translated[4..6].copy_from_slice(&host.1.to_be_bytes());
+                set_ipv6_checksum(
+                    &mut translated,
+                    IpNextHeaderProtocols::Icmpv6,
+                    source,
+                    &host.0,
+                );
+                Ok((host.0, translated))
+            }
+            t if t == IcmpTypes::DestinationUnreachable.0
+                || t == IcmpTypes::TimeExceeded.0
+                || t == IcmpTypes::ParameterProblem.0 =>
+            {
+                self.translate_icmp_error(message, source)
+            }
+            _ => Err(DropReason::Untranslatable),
+        }
+    }
+
+    /// Translates an ICMP error about a packet we translated from IPv6, along with the embedded
+    /// packet.
+    fn translate_icmp_error(
+        &self,
+        message: &[u8],
+        source: &Ipv6Addr,
+    ) -> Result<(Ipv6Addr, Vec<u8>), DropReason> {
+        let rest_of_header = u32::from_be_bytes([message[4], message[5], message[6], message[7]]);
+        let (icmpv6_type, icmpv6_code, rest_of_header) =
+            icmp_error_to_icmpv6(message[0], message[1], rest_of_header)
+                .ok_or(DropReason::Untranslatable)?;
+
+        let embedded = &message[ICMP_HEADER_LENGTH..];
+        let inner = Ipv4Packet::new(embedded).ok_or(DropReason::MalformedHeader)?;
+        let header_length = inner.get_header_length() as usize * 4;
+        if header_length < IPV4_HEADER_LENGTH || header_length > embedded.len() {
+            return Err(DropReason::MalformedHeader);
+        }
+        if inner.get_fragment_offset() != 0 {
+            return Err(DropReason::Untranslatable);
+        }
+        let mut inner_payload = embedded[header_length..].to_vec();
+        let inner_payload_length =
+            (inner.get_total_length() as usize).saturating_sub(header_length);
+
+        // The embedded packet was sent from the pool to the remote.
+        let protocol = inner.get_next_level_protocol();
+        let (mapped_port, remote_port) =
+            embedded_ports(protocol, &inner_payload, IcmpTypes::EchoRequest.0)?;
+        let host = self
+            .table
+            .lookup_host(
+                protocol,
+                (inner.get_source(), mapped_port),
+                (inner.get_destination(), remote_port),
+            )
+            .ok_or(DropReason::NoTranslation)?;
+        let remote = self.embed_ipv4(&inner.get_destination());
+
+        let old_pseudo_header = [
+            &inner.get_source().octets()[..],
+            &inner.get_destination().octets(),
+        ]
+        .concat();
+        let new_pseudo_header = [&host.0.octets()[..], &remote.octets()].concat();
+        let next_header = if protocol == IpNextHeaderProtocols::Icmp {
+            let old = [&inner_payload[..2], &mapped_port.to_be_bytes()].concat();
+            inner_payload[0] = Icmpv6Types::EchoRequest.0;
+            inner_payload[4..6].copy_from_slice(&host.1.to_be_bytes());
+            let new = [
+                &new_pseudo_header[..],
+                &(inner_payload_length as u32).to_be_bytes(),
+                &[0, 0, 0, IpNextHeaderProtocols::Icmpv6.0],
+                &inner_payload[..2],
+                &host.1.to_be_bytes(),
+            ]
+            .concat();
+            update_embedded_checksum(&mut inner_payload, protocol, &old, &new);
+            IpNextHeaderProtocols::Icmpv6
+        } else {
+            inner_payload[0..2].copy_from_slice(&host.1.to_be_bytes());
+            let old = [&old_pseudo_header[..], &mapped_port.to_be_bytes()].concat();
+            let new = [&new_pseudo_header[..], &host.1.to_be_bytes()].concat();
+            update_embedded_checksum(&mut inner_payload, protocol, &old, &new);
+            protocol
+        };
+
+        let inner_header = ipv6_header(
+            &inner,
+            host.0,
+            remote,
+            next_header,
+            inner.get_ttl(),
+            inner_payload_length,
+        );
+
+        let mut translated = vec![icmpv6_type, icmpv6_code, 0, 0];
+        translated.extend_from_slice(&rest_of_header.to_be_bytes());
+        translated.extend_from_slice(&inner_header);
+        translated.extend_from_slice(&inner_payload);
+        translated.truncate(ICMPV6_ERROR_MAX_LENGTH - IPV6_HEADER_LENGTH);
+        set_ipv6_checksum(
+            &mut translated,
+            IpNextHeaderProtocols::Icmpv6,
+            source,
+            &host.0,
+        );
+        Ok((host.0, translated))
+    }
+
+    /// The IPv6 address representing the IPv4 address (RFC 6052 2.2).
+    fn embed_ipv4(&self, address: &Ipv4Addr) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.prefix.network()) | u32::from(*address) as u128)
+    }
+
+    fn spawn(mut self) -> JoinHandle<()> {
+        let fut = async move {
+            debug!("Started Nat64Handler");
+
+            let mut timer = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                select! {
+                    _ = timer.tick() => self.table.expire(Instant::now()),
+                    Some(event) = self.receiver.recv() => {
+                        match event {
+                            Nat64HandlerEvent::TranslateIpv6 { vrf, packet } => {
+                                self.handle_ipv6(vrf, packet)
+                            }
+                            Nat64HandlerEvent::TranslateIpv4 { vrf, packet } => {
+                                self.handle_ipv4(vrf, packet)
+                            }
+                            Nat64HandlerEvent::Shutdown => {
+                                info!(
+                                    "Nat64Handler had {} sessions",
+                                    self.table.sessions.len()
+                                );
+                                self.drop_counters.log("Nat64Handler");
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        tokio::runtime::Handle::current().spawn(fut)
+    }
+}
+
+/// The IPv4 address embedded in the last 32 bits of an address in the /96 prefix.
+fn extract_ipv4(address: &Ipv6Addr) -> Ipv4Addr {
+    Ipv4Addr::from(u128::from(*address) as u32)
+}
+
+/// The source and destination ports of a TCP or UDP segment, and the TCP flags.
+fn ports(protocol: IpNextHeaderProtocol, segment: &[u8]) -> Option<(u16, u16, u16)> {
+    let minimum_length = match protocol {
+        IpNextHeaderProtocols::Tcp => TCP_MINIMUM_HEADER_LENGTH,
+        _ => UDP_HEADER_LENGTH,
+    };
+    if segment.len() < minimum_length {
+        return None;
+    }
+
+    let tcp_flags = match protocol {
+        IpNextHeaderProtocols::Tcp => segment[13] as u16,
+        _ => 0,
+    };
+    Some((
+        u16::from_be_bytes([segment[0], segment[1]]),
+        u16::from_be_bytes([segment[2], segment[3]]),
+        tcp_flags,
+    ))
+}
+
+/// The source and destination ports of an embedded TCP/UDP header, or the identifier and zero for
+/// an embedded ICMP query of the type.
+fn embedded_ports(
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+    query_type: u8,
+) -> Result<(u16, u16), DropReason> {
+    match protocol {
+        IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp if payload.len() >= 4 => Ok((
+            u16::from_be_bytes([payload[0], payload[1]]),
+            u16::from_be_bytes([payload[2], payload[3]]),
+        )),
+        IpNextHeaderProtocols::Icmp if payload.len() >= 6 && payload[0] == query_type => {
+            Ok((u16::from_be_bytes([payload[4], payload[5]]), 0))
+        }
+        _ => Err(DropReason::Untranslatable),
+    }
+}
+
+/// Maps the type, code and second word of an ICMPv6 error to ICMP (RFC 7915 5.2).
+fn icmpv6_error_to_icmp(icmpv6_type: u8, code: u8, rest_of_header: u32) -> Option<(u8, u8, u32)> {
+    let unreachable = IcmpTypes::DestinationUnreachable.0;
+    match (icmpv6_type, code) {
+        // No route, beyond scope, address unreachable: host unreachable.
+        (1, 0 | 2 | 3) => Some((unreachable, 1, 0)),
+        // Administratively prohibited.
+        (1, 1) => Some((unreachable, 10, 0)),
+        (1, 4) => Some((unreachable, 3, 0)),
+        // Packet Too Big: fragmentation needed, with the MTU adjusted for the smaller header.
+        (2, _) => {
+            let mtu = rest_of_header.saturating_sub(20).min(u16::MAX as u32);
+            Some((unreachable, 4, mtu))
+        }
+        (3, _) => Some((IcmpTypes::TimeExceeded.0, code, 0)),
+        (4, 0) => {
+            let pointer = match rest_of_header {
+                0 => 0,
+                1 => 1,
+                4 | 5 => 2,
+                6 => 9,
+                7 => 8,
+                8..=23 => 12,
+                24..=39 => 16,
+                _ => return None,
+            };
+            Some((IcmpTypes::ParameterProblem.0, 0, pointer << 24))
+        }
+        // Unrecognized next header: protocol unreachable.
+        (4, 1) => Some((unreachable, 2, 0)),
+        _ => None,
+    }
+}
+
+/// Maps the type, code and second word of an ICMP error to ICMPv6 (RFC 7915 4.2).
+fn icmp_error_to_icmpv6(icmp_type: u8, code: u8, rest_of_header: u32) -> Option<(u8, u8, u32)> {
+    let unreachable = Icmpv6Types::DestinationUnreachable.0;
+    if icmp_type == IcmpTypes::TimeExceeded.0 {
+        return Some((Icmpv6Types::TimeExceeded.0, code, 0));
+    }
+    if icmp_type == IcmpTypes::ParameterProblem.0 {
+        let pointer = match (code, rest_of_header >> 24) {
+            (0 | 2, 0) => 0,
+            (0 | 2, 1) => 1,
+            (0 | 2, 2 | 3) => 4,
+            (0 | 2, 8) => 7,
+            (0 | 2, 9) => 6,
+            (0 | 2, 12..=15) => 8,
+            (0 | 2, 16..=19) => 24,
+            _ => return None,
+        };
+        return Some((Icmpv6Types::ParameterProblem.0, 0, pointer));
+    }
+
+    match code {
+        // Network, host, source route failed and the other unreachables: no route.
+        0 | 1 | 5 | 6 | 7 | 8 | 11 | 12 => Some((unreachable, 0, 0)),
+        // Protocol unreachable: unrecognized next header.
+        2 => Some((Icmpv6Types::ParameterProblem.0, 1, 6)),
+        3 => Some((unreachable, 4, 0)),
+        // Fragmentation needed: Packet Too Big, with the MTU adjusted for the larger header. An
+        // MTU below the IPv6 minimum is reported as is, and the host goes on sending packets of
+        // the minimum size, which are translated with DF cleared.
+        4 => {
+            let mtu = (rest_of_header & 0xffff) + 20;
+            Some((Icmpv6Types::PacketTooBig.0, 0, mtu))
+        }
+        // Administratively prohibited.
+        9 | 10 | 13 | 15 => Some((unreachable, 1, 0)),
+        _ => None,
+    }
+}
+
+/// Builds the IPv4 header translated from the IPv6 one (RFC 7915 5.1). DF is set unless the
+/// packet may need to be fragmented.
+fn ipv4_header(
+    ipv6: &Ipv6Packet,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+    ttl: u8,
+    payload_length: usize,
+    identification: u16,
+) -> Vec<u8> {
+    let total_length = IPV4_HEADER_LENGTH + payload_length;
+    let mut buffer = vec![0u8; IPV4_HEADER_LENGTH];
+    let mut header = MutableIpv4Packet::new(&mut buffer).expect("buffer should be large enough");
+    header.set_version(4);
+    header.set_header_length((IPV4_HEADER_LENGTH / 4) as u8);
+    header.set_dscp(ipv6.get_traffic_class() >> 2);
+    header.set_ecn(ipv6.get_traffic_class() & 0x03);
+    header.set_total_length(total_length as u16);
+    header.set_identification(identification);
+    if total_length > MAX_FRAGMENTABLE_LENGTH {
+        header.set_flags(Ipv4Flags::DontFragment);
+    }
+    header.set_ttl(ttl);
+    header.set_next_level_protocol(protocol);
+    header.set_source(source);
+    header.set_destination(destination);
+    header.set_checksum(pnet_packet::ipv4::checksum(&header.to_immutable()));
+    buffer
+}
+
+/// Builds the IPv6 header translated from the IPv4 one (RFC 7915 4.1).
+fn ipv6_header(
+    ipv4: &Ipv4Packet,
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+    next_header: IpNextHeaderProtocol,
+    hop_limit: u8,
+    payload_length: usize,
+) -> Vec<u8> {
+    let mut buffer = vec![0u8; IPV6_HEADER_LENGTH];
+    let mut header = MutableIpv6Packet::new(&mut buffer).expect("buffer should be large enough");
+    header.set_version(6);
+    header.set_traffic_class(ipv4.get_dscp() << 2 | ipv4.get_ecn());
+    header.set_payload_length(payload_length as u16);
+    header.set_next_header(next_header);
+    header.set_hop_limit(hop_limit);
+    header.set_source(source);
+    header.set_destination(destination);
+    buffer
+}
+
+/// The offset of the checksum in the upper-layer header.
+fn checksum_offset(protocol: IpNextHeaderProtocol) -> usize {
+    match protocol {
+        IpNextHeaderProtocols::Tcp => 16,
+        IpNextHeaderProtocols::Udp => 6,
+        _ => 2,
+    }
+}
+
+/// Recomputes the TCP or UDP checksum for the IPv4 pseudo header.
+fn set_ipv4_checksum(
+    segment: &mut [u8],
+    protocol: IpNextHeaderProtocol,
+    source: &Ipv4Addr,
+    destination: &Ipv4Addr,
+) {
+    let offset = checksum_offset(protocol);
+    let checksum =
+        pnet_packet::util::ipv4_checksum(segment, offset / 2, &[], source, destination, protocol);
+    store_checksum(segment, protocol, offset, checksum);
+}
+
+/// Recomputes the TCP, UDP or ICMPv6 checksum for the IPv6 pseudo header.
+fn set_ipv6_checksum(
+    message: &mut [u8],
+    protocol: IpNextHeaderProtocol,
+    source: &Ipv6Addr,
+    destination: &Ipv6Addr,
+) {
+    let offset = checksum_offset(protocol);
+    let checksum =
+        pnet_packet::util::ipv6_checksum(message, offset / 2, &[], source, destination, protocol);
+    store_checksum(message, protocol, offset, checksum);
+}
+
+/// Recomputes the ICMP checksum, which has no pseudo header.
+fn set_icmp_checksum(message: &mut [u8]) {
+    let checksum = pnet_packet::util::checksum(message, 1);
+    store_checksum(message, IpNextHeaderProtocols::Icmp, 2, checksum);
+}
+
+fn store_checksum(
+    message: &mut [u8],
+    protocol: IpNextHeaderProtocol,
+    offset: usize,
+    checksum: u16,
+) {
+    // A zero UDP checksum means none was computed (RFC 768).
+    let checksum = match checksum {
+        0 if protocol == IpNextHeaderProtocols::Udp => 0xffff,
+        checksum => checksum,
+    };
+    message[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Updates the checksum of an embedded, possibly truncated, upper-layer header if it is present.
+/// A zero UDP checksum is left as is.
+fn update_embedded_checksum(
+    payload: &mut [u8],
+    protocol: IpNextHeaderProtocol,
+    old: &[u8],
+    new: &[u8],
+) {
+    let offset = checksum_offset(protocol);
+    if payload.len() < offset + 2
+        || (protocol == IpNextHeaderProtocols::Udp && payload[offset..offset + 2] == [0, 0])
+    {
+        return;
+    }
+    checksum::update_field(payload, offset, old, new);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipv4::build_ipv4_packet;
+    use pnet_packet::Packet;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const HOST: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 2);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 168, 2, 2);
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 254);
+
+    fn pool() -> Ipv4Network {
+        "192.168.0.64/30".parse().unwrap()
+    }
+
+    fn handler() -> Nat64Handler {
+        let (_, receiver) = unbounded_channel();
+        let (sender_ipv4, _) = unbounded_channel();
+        let (sender_ipv6, _) = unbounded_channel();
+        Nat64Handler {
+            prefix: "64:ff9b::/96".parse().unwrap(),
+            table: SessionTable::new(Some(pool())),
+            identification: 0,
+            receiver,
+            sender_ipv4,
+            sender_ipv6,
+            drop_counters: DropCounters::default(),
+        }
+    }
+
+    fn embedded(address: Ipv4Addr) -> Ipv6Addr {
+        handler().embed_ipv4(&address)
+    }
+
+    fn port(bytes: &[u8]) -> u16 {
+        u16::from_be_bytes([bytes[0], bytes[1]])
+    }
+
+    /// A TCP or UDP segment, or an ICMP or ICMPv6 query of the type, with a few bytes of data.
+    /// The checksum is left zero.
+    fn message(protocol: IpNextHeaderProtocol, source_port: u16, destination_port: u16) -> Vec<u8> {
+        let mut message = match protocol {
+            IpNextHeaderProtocols::Tcp => {
+                let mut segment = vec![0u8; TCP_MINIMUM_HEADER_LENGTH];
+                segment[12] = 5 << 4;
+                segment
+            }
+            _ => vec![0u8; UDP_HEADER_LENGTH],
+        };
+        message[0..2].copy_from_slice(&source_port.to_be_bytes());
+        message[2..4].copy_from_slice(&destination_port.to_be_bytes());
+        message.extend_from_slice(b"data");
+        if protocol == IpNextHeaderProtocols::Udp {
+            let length = message.len() as u16;
+            message[4..6].copy_from_slice(&length.to_be_bytes());
+        }
+        message
+    }
+
+    fn tcp(source_port: u16, destination_port: u16, tcp_flags: u16) -> Vec<u8> {
+        let mut segment = message(IpNextHeaderProtocols::Tcp, source_port, destination_port);
+        segment[13] = tcp_flags as u8;
+        segment
+    }
+
+    fn echo(icmp_type: u8, identifier: u16) -> Vec<u8> {
+        let mut message = vec![icmp_type, 0, 0, 0, 0, 0, 0, 1];
+        message[4..6].copy_from_slice(&identifier.to_be_bytes());
+        message.extend_from_slice(b"data");
+        message
+    }
+
+    /// An ICMP or ICMPv6 error quoting the packet.
+    fn error(error_type: u8, code: u8, rest_of_header: u32, packet: &[u8]) -> Vec<u8> {
+        let mut message = vec![error_type, code, 0, 0];
+        message.extend_from_slice(&rest_of_header.to_be_bytes());
+        message.extend_from_slice(packet);
+        message
+    }
+
+    fn ipv4_packet(
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        protocol: IpNextHeaderProtocol,
+        mut payload: Vec<u8>,
+    ) -> Vec<u8> {
+        match protocol {
+            IpNextHeaderProtocols::Icmp => set_icmp_checksum(&mut payload),
+            _ => set_ipv4_checksum(&mut payload, protocol, &source, &destination),
+        }
+        build_ipv4_packet(source, destination, protocol, &payload)
+    }
+
+    fn ipv6_packet(
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+        next_header: IpNextHeaderProtocol,
+        mut payload: Vec<u8>,
+    ) -> Vec<u8> {
+        set_ipv6_checksum(&mut payload, next_header, &source, &destination);
+        let mut buffer = vec![0u8; IPV6_HEADER_LENGTH + payload.len()];
+        let mut packet = MutableIpv6Packet::new(&mut buffer).unwrap();
+        packet.set_version(6);
+        packet.set_payload_length(payload.len() as u16);
+        packet.set_next_header(next_header);
+        packet.set_hop_limit(64);
+        packet.set_source(source);
+        packet.set_destination(destination);
+        packet.set_payload(&payload);
+        buffer
+    }
+
+    /// Whether the header checksum and the upper-layer checksum of the IPv4 packet are valid.
+    fn has_valid_ipv4_checksums(packet: &[u8]) -> bool {
+        let ipv4 = Ipv4Packet::new(packet).unwrap();
+        let protocol = ipv4.get_next_level_protocol();
+        let payload = ipv4.payload();
+        let offset = checksum_offset(protocol);
+        let expected = match protocol {
+            IpNextHeaderProtocols::Icmp => pnet_packet::util::checksum(payload, offset / 2),
+            _ => pnet_packet::util::ipv4_checksum(
+                payload,
+                offset / 2,
+                &[],
+                &ipv4.get_source(),
+                &ipv4.get_destination(),
+                protocol,
+            ),
+        };
+        pnet_packet::ipv4::checksum(&ipv4) == ipv4.get_checksum()
+            && port(&payload[offset..]) == expected
+    }
+
+    /// Whether the upper-layer checksum of the IPv6 packet is valid.
+    fn has_valid_ipv6_checksum(packet: &[u8]) -> bool {
+        let ipv6 = Ipv6Packet::new(packet).unwrap();
+        let next_header = ipv6.get_next_header();
+        let payload = ipv6.payload();
+        let offset = checksum_offset(next_header);
+        let expected = pnet_packet::util::ipv6_checksum(
+            payload,
+            offset / 2,
+            &[],
+            &ipv6.get_source(),
+            &ipv6.get_destination(),
+            next_header,
+        );
+        port(&payload[offset..]) == expected
+    }
+
+    #[test]
+    fn maps_icmpv6_errors_to_icmp() {
+        let cases = [
+            // Destination Unreachable.
+            ((1, 0, 0), Some((3, 1, 0))),
+            ((1, 1, 0), Some((3, 10, 0))),
+            ((1, 2, 0), Some((3, 1, 0))),
+            ((1, 3, 0), Some((3, 1, 0))),
+            ((1, 4, 0), Some((3, 3, 0))),
+            ((1, 5, 0), None),
+            // Packet Too Big.
+            ((2, 0, 1500), Some((3, 4, 1480))),
+            // Time Exceeded.
+            ((3, 0, 0), Some((11, 0, 0))),
+            ((3, 1, 0), Some((11, 1, 0))),
+            // Parameter Problem, with the pointer to the corresponding IPv4 field.
+            ((4, 0, 0), Some((12, 0, 0))),
+            ((4, 0, 1), Some((12, 0, 1 << 24))),
+            ((4, 0, 4), Some((12, 0, 2 << 24))),
+            ((4, 0, 5), Some((12, 0, 2 << 24))),
+            ((4, 0, 6), Some((12, 0, 9 << 24))),
+            ((4, 0, 7), Some((12, 0, 8 << 24))),
+            ((4, 0, 8), Some((12, 0, 12 << 24))),
+            ((4, 0, 23), Some((12, 0, 12 << 24))),
+            ((4, 0, 24), Some((12, 0, 16 << 24))),
+            ((4, 0, 39), Some((12, 0, 16 << 24))),
+            ((4, 0, 2), None),
+            ((4, 0, 40), None),
+            ((4, 1, 0), Some((3, 2, 0))),
+            ((4, 2, 0), None),
+        ];
+        for ((icmpv6_type, code, rest_of_header), expected) in cases {
+            assert_eq!(
+                icmpv6_error_to_icmp(icmpv6_type, code, rest_of_header),
+                expected,
+                "type {} code {} rest {}",
+                icmpv6_type,
+                code,
+                rest_of_header
+            );
+        }
+    }
+
+    #[test]
+    fn maps_icmp_errors_to_icmpv6() {
+        let cases = [
+            // Destination Unreachable.
+            ((3, 0, 0), Some((1, 0, 0))),
+            ((3, 1, 0), Some((1, 0, 0))),
+            ((3, 2, 0), Some((4, 1, 6))),
+            ((3, 3, 0), Some((1, 4, 0))),
+            ((3, 5, 0), Some((1, 0, 0))),
+            ((3, 9, 0), Some((1, 1, 0))),
+            ((3, 10, 0), Some((1, 1, 0))),
+            ((3, 13, 0), Some((1, 1, 0))),
+            ((3, 14, 0), None),
+            // Fragmentation Needed, even below the IPv6 minimum MTU.
+            ((3, 4, 1400), Some((2, 0, 1420))),
+            ((3, 4, 0xdead_0000 | 1400), Some((2, 0, 1420))),
+            ((3, 4, 576), Some((2, 0, 596))),
+            // Time Exceeded.
+            ((11, 0, 0), Some((3, 0, 0))),
+            ((11, 1, 0), Some((3, 1, 0))),
+            // Parameter Problem, with the pointer to the corresponding IPv6 field.
+            ((12, 0, 0), Some((4, 0, 0))),
+            ((12, 0, 1 << 24), Some((4, 0, 1))),
+            ((12, 0, 2 << 24), Some((4, 0, 4))),
+            ((12, 0, 3 << 24), Some((4, 0, 4))),
+            ((12, 0, 8 << 24), Some((4, 0, 7))),
+            ((12, 0, 9 << 24), Some((4, 0, 6))),
+            ((12, 0, 12 << 24), Some((4, 0, 8))),
+            ((12, 0, 16 << 24), Some((4, 0, 24))),
+            ((12, 2, 19 << 24), Some((4, 0, 24))),
+            ((12, 0, 10 << 24), None),
+            ((12, 1, 0), None),
+        ];
+        for ((icmp_type, code, rest_of_header), expected) in cases {
+            assert_eq!(
+                icmp_error_to_icmpv6(icmp_type, code, rest_of_header),
+                expected,
+                "type {} code {} rest {}",
+                icmp_type,
+                code,
+                rest_of_header
+            );
+        }
+    }
+
+    #[test]
+    fn finds_embedded_ports() {
+        let segment = message(IpNextHeaderProtocols::Udp, 40000, 53);
+        assert_eq!(
+            embedded_ports(IpNextHeaderProtocols::Udp, &segment, 0),
+            Ok((40000, 53))
+        );
+        assert_eq!(
+            embedded_ports(IpNextHeaderProtocols::Tcp, &segment[..4], 0),
+            Ok((40000, 53))
+        );
+        assert_eq!(
+            embedded_ports(IpNextHeaderProtocols::Tcp, &segment[..3], 0),
+            Err(DropReason::Untranslatable)
+        );
+
+        let request = echo(IcmpTypes::EchoRequest.0, 7);
+        assert_eq!(
+            embedded_ports(
+                IpNextHeaderProtocols::Icmp,
+                &request,
+                IcmpTypes::EchoRequest.0
+            ),
+            Ok((7, 0))
+        );
+        assert_eq!(
+            embedded_ports(
+                IpNextHeaderProtocols::Icmp,
+                &request,
+                IcmpTypes::EchoReply.0
+            ),
+            Err(DropReason::Untranslatable)
+        );
+        assert_eq!(
+            embedded_ports(
+                IpNextHeaderProtocols::Icmp,
+                &request[..5],
+                IcmpTypes::EchoRequest.0
+            ),
+            Err(DropReason::Untranslatable)
+        );
+        assert_eq!(
+            embedded_ports(IpNextHeaderProtocols::Gre, &segment, 0),
+            Err(DropReason::Untranslatable)
+        );
+    }
+
+    #[test]
+    fn allocates_pool_endpoints() {
+        let mut table = SessionTable::new(Some(pool()));
+        let now = Instant::now();
+        let udp = |host, remote| SessionKey {
+            protocol: IpNextHeaderProtocols::Udp,
+            host,
+            remote,
+        };
+
+        // The port of the host is kept if free.
+        let mapped = table
+            .outbound(udp((HOST, 40000), (REMOTE, 53)), 0, now)
+            .unwrap();
+        assert!(pool().contains(mapped.0));
+        assert_eq!(mapped.1, 40000);
+
+        // The sessions of a host endpoint share its binding.
+        let other_remote = (Ipv4Addr::new(192, 168, 2, 3), 53);
+        assert_eq!(
+            table.outbound(udp((HOST, 40000), other_remote), 0, now),
+            Ok(mapped)
+        );
+        assert_eq!(table.bindings.len(), 1);
+
+        // Other protocols have their own ports.
+        assert_eq!(
+            table.allocate(IpNextHeaderProtocols::Tcp, (HOST, 40000)),
+            Some(mapped)
+        );
+
+        // A host whose address maps to the same pool address gets the next free port.
+        let neighbor = Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 6);
+        assert_eq!(
+            table.allocate(IpNextHeaderProtocols::Udp, (neighbor, 40000)),
+            Some((mapped.0, 40001))
+        );
+
+        // The well-known ports aren't allocated.
+        let mapped = table
+            .outbound(udp((HOST, 53), (REMOTE, 53)), 0, now)
+            .unwrap();
+        assert_eq!(mapped.1, FIRST_PORT);
+
+        // Only SYNs create TCP sessions.
+        let tcp = SessionKey {
+            protocol: IpNextHeaderProtocols::Tcp,
+            host: (HOST, 40000),
+            remote: (REMOTE, 80),
+        };
+        assert_eq!(
+            table.outbound(tcp, TcpFlags::ACK, now),
+            Err(DropReason::NoTranslation)
+        );
+
+        let mut table = SessionTable::new(None);
+        assert_eq!(
+            table.outbound(udp((HOST, 40000), (REMOTE, 53)), 0, now),
+            Err(DropReason::PoolExhausted)
+        );
+    }
+
+    #[test]
+    fn expires_sessions_and_bindings() {
+        let mut table = SessionTable::new(Some(pool()));
+        let now = Instant::now();
+        let udp = SessionKey {
+            protocol: IpNextHeaderProtocols::Udp,
+            host: (HOST, 40000),
+            remote: (REMOTE, 53),
+        };
+        let tcp = SessionKey {
+            protocol: IpNextHeaderProtocols::Tcp,
+            host: (HOST, 40000),
+            remote: (REMOTE, 80),
+        };
+        let udp_mapped = table.outbound(udp, 0, now).unwrap();
+        let tcp_mapped = table.outbound(tcp, TcpFlags::SYN, now).unwrap();
+
+        // The SYN-ACK establishes the TCP session.
+        assert_eq!(
+            table.inbound(
+                IpNextHeaderProtocols::Tcp,
+                tcp_mapped,
+                tcp.remote,
+                TcpFlags::SYN | TcpFlags::ACK,
+                now
+            ),
+            Some(tcp.host)
+        );
+
+        table.expire(now + UDP_TIMEOUT - Duration::from_secs(1));
+        assert_eq!(table.sessions.len(), 2);
+
+        table.expire(now + UDP_TIMEOUT);
+        assert_eq!(table.sessions.len(), 1);
+        assert!(!table
+            .reverse_bindings
+            .contains_key(&(IpNextHeaderProtocols::Udp, udp_mapped)));
+        assert_eq!(
+            table.inbound(IpNextHeaderProtocols::Udp, udp_mapped, udp.remote, 0, now),
+            None
+        );
+
+        table.expire(now + TCP_ESTABLISHED_TIMEOUT);
+        assert!(table.sessions.is_empty());
+        assert!(table.bindings.is_empty());
+        assert!(table.reverse_bindings.is_empty());
+    }
+
+    /// Translates a packet of the protocol from the host to the remote and its reply back,
+    /// checking the headers and checksums.
+    fn assert_round_trip(
+        protocol: IpNextHeaderProtocol,
+        request: Vec<u8>,
+        reply: impl Fn(u16) -> Vec<u8>,
+    ) {
+        let mut nat64 = handler();
+        let next_header = match protocol {
+            IpNextHeaderProtocols::Icmp => IpNextHeaderProtocols::Icmpv6,
+            protocol => protocol,
+        };
+
+        let packet = ipv6_packet(HOST, embedded(REMOTE), next_header, request);
+        let translated = nat64.translate_to_ipv4(&packet).unwrap();
+        assert!(has_valid_ipv4_checksums(&translated));
+        let ipv4 = Ipv4Packet::new(&translated).unwrap();
+        assert!(pool().contains(ipv4.get_source()));
+        assert_eq!(ipv4.get_destination(), REMOTE);
+        assert_eq!(ipv4.get_next_level_protocol(), protocol);
+        assert_eq!(ipv4.get_ttl(), 63);
+        assert_eq!(ipv4.get_flags(), 0);
+        let mapped_port = match protocol {
+            IpNextHeaderProtocols::Icmp => {
+                assert_eq!(ipv4.payload()[0], IcmpTypes::EchoRequest.0);
+                port(&ipv4.payload()[4..])
+            }
+            _ => port(ipv4.payload()),
+        };
+
+        let packet = ipv4_packet(REMOTE, ipv4.get_source(), protocol, reply(mapped_port));
+        let translated = nat64.translate_to_ipv6(&packet).unwrap();
+        assert!(has_valid_ipv6_checksum(&translated));
+        let ipv6 = Ipv6Packet::new(&translated).unwrap();
+        assert_eq!(ipv6.get_source(), embedded(REMOTE));
+        assert_eq!(ipv6.get_destination(), HOST);
+        assert_eq!(ipv6.get_next_header(), next_header);
+        assert_eq!(ipv6.get_hop_limit(), 63);
+        match protocol {
+            IpNextHeaderProtocols::Icmp => {
+                assert_eq!(ipv6.payload()[0], Icmpv6Types::EchoReply.0);
+                assert_eq!(port(&ipv6.payload()[4..]), 7);
+            }
+            _ => assert_eq!(port(&ipv6.payload()[2..]), 40000),
+        }
+    }
+
+    #[test]
+    fn translates_tcp() {
+        assert_round_trip(
+            IpNextHeaderProtocols::Tcp,
+            tcp(40000, 80, TcpFlags::SYN),
+            |mapped_port| tcp(80, mapped_port, TcpFlags::SYN | TcpFlags::ACK),
+        );
+    }
+
+    #[test]
+    fn translates_udp() {
+        assert_round_trip(
+            IpNextHeaderProtocols::Udp,
+            message(IpNextHeaderProtocols::Udp, 40000, 53),
+            |mapped_port| message(IpNextHeaderProtocols::Udp, 53, mapped_port),
+        );
+    }
+
+    #[test]
+    fn translates_icmp_queries() {
+        assert_round_trip(
+            IpNextHeaderProtocols::Icmp,
+            echo(Icmpv6Types::EchoRequest.0, 7),
+            |mapped_identifier| echo(IcmpTypes::EchoReply.0, mapped_identifier),
+        );
+    }
+
+    #[test]
+    fn sets_dont_fragment_on_large_packets() {
+        let mut nat64 = handler();
+        let udp = |length| {
+            let mut segment = message(IpNextHeaderProtocols::Udp, 40000, 53);
+            segment.resize(length, 0);
+            segment[4..6].copy_from_slice(&(length as u16).to_be_bytes());
+            ipv6_packet(HOST, embedded(REMOTE), IpNextHeaderProtocols::Udp, segment)
+        };
+
+        // Up to 1260 bytes, the packet may be fragmented and has an Identification.
+        let first = nat64.translate_to_ipv4(&udp(1240)).unwrap();
+        let first = Ipv4Packet::new(&first).unwrap();
+        assert_eq!(first.get_total_length(), 1260);
+        assert_eq!(first.get_flags(), 0);
+        let second = nat64.translate_to_ipv4(&udp(1240)).unwrap();
+        let second = Ipv4Packet::new(&second).unwrap();
+        assert_ne!(second.get_identification(), first.get_identification());
+
+        let large = nat64.translate_to_ipv4(&udp(1241)).unwrap();
+        let large = Ipv4Packet::new(&large).unwrap();
+        assert_eq!(large.get_flags(), Ipv4Flags::DontFragment);
+        assert!(has_valid_ipv4_checksums(large.packet()));
+    }
+
+    #[test]
+    fn translates_icmp_errors_to_icmpv6() {
+        let mut nat64 = handler();
+        let packet = ipv6_packet(
+            HOST,
+            embedded(REMOTE),
+            IpNextHeaderProtocols::Udp,
+            message(IpNextHeaderProtocols::Udp, 40000, 53),
+        );
+        let outbound = nat64.translate_to_ipv4(&packet).unwrap();
+        let mapped = Ipv4Packet::new(&outbound).unwrap().get_source();
+
+        // A router on the way reports that the TTL of the translated packet expired.
+        let packet = ipv4_packet(
+            ROUTER,
+            mapped,
+            IpNextHeaderProtocols::Icmp,
+            error(IcmpTypes::TimeExceeded.0, 0, 0, &outbound),
+        );
+        let translated = nat64.translate_to_ipv6(&packet).unwrap();
+        assert!(has_valid_ipv6_checksum(&translated));
+        let ipv6 = Ipv6Packet::new(&translated).unwrap();
+        assert_eq!(ipv6.get_source(), embedded(ROUTER));
+        assert_eq!(ipv6.get_destination(), HOST);
+        assert_eq!(ipv6.get_next_header(), IpNextHeaderProtocols::Icmpv6);
+        assert_eq!(ipv6.payload()[..2], [Icmpv6Types::TimeExceeded.0, 0]);
+
+        // The embedded packet is the one the host sent.
+        let inner = &ipv6.payload()[ICMPV6_HEADER_LENGTH..];
+        assert!(has_valid_ipv6_checksum(inner));
+        let inner = Ipv6Packet::new(inner).unwrap();
+        assert_eq!(inner.get_source(), HOST);
+        assert_eq!(inner.get_destination(), embedded(REMOTE));
+        assert_eq!(inner.get_next_header(), IpNextHeaderProtocols::Udp);
+        assert_eq!(port(inner.payload()), 40000);
+    }
+
+    #[test]
+    fn translates_icmpv6_errors_to_icmp() {
+        let mut nat64 = handler();
+        let packet = ipv6_packet(
+            HOST,
+            embedded(REMOTE),
+            IpNextHeaderProtocols::Udp,
+            message(IpNextHeaderProtocols::Udp, 40000, 53),
+        );
+        let mapped = Ipv4Packet::new(&nat64.translate_to_ipv4(&packet).unwrap())
+            .unwrap()
+            .get_source();
+        let packet = ipv4_packet(
+            REMOTE,
+            mapped,
+            IpNextHeaderProtocols::Udp,
+            message(IpNextHeaderProtocols::Udp, 53, 40000),
+        );
+        let inbound = nat64.translate_to_ipv6(&packet).unwrap();
+
+        // The host reports that the port of the reply is unreachable.
+        let packet = ipv6_packet(
+            HOST,
+            embedded(REMOTE),
+            IpNextHeaderProtocols::Icmpv6,
+            error(Icmpv6Types::DestinationUnreachable.0, 4, 0, &inbound),
+        );
+        let translated = nat64.translate_to_ipv4(&packet).unwrap();
+        assert!(has_valid_ipv4_checksums(&translated));
+        let ipv4 = Ipv4Packet::new(&translated).unwrap();
+        assert_eq!(ipv4.get_source(), mapped);
+        assert_eq!(ipv4.get_destination(), REMOTE);
+        assert_eq!(ipv4.get_next_level_protocol(), IpNextHeaderProtocols::Icmp);
+        assert_eq!(
+            ipv4.payload()[..2],
+            [IcmpTypes::DestinationUnreachable.0, 3]
+        );
+
+        // The embedded packet is the one the remote sent.
+        let inner = &ipv4.payload()[ICMP_HEADER_LENGTH..];
+        assert!(has_valid_ipv4_checksums(inner));
+        let inner = Ipv4Packet::new(inner).unwrap();
+        assert_eq!(inner.get_source(), REMOTE);
+        assert_eq!(inner.get_destination(), mapped);
+        assert_eq!(port(&inner.payload()[2..]), 40000);
+    }
+
+    #[test]
+    fn translates_icmpv6_errors_about_echo_replies() {
+        let mut nat64 = handler();
+        let packet = ipv6_packet(
+            HOST,
+            embedded(REMOTE),
+            IpNextHeaderProtocols::Icmpv6,
+            echo(Icmpv6Types::EchoRequest.0, 7),
+        );
+        let outbound = nat64.translate_to_ipv4(&packet).unwrap();
+        let outbound = Ipv4Packet::new(&outbound).unwrap();
+        let mapped = (outbound.get_source(), port(&outbound.payload()[4..]));
+        let packet = ipv4_packet(
+            REMOTE,
+            mapped.0,
+            IpNextHeaderProtocols::Icmp,
+            echo(IcmpTypes::EchoReply.0, mapped.1),
+        );
+        let inbound = nat64.translate_to_ipv6(&packet).unwrap();
+
+        let packet = ipv6_packet(
+            HOST,
+            embedded(REMOTE),
+            IpNextHeaderProtocols::Icmpv6,
+            error(Icmpv6Types::DestinationUnreachable.0, 4, 0, &inbound),
+        );
+        let translated = nat64.translate_to_ipv4(&packet).unwrap();
+        assert!(has_valid_ipv4_checksums(&translated));
+        let ipv4 = Ipv4Packet::new(&translated).unwrap();
+        let inner = &ipv4.payload()[ICMP_HEADER_LENGTH..];
+        assert!(has_valid_ipv4_checksums(inner));
+        let inner = Ipv4Packet::new(inner).unwrap();
+        assert_eq!(inner.payload()[0], IcmpTypes::EchoReply.0);
+        assert_eq!(port(&inner.payload()[4..]), mapped.1);
+    }
+}
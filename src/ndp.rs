@@ -0,0 +1,1013 @@
+use crate::config::Config;
+use crate::ipv6::{build_ipv6_packet, Ipv6HandlerEvent, ALL_NODES};
+use crate::rate_limit::TokenBucket;
+use crate::router_advertisement::RouterAdvertiser;
+use ipnetwork::IpNetwork;
+use pnet_datalink::{MacAddr, NetworkInterface};
+use pnet_packet::icmpv6::ndp::{NdpOptionTypes, NeighborAdvertFlags};
+use pnet_packet::icmpv6::{Icmpv6Packet, Icmpv6Types, MutableIcmpv6Packet};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv6::Ipv6Packet;
+use pnet_packet::Packet;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+
+/// NDP messages must be sent with the maximum hop limit, so that receivers can tell they haven't
+/// been forwarded (RFC 4861 7.1).
+pub(crate) const NDP_HOP_LIMIT: u8 = 255;
+
+/// The length of the Neighbor Solicitation and Advertisement messages without options.
+const NEIGHBOR_MESSAGE_LENGTH: usize = 24;
+/// The length of the Router Solicitation message without options.
+const ROUTER_SOLICITATION_LENGTH: usize = 8;
+/// The length of the source/target link-layer address options for Ethernet.
+const LINK_LAYER_ADDRESS_OPTION_LENGTH: usize = 8;
+
+/// Protocol constants (RFC 4861 10). The reachable time isn't randomized.
+const MAX_MULTICAST_SOLICIT: u8 = 3;
+const MAX_UNICAST_SOLICIT: u8 = 3;
+const REACHABLE_TIME: Duration = Duration::from_secs(30);
+const RETRANS_TIMER: Duration = Duration::from_secs(1);
+const DELAY_FIRST_PROBE_TIME: Duration = Duration::from_secs(5);
+
+/// How often the timers of the neighbor cache and DAD are checked.
+const TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Limits against exhausting the neighbor cache by scanning a subnet (RFC 6583 7.1): the number of
+/// incomplete entries per interface, the rate of solicitations per interface, and how long unused
+/// stale entries are kept.
+const MAX_INCOMPLETE_NEIGHBORS: usize = 256;
+const SOLICITATION_RATE: u32 = 100;
+const SOLICITATION_BURST: u32 = 100;
+const STALE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The solicited-node multicast address of the address (RFC 4291 2.7.1).
+pub(crate) fn solicited_node_multicast(address: &Ipv6Addr) -> Ipv6Addr {
+    let octets = address.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | octets[13] as u16,
+        u16::from_be_bytes([octets[14], octets[15]]),
+    )
+}
+
+/// The Ethernet address an IPv6 multicast address is mapped to (RFC 2464 7).
+pub(crate) fn multicast_mac(address: &Ipv6Addr) -> MacAddr {
+    let octets = address.octets();
+    MacAddr::new(0x33, 0x33, octets[12], octets[13], octets[14], octets[15])
+}
+
+/// Neighbor reachability states (RFC 4861 7.3.2).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum NeighborState {
+    /// Address resolution is in progress.
+    Incomplete,
+    Reachable,
+    /// Not known to be reachable. Verified when a packet is sent.
+    Stale,
+    /// Waiting for upper layers to confirm reachability before probing.
+    Delay,
+    Probe,
+}
+
+#[derive(Debug)]
+pub(crate) struct Neighbor {
+    /// `None` while incomplete.
+    pub(crate) mac: Option<MacAddr>,
+    pub(crate) state: NeighborState,
+    /// The number of solicitations sent in the incomplete or probe state.
+    probes: u8,
+    /// When the timer of the state fires. In the stale state, when the unused entry is removed.
+    deadline: Instant,
+}
+
+impl Neighbor {
+    fn new(mac: Option<MacAddr>, state: NeighborState, deadline: Instant) -> Self {
+        Neighbor {
+            mac,
+            state,
+            probes: 0,
+            deadline,
+        }
+    }
+
+    fn make_stale(&mut self, now: Instant) {
+        self.state = NeighborState::Stale;
+        self.deadline = now + STALE_TIMEOUT;
+    }
+}
+
+/// Neighbor entries scoped by interface, as link-local addresses are only unique on a link.
+pub(crate) struct NeighborCache {
+    entries: HashMap<u32, HashMap<Ipv6Addr, Neighbor>>,
+    /// The number of incomplete entries keyed by the interface index. Recounted by the timers, so
+    /// entries resolved since then are still counted.
+    incomplete: HashMap<u32, usize>,
+}
+
+impl NeighborCache {
+    pub(crate) fn new() -> Self {
+        NeighborCache {
+            entries: HashMap::new(),
+            incomplete: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn get(&self, interface_index: u32, address: &Ipv6Addr) -> Option<&Neighbor> {
+        self.entries
+            .get(&interface_index)
+            .and_then(|entries| entries.get(address))
+    }
+
+    fn get_mut(&mut self, interface_index: u32, address: &Ipv6Addr) -> Option<&mut Neighbor> {
+        self.entries
+            .get_mut(&interface_index)
+            .and_then(|entries| entries.get_mut(address))
+    }
+
+    fn put(&mut self, interface_index: u32, address: Ipv6Addr, neighbor: Neighbor) {
+        self.entries
+            .entry(interface_index)
+            .or_default()
+            .insert(address, neighbor);
+    }
+
+    /// Adds an incomplete entry for a solicitation just sent, unless the interface already has
+    /// too many.
+    fn insert_incomplete(&mut self, interface_index: u32, address: Ipv6Addr, now: Instant) -> bool {
+        let incomplete = self.incomplete.entry(interface_index).or_default();
+        if *incomplete >= MAX_INCOMPLETE_NEIGHBORS {
+            return false;
+        }
+        *incomplete += 1;
+
+        let mut neighbor = Neighbor::new(None, NeighborState::Incomplete, now + RETRANS_TIMER);
+        neighbor.probes = 1;
+        self.put(interface_index, address, neighbor);
+        true
+    }
+
+    fn count_incomplete(&mut self) {
+        self.incomplete = self
+            .entries
+            .iter()
+            .map(|(&interface_index, entries)| {
+                let count = entries
+                    .values()
+                    .filter(|neighbor| neighbor.state == NeighborState::Incomplete)
+                    .count();
+                (interface_index, count)
+            })
+            .collect();
+    }
+}
+
+/// The state of our addresses with respect to Duplicate Address Detection (RFC 4862 5.4).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AddressState {
+    /// DAD is in progress until the deadline.
+    Tentative {
+        deadline: Instant,
+    },
+    Preferred,
+    Duplicate,
+}
+
+struct OwnAddress {
+    /// The interface index (operating system specific) the address is assigned to.
+    interface_index: u32,
+    state: AddressState,
+}
+
+#[derive(Debug)]
+pub(crate) enum NdpHandlerEvent {
+    /// Received a Neighbor Solicitation, Neighbor Advertisement or Router Solicitation.
+    ReceivedPacket {
+        /// The interface index (operating system specific) the packet arrived on.
+        interface_index: u32,
+        packet: Ipv6Packet<'static>,
+    },
+    /// A packet is being sent to the neighbor: resolves its link-layer address if unknown, or
+    /// starts verifying the reachability of a stale entry.
+    Resolve {
+        interface_index: u32,
+        address: Ipv6Addr,
+    },
+    Shutdown,
+}
+
+pub(crate) async fn spawn_ndp_handler(
+    interfaces: &[NetworkInterface],
+    config: &Config,
+    neighbor_cache: Arc<RwLock<NeighborCache>>,
+    receiver: UnboundedReceiver<NdpHandlerEvent>,
+    sender_ipv6: UnboundedSender<Ipv6HandlerEvent>,
+) -> JoinHandle<()> {
+    let interface_map = interfaces
+        .iter()
+        .map(|i| (i.index, i.clone()))
+        .collect::<HashMap<_, _>>();
+
+    // The first advertisements wait for DAD of the link-local addresses.
+    let start = Instant::now() + RETRANS_TIMER;
+    let advertisers = interfaces
+        .iter()
+        .filter_map(|i| {
+            let interface_config = config.interfaces.get(&i.name).cloned().unwrap_or_default();
+            RouterAdvertiser::new(
+                i,
+                &interface_config.router_advertisement,
+                interface_config.mtu,
+                start,
+            )
+            .map(|advertiser| (i.index, advertiser))
+        })
+        .collect();
+
+    NdpHandler {
+        interfaces: interface_map,
+        addresses: HashMap::new(),
+        advertisers,
+        solicitation_limiters: HashMap::new(),
+        neighbor_cache,
+        receiver,
+        sender_ipv6,
+    }
+    .spawn()
+}
+
+struct NdpHandler {
+    /// The interfaces keyed by the interface index (operating system specific).
+    interfaces: HashMap<u32, NetworkInterface>,
+    /// Our addresses, undergoing or having passed DAD.
+    addresses: HashMap<Ipv6Addr, OwnAddress>,
+    /// The interfaces sending Router Advertisements, keyed by the interface index.
+    advertisers: HashMap<u32, RouterAdvertiser>,
+    /// The rate limiters of the solicitations sent to resolve addresses or verify reachability,
+    /// keyed by the interface index.
+    solicitation_limiters: HashMap<u32, TokenBucket>,
+    neighbor_cache: Arc<RwLock<NeighborCache>>,
+    receiver: UnboundedReceiver<NdpHandlerEvent>,
+    sender_ipv6: UnboundedSender<Ipv6HandlerEvent>,
+}
+
+impl NdpHandler {
+    fn spawn(mut self) -> JoinHandle<()> {
+        let fut = async move {
+            debug!("Started NdpHandler");
+
+            self.start_duplicate_address_detection();
+
+            let mut timer = tokio::time::interval(TIMER_INTERVAL);
+            loop {
+                select! {
+                    _ = timer.tick() => self.handle_timers(),
+                    Some(event) = self.receiver.recv() => {
+                        match event {
+                            NdpHandlerEvent::ReceivedPacket {
+                                interface_index,
+                                packet,
+                            } => self.handle_received_packet(interface_index, packet),
+                            NdpHandlerEvent::Resolve {
+                                interface_index,
+                                address,
+                            } => self.resolve(interface_index, address),
+                            NdpHandlerEvent::Shutdown => return,
+                        }
+                    }
+                }
+            }
+        };
+
+        tokio::runtime::Handle::current().spawn(fut)
+    }
+
+    /// Sends a Neighbor Solicitation for each of our addresses from the unspecified address.
+    fn start_duplicate_address_detection(&mut self) {
+        let deadline = Instant::now() + RETRANS_TIMER;
+        let addresses = self
+            .interfaces
+            .values()
+            .flat_map(|i| {
+                i.ips.iter().filter_map(move |ipn| match ipn {
+                    IpNetwork::V4(_) => None,
+                    IpNetwork::V6(ipv6n) => Some((i.index, ipv6n.ip())),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (interface_index, address) in addresses {
+            self.addresses.insert(
+                address,
+                OwnAddress {
+                    interface_index,
+                    state: AddressState::Tentative { deadline },
+                },
+            );
+            self.send_solicitation(
+                interface_index,
+                Ipv6Addr::UNSPECIFIED,
+                solicited_node_multicast(&address),
+                address,
+            );
+        }
+    }
+
+    fn handle_timers(&mut self) {
+        let now = Instant::now();
+
+        for (address, own) in self.addresses.iter_mut() {
+            if let AddressState::Tentative { deadline } = own.state {
+                if now >= deadline {
+                    info!("Duplicate Address Detection succeeded for {}", address);
+                    own.state = AddressState::Preferred;
+                }
+            }
+        }
+
+        let mut solicitations = vec![];
+        {
+            let mut neighbor_cache = self.neighbor_cache.write().expect("write guard");
+            for (interface_index, entries) in neighbor_cache.entries.iter_mut() {
+                entries.retain(|address, neighbor| {
+                    if now < neighbor.deadline {
+                        return true;
+                    }
+
+                    match neighbor.state {
+                        NeighborState::Incomplete | NeighborState::Probe => {
+                            let max = match neighbor.state {
+                                NeighborState::Incomplete => MAX_MULTICAST_SOLICIT,
+                                _ => MAX_UNICAST_SOLICIT,
+                            };
+                            if neighbor.probes >= max {
+                                debug!(
+                                    "Neighbor unreachable. interface: {}, ipv6: {}",
+                                    interface_index, address
+                                );
+                                return false;
+                            }
+                            neighbor.probes += 1;
+                            neighbor.deadline = now + RETRANS_TIMER;
+                            solicitations.push((*interface_index, *address, neighbor.state));
+                        }
+                        NeighborState::Reachable => neighbor.make_stale(now),
+                        NeighborState::Delay => {
+                            neighbor.state = NeighborState::Probe;
+                            neighbor.probes = 1;
+                            neighbor.deadline = now + RETRANS_TIMER;
+                            solicitations.push((*interface_index, *address, neighbor.state));
+                        }
+                        NeighborState::Stale => {
+                            debug!(
+                                "Removed an unused stale neighbor. interface: {}, ipv6: {}",
+                                interface_index, address
+                            );
+                            return false;
+                        }
+                    }
+                    true
+                });
+            }
+            neighbor_cache.count_incomplete();
+        }
+
+        for (interface_index, address, state) in solicitations {
+            self.solicit(interface_index, address, state == NeighborState::Probe);
+        }
+
+        let due = self
+            .advertisers
+            .iter_mut()
+            .filter_map(|(&interface_index, advertiser)| {
+                advertiser.poll(now).then_some(interface_index)
+            })
+            .collect::<Vec<_>>();
+        for interface_index in due {
+            self.send_router_advertisement(interface_index);
+        }
+    }
+
+    fn resolve(&mut self, interface_index: u32, address: Ipv6Addr) {
+        let now = Instant::now();
+        {
+            let mut neighbor_cache = self.neighbor_cache.write().expect("write guard");
+            match neighbor_cache.get_mut(interface_index, &address) {
+                Some(neighbor) => {
+                    if neighbor.state == NeighborState::Stale {
+                        neighbor.state = NeighborState::Delay;
+                        neighbor.deadline = now + DELAY_FIRST_PROBE_TIME;
+                    }
+                    return;
+                }
+                None => {
+                    if !neighbor_cache.insert_incomplete(interface_index, address, now) {
+                        debug!(
+                            "Too many incomplete neighbors to resolve {} via the interface {}",
+                            address, interface_index
+                        );
+                        return;
+                    }
+                }
+            }
+        }
+
+        self.solicit(interface_index, address, false);
+    }
+
+    /// Sends a Neighbor Solicitation to resolve the address, or a unicast one to verify that the
+    /// neighbor is still reachable. Rate limited per interface; an entry missing a solicitation
+    /// sends the next one after the retransmission timer.
+    fn solicit(&mut self, interface_index: u32, address: Ipv6Addr, unicast: bool) {
+        let limiter = self
+            .solicitation_limiters
+            .entry(interface_index)
+            .or_insert_with(|| TokenBucket::new(SOLICITATION_RATE, SOLICITATION_BURST));
+        if !limiter.try_take() {
+            debug!(
+                "Rate limited the solicitation of {} via the interface {}",
+                address, interface_index
+            );
+            return;
+        }
+
+        let source = match self.source_address(interface_index) {
+            Some(source) => source,
+            None => {
+                error!(
+                    "No usable IPv6 address to solicit {} via the interface {}",
+                    address, interface_index
+                );
+                return;
+            }
+        };
+        let destination = if unicast {
+            address
+        } else {
+            solicited_node_multicast(&address)
+        };
+        self.send_solicitation(interface_index, source, destination, address);
+    }
+
+    fn handle_received_packet(&mut self, interface_index: u32, packet: Ipv6Packet) {
+        if !self.interfaces.contains_key(&interface_index) {
+            error!("Unknown interface index: {}", interface_index);
+            return;
+        }
+
+        // Validate the message (RFC 4861 6.1.1, 7.1).
+        let message = packet.payload();
+        let icmpv6 = match Icmpv6Packet::new(message) {
+            Some(icmpv6) => icmpv6,
+            None => return,
+        };
+        let header_length = match icmpv6.get_icmpv6_type() {
+            Icmpv6Types::RouterSolicit => ROUTER_SOLICITATION_LENGTH,
+            _ => NEIGHBOR_MESSAGE_LENGTH,
+        };
+        if packet.get_hop_limit() != NDP_HOP_LIMIT
+            || icmpv6.get_icmpv6_code().0 != 0
+            || message.len() < header_length
+            || icmpv6.get_checksum()
+                != pnet_packet::icmpv6::checksum(
+                    &icmpv6,
+                    &packet.get_source(),
+                    &packet.get_destination(),
+                )
+        {
+            debug!("Discarded an invalid NDP message: {:?}", packet);
+            return;
+        }
+        let options = match parse_options(&message[header_length..]) {
+            Some(options) => options,
+            None => {
+                debug!(
+                    "Discarded an NDP message with invalid options: {:?}",
+                    packet
+                );
+                return;
+            }
+        };
+
+        if icmpv6.get_icmpv6_type() == Icmpv6Types::RouterSolicit {
+            self.handle_router_solicitation(
+                interface_index,
+                packet.get_source(),
+                find_link_layer_address(&options, NdpOptionTypes::SourceLLAddr.0),
+            );
+            return;
+        }
+
+        let target = target_address(message);
+        if target.is_multicast() {
+            debug!(
+                "Discarded an NDP message for a multicast target: {:?}",
+                packet
+            );
+            return;
+        }
+
+        match icmpv6.get_icmpv6_type() {
+            Icmpv6Types::NeighborSolicit => self.handle_solicitation(
+                interface_index,
+                packet.get_source(),
+                packet.get_destination(),
+                target,
+                find_link_layer_address(&options, NdpOptionTypes::SourceLLAddr.0),
+            ),
+            Icmpv6Types::NeighborAdvert => self.handle_advertisement(
+                interface_index,
+                packet.get_destination(),
+                message[4],
+                target,
+                find_link_layer_address(&options, NdpOptionTypes::TargetLLAddr.0),
+            ),
+            other => debug!("Unsupported NDP message type: {:?}", other),
+        }
+    }
+
+    /// RFC 4861 7.2.3
+    fn handle_solicitation(
+        &mut self,
+        interface_index: u32,
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+        target: Ipv6Addr,
+        source_mac: Option<MacAddr>,
+    ) {
+        let duplicate_address_detection = source.is_unspecified();
+        if duplicate_address_detection
+            && (source_mac.is_some() || destination != solicited_node_multicast(&target))
+        {
+            debug!("Discarded an invalid DAD solicitation for {}", target);
+            return;
+        }
+
+        let state = match self.addresses.get_mut(&target) {
+            Some(own) if own.interface_index == interface_index => &mut own.state,
+            // We don't proxy.
+            _ => return,
+        };
+
+        match *state {
+            AddressState::Tentative { .. } => {
+                // Someone else is performing DAD for the address.
+                if duplicate_address_detection {
+                    error!("Duplicate address detected: {}", target);
+                    *state = AddressState::Duplicate;
+                }
+                return;
+            }
+            AddressState::Duplicate => return,
+            AddressState::Preferred => {}
+        }
+
+        if let Some(mac) = source_mac {
+            self.update_from_solicitation(interface_index, source, mac);
+        }
+
+        // A reply to DAD lets all the nodes know the address is in use.
+        let (reply_destination, solicited) = if duplicate_address_detection {
+            (ALL_NODES, false)
+        } else {
+            (source, true)
+        };
+        self.send_advertisement(interface_index, target, reply_destination, solicited);
+    }
+
+    /// RFC 4861 6.2.6
+    fn handle_router_solicitation(
+        &mut self,
+        interface_index: u32,
+        source: Ipv6Addr,
+        source_mac: Option<MacAddr>,
+    ) {
+        if source.is_unspecified() && source_mac.is_some() {
+            debug!("Discarded an invalid Router Solicitation from the unspecified address");
+            return;
+        }
+        if !self.advertisers.contains_key(&interface_index) {
+            return;
+        }
+
+        if let Some(mac) = source_mac {
+            self.update_from_solicitation(interface_index, source, mac);
+        }
+        if let Some(advertiser) = self.advertisers.get_mut(&interface_index) {
+            advertiser.solicited(Instant::now());
+        }
+    }
+
+    fn send_router_advertisement(&self, interface_index: u32) {
+        // Router Advertisements must be sent from the link-local address (RFC 4861 4.2).
+        let source = match self.addresses.iter().find(|(address, own)| {
+            own.interface_index == interface_index
+                && own.state == AddressState::Preferred
+                && is_link_local(address)
+        }) {
+            Some((address, _)) => *address,
+            None => {
+                debug!(
+                    "No usable link-local address to advertise via the interface {}",
+                    interface_index
+                );
+                return;
+            }
+        };
+
+        let message =
+            self.advertisers[&interface_index].build_message(self.interfaces[&interface_index].mac);
+        self.send(interface_index, source, ALL_NODES, message);
+    }
+
+    /// Records the link-layer address of the sender of a solicitation.
+    fn update_from_solicitation(&self, interface_index: u32, address: Ipv6Addr, mac: MacAddr) {
+        let mut neighbor_cache = self.neighbor_cache.write().expect("write guard");
+        match neighbor_cache.get_mut(interface_index, &address) {
+            Some(neighbor) if neighbor.mac == Some(mac) => {}
+            Some(neighbor) => {
+                debug!(
+                    "Updated neighbor cache. interface: {}, ipv6: {}, old_mac: {:?}, new_mac: {}",
+                    interface_index, address, neighbor.mac, mac
+                );
+                neighbor.mac = Some(mac);
+                neighbor.make_stale(Instant::now());
+            }
+            None => neighbor_cache.put(
+                interface_index,
+                address,
+                Neighbor::new(
+                    Some(mac),
+                    NeighborState::Stale,
+                    Instant::now() + STALE_TIMEOUT,
+                ),
+            ),
+        }
+    }
+
+    /// RFC 4861 7.2.5
+    fn handle_advertisement(
+        &mut self,
+        interface_index: u32,
+        destination: Ipv6Addr,
+        flags: u8,
+        target: Ipv6Addr,
+        target_mac: Option<MacAddr>,
+    ) {
+        let solicited = flags & NeighborAdvertFlags::Solicited != 0;
+        let override_ = flags & NeighborAdvertFlags::Override != 0;
+        if destination.is_multicast() && solicited {
+            debug!("Discarded a solicited advertisement to a multicast address");
+            return;
+        }
+
+        if let Some(own) = self.addresses.get_mut(&target) {
+            // Our own advertisement looped back.
+            if target_mac.is_some() && target_mac == self.interfaces[&own.interface_index].mac {
+                return;
+            }
+            match own.state {
+                AddressState::Tentative { .. } => {
+                    error!("Duplicate address detected: {}", target);
+                    own.state = AddressState::Duplicate;
+                }
+                _ => error!("Another node advertises our address: {}", target),
+            }
+            return;
+        }
+
+        let now = Instant::now();
+        let mut neighbor_cache = self.neighbor_cache.write().expect("write guard");
+        let neighbor = match neighbor_cache.get_mut(interface_index, &target) {
+            Some(neighbor) => neighbor,
+            None => return,
+        };
+
+        if neighbor.state == NeighborState::Incomplete {
+            let mac = match target_mac {
+                Some(mac) => mac,
+                None => return,
+            };
+            neighbor.mac = Some(mac);
+            if solicited {
+                neighbor.state = NeighborState::Reachable;
+                neighbor.deadline = now + REACHABLE_TIME;
+            } else {
+                neighbor.make_stale(now);
+            }
+            debug!(
+                "Resolved a neighbor. interface: {}, ipv6: {}, mac: {}",
+                interface_index, target, mac
+            );
+            return;
+        }
+
+        let different = target_mac.is_some() && target_mac != neighbor.mac;
+        if !override_ && different {
+            if neighbor.state == NeighborState::Reachable {
+                neighbor.make_stale(now);
+            }
+            return;
+        }
+
+        if different {
+            neighbor.mac = target_mac;
+        }
+        if solicited {
+            neighbor.state = NeighborState::Reachable;
+            neighbor.deadline = now + REACHABLE_TIME;
+        } else if different {
+            neighbor.make_stale(now);
+        }
+    }
+
+    fn send_solicitation(
+        &self,
+        interface_index: u32,
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+        target: Ipv6Addr,
+    ) {
+        // The source link-layer address must not be included in DAD.
+        let mac = if source.is_unspecified() {
+            None
+        } else {
+            self.interfaces[&interface_index].mac
+        };
+        let message = build_neighbor_message(Icmpv6Types::NeighborSolicit.0, 0, target, mac);
+        self.send(interface_index, source, destination, message);
+    }
+
+    fn send_advertisement(
+        &self,
+        interface_index: u32,
+        target: Ipv6Addr,
+        destination: Ipv6Addr,
+        solicited: bool,
+    ) {
+        let mut flags = NeighborAdvertFlags::Router | NeighborAdvertFlags::Override;
+        if solicited {
+            flags |= NeighborAdvertFlags::Solicited;
+        }
+        let message = build_neighbor_message(
+            Icmpv6Types::NeighborAdvert.0,
+            flags,
+            target,
+            self.interfaces[&interface_index].mac,
+        );
+        self.send(interface_index, target, destination, message);
+    }
+
+    fn send(
+        &self,
+        interface_index: u32,
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+        message: Vec<u8>,
+    ) {
+        let mut message = message;
+        let mut icmpv6 = MutableIcmpv6Packet::new(&mut message).expect("valid ICMPv6 packet");
+        icmpv6.set_checksum(pnet_packet::icmpv6::checksum(
+            &icmpv6.to_immutable(),
+            &source,
+            &destination,
+        ));
+
+        let packet = build_ipv6_packet(
+            source,
+            destination,
+            IpNextHeaderProtocols::Icmpv6,
+            NDP_HOP_LIMIT,
+            &message,
+        );
+        if let Err(e) = self.sender_ipv6.send(Ipv6HandlerEvent::SendPacket {
+            interface_index,
+            packet,
+        }) {
+            error!("Failed to send the NDP message to Ipv6Handler: {}", e);
+        }
+    }
+
+    /// Picks a preferred address of the interface, link-local first.
+    fn source_address(&self, interface_index: u32) -> Option<Ipv6Addr> {
+        let mut addresses = self
+            .addresses
+            .iter()
+            .filter(|(_, own)| {
+                own.interface_index == interface_index && own.state == AddressState::Preferred
+            })
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        addresses.sort_by_key(|address| !is_link_local(address));
+        addresses.first().copied()
+    }
+}
+
+pub(crate) fn is_link_local(address: &Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Builds a Neighbor Solicitation or Advertisement with the checksum left zero.
+fn build_neighbor_message(
+    icmpv6_type: u8,
+    flags: u8,
+    target: Ipv6Addr,
+    mac: Option<MacAddr>,
+) -> Vec<u8> {
+    let mut message = vec![0u8; NEIGHBOR_MESSAGE_LENGTH];
+    message[0] = icmpv6_type;
+    message[4] = flags;
+    message[8..NEIGHBOR_MESSAGE_LENGTH].copy_from_slice(&target.octets());
+
+    if let Some(mac) = mac {
+        let option_type = if icmpv6_type == Icmpv6Types::NeighborSolicit.0 {
+            NdpOptionTypes::SourceLLAddr
+        } else {
+            NdpOptionTypes::TargetLLAddr
+        };
+        message.extend_from_slice(&[option_type.0, (LINK_LAYER_ADDRESS_OPTION_LENGTH / 8) as u8]);
+        message.extend_from_slice(&mac.octets());
+    }
+
+    message
+}
+
+fn target_address(message: &[u8]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&message[8..NEIGHBOR_MESSAGE_LENGTH]);
+    Ipv6Addr::from(octets)
+}
+
+/// Splits NDP options into their types and data. Returns `None` if any option has zero length
+/// (RFC 4861 4.6).
+pub(crate) fn parse_options(mut options: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut parsed = vec![];
+    while !options.is_empty() {
+        let length = *options.get(1)? as usize * 8;
+        if length == 0 || length > options.len() {
+            return None;
+        }
+        parsed.push((options[0], &options[2..length]));
+        options = &options[length..];
+    }
+    Some(parsed)
+}
+
+pub(crate) fn find_link_layer_address(options: &[(u8, &[u8])], option_type: u8) -> Option<MacAddr> {
+    options
+        .iter()
+        .find(|(t, data)| *t == option_type && data.len() >= 6)
+        .map(|(_, data)| MacAddr::new(data[0], data[1], data[2], data[3], data[4], data[5]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    const INTERFACE_INDEX: u32 = 1;
+
+    fn neighbor(index: u16) -> Ipv6Addr {
+        Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, index)
+    }
+
+    /// A handler with a preferred link-local address, and the receiver of the packets it sends.
+    fn handler() -> (NdpHandler, UnboundedReceiver<Ipv6HandlerEvent>) {
+        let interface = NetworkInterface {
+            name: "eth0".to_string(),
+            description: String::new(),
+            index: INTERFACE_INDEX,
+            mac: Some(MacAddr::new(0x02, 0, 0, 0, 0, 1)),
+            ips: vec!["fe80::ffff/64".parse().unwrap()],
+            flags: 0,
+        };
+        let addresses = HashMap::from([(
+            Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0xffff),
+            OwnAddress {
+                interface_index: INTERFACE_INDEX,
+                state: AddressState::Preferred,
+            },
+        )]);
+        let (_, receiver) = unbounded_channel();
+        let (sender_ipv6, receiver_ipv6) = unbounded_channel();
+        let handler = NdpHandler {
+            interfaces: HashMap::from([(INTERFACE_INDEX, interface)]),
+            addresses,
+            advertisers: HashMap::new(),
+            solicitation_limiters: HashMap::new(),
+            neighbor_cache: Arc::new(RwLock::new(NeighborCache::new())),
+            receiver,
+            sender_ipv6,
+        };
+        (handler, receiver_ipv6)
+    }
+
+    fn state(handler: &NdpHandler, address: &Ipv6Addr) -> Option<NeighborState> {
+        let neighbor_cache = handler.neighbor_cache.read().unwrap();
+        neighbor_cache
+            .get(INTERFACE_INDEX, address)
+            .map(|neighbor| neighbor.state)
+    }
+
+    #[test]
+    fn caps_incomplete_neighbors() {
+        let now = Instant::now();
+        let mut neighbor_cache = NeighborCache::new();
+        for i in 0..MAX_INCOMPLETE_NEIGHBORS {
+            assert!(neighbor_cache.insert_incomplete(INTERFACE_INDEX, neighbor(i as u16), now));
+        }
+        assert!(!neighbor_cache.insert_incomplete(INTERFACE_INDEX, neighbor(0xffff), now));
+        assert!(neighbor_cache.insert_incomplete(2, neighbor(0xffff), now));
+
+        // Resolved entries no longer count once recounted.
+        let resolved = neighbor_cache
+            .get_mut(INTERFACE_INDEX, &neighbor(0))
+            .unwrap();
+        resolved.mac = Some(MacAddr::new(0x02, 0, 0, 0, 0, 2));
+        resolved.make_stale(now);
+        assert!(!neighbor_cache.insert_incomplete(INTERFACE_INDEX, neighbor(0xffff), now));
+        neighbor_cache.count_incomplete();
+        assert!(neighbor_cache.insert_incomplete(INTERFACE_INDEX, neighbor(0xffff), now));
+        assert!(!neighbor_cache.insert_incomplete(INTERFACE_INDEX, neighbor(0xfffe), now));
+    }
+
+    #[test]
+    fn rate_limits_solicitations() {
+        let (mut handler, mut receiver) = handler();
+        let count = 2 * SOLICITATION_BURST as u16;
+        for i in 1..=count {
+            handler.resolve(INTERFACE_INDEX, neighbor(i));
+        }
+
+        let mut sent = 0;
+        while let Ok(event) = receiver.try_recv() {
+            assert!(matches!(
+                event,
+                Ipv6HandlerEvent::SendPacket {
+                    interface_index: INTERFACE_INDEX,
+                    ..
+                }
+            ));
+            sent += 1;
+        }
+        // The bucket may have refilled a little while resolving.
+        assert!(sent >= SOLICITATION_BURST && sent < count as u32);
+        // The entries without a solicitation still retransmit.
+        assert_eq!(
+            state(&handler, &neighbor(count)),
+            Some(NeighborState::Incomplete)
+        );
+    }
+
+    #[test]
+    fn removes_unused_stale_neighbors() {
+        let (mut handler, _receiver) = handler();
+        let mac = Some(MacAddr::new(0x02, 0, 0, 0, 0, 2));
+        let now = Instant::now();
+        let expired = now - Duration::from_secs(1);
+        {
+            let mut neighbor_cache = handler.neighbor_cache.write().unwrap();
+            neighbor_cache.put(
+                INTERFACE_INDEX,
+                neighbor(1),
+                Neighbor::new(mac, NeighborState::Stale, expired),
+            );
+            neighbor_cache.put(
+                INTERFACE_INDEX,
+                neighbor(2),
+                Neighbor::new(mac, NeighborState::Stale, now + STALE_TIMEOUT),
+            );
+            neighbor_cache.put(
+                INTERFACE_INDEX,
+                neighbor(3),
+                Neighbor::new(mac, NeighborState::Reachable, expired),
+            );
+        }
+
+        handler.handle_timers();
+        assert_eq!(state(&handler, &neighbor(1)), None);
+        assert_eq!(state(&handler, &neighbor(2)), Some(NeighborState::Stale));
+        assert_eq!(state(&handler, &neighbor(3)), Some(NeighborState::Stale));
+        {
+            // Entries made stale are kept for the timeout.
+            let neighbor_cache = handler.neighbor_cache.read().unwrap();
+            let stale = neighbor_cache.get(INTERFACE_INDEX, &neighbor(3)).unwrap();
+            assert!(stale.deadline >= now + STALE_TIMEOUT);
+        }
+
+        // Stale entries in use are verified instead.
+        handler.resolve(INTERFACE_INDEX, neighbor(3));
+        assert_eq!(state(&handler, &neighbor(3)), Some(NeighborState::Delay));
+    }
+}
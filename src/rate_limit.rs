@@ -0,0 +1,43 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket refilled at a constant rate, up to its burst size.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    /// Tokens added per second.
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a bucket which starts full.
+    pub(crate) fn new(rate: u32, burst: u32) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if available.
+    pub(crate) fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long it has been since a token was last requested.
+    pub(crate) fn idle_for(&self) -> Duration {
+        self.last_refill.elapsed()
+    }
+}
@@ -0,0 +1,187 @@
+use crate::config::{AdvertisedPrefix, RouterAdvertisementConfig};
+use crate::ipv6::DEFAULT_HOP_LIMIT;
+use crate::ndp;
+use ipnetwork::{IpNetwork, Ipv6Network};
+use pnet_datalink::{MacAddr, NetworkInterface};
+use pnet_packet::icmpv6::ndp::{NdpOptionTypes, RouterAdvertFlags};
+use pnet_packet::icmpv6::Icmpv6Types;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The length of the Router Advertisement message without options.
+const ROUTER_ADVERTISEMENT_LENGTH: usize = 16;
+
+/// Protocol constants (RFC 4861 10).
+const MAX_INITIAL_RTR_ADVERT_INTERVAL: Duration = Duration::from_secs(16);
+const MAX_INITIAL_RTR_ADVERTISEMENTS: u8 = 3;
+const MIN_DELAY_BETWEEN_RAS: Duration = Duration::from_secs(3);
+
+/// The upper limit of the router lifetime (RFC 4861 6.2.1).
+pub(crate) const MAX_ROUTER_LIFETIME: u32 = 9000;
+
+/// The Recursive DNS Server option (RFC 8106 5.1).
+const OPTION_RDNSS: u8 = 25;
+
+/// Prefix information option flags (RFC 4861 4.6.2).
+const PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// Sends the Router Advertisements of an interface: periodically, and in response to Router
+/// Solicitations (RFC 4861 6.2).
+pub(crate) struct RouterAdvertiser {
+    config: RouterAdvertisementConfig,
+    /// The configured prefixes, or the prefixes of the interface's global addresses.
+    prefixes: Vec<AdvertisedPrefix>,
+    mtu: u16,
+    /// The number of unsolicited advertisements sent so far, up to the initial ones.
+    sent: u8,
+    next_advertisement: Instant,
+    last_advertisement: Option<Instant>,
+}
+
+impl RouterAdvertiser {
+    /// Returns `None` unless the interface sends advertisements. The first one is sent at `start`.
+    pub(crate) fn new(
+        interface: &NetworkInterface,
+        config: &RouterAdvertisementConfig,
+        mtu: u16,
+        start: Instant,
+    ) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+
+        let prefixes = if config.prefixes.is_empty() {
+            interface
+                .ips
+                .iter()
+                .filter_map(|ipn| match ipn {
+                    IpNetwork::V6(ipv6n) if !ndp::is_link_local(&ipv6n.ip()) => {
+                        Some(AdvertisedPrefix::new(
+                            Ipv6Network::new(ipv6n.network(), ipv6n.prefix())
+                                .expect("valid prefix"),
+                        ))
+                    }
+                    _ => None,
+                })
+                .collect()
+        } else {
+            config.prefixes.clone()
+        };
+
+        Some(RouterAdvertiser {
+            config: config.clone(),
+            prefixes,
+            mtu,
+            sent: 0,
+            next_advertisement: start,
+            last_advertisement: None,
+        })
+    }
+
+    /// Returns whether an advertisement is due, scheduling the next unsolicited one if so.
+    pub(crate) fn poll(&mut self, now: Instant) -> bool {
+        if now < self.next_advertisement {
+            return false;
+        }
+
+        self.sent = self.sent.saturating_add(1);
+        let max = Duration::from_secs(self.config.interval as u64);
+        let min = max / 3;
+        let mut interval = min + jitter(max - min);
+        if self.sent <= MAX_INITIAL_RTR_ADVERTISEMENTS {
+            interval = interval.min(MAX_INITIAL_RTR_ADVERT_INTERVAL);
+        }
+        self.next_advertisement = now + interval;
+        self.last_advertisement = Some(now);
+        true
+    }
+
+    /// Schedules an advertisement in response to a Router Solicitation. Advertisements to the
+    /// all-nodes group are sent at most once per `MIN_DELAY_BETWEEN_RAS` (RFC 4861 6.2.6).
+    pub(crate) fn solicited(&mut self, now: Instant) {
+        let earliest = match self.last_advertisement {
+            Some(last) => (last + MIN_DELAY_BETWEEN_RAS).max(now),
+            None => now,
+        };
+        self.next_advertisement = self.next_advertisement.min(earliest);
+    }
+
+    /// Builds the Router Advertisement with the checksum left zero.
+    pub(crate) fn build_message(&self, mac: Option<MacAddr>) -> Vec<u8> {
+        let mut message = vec![0u8; ROUTER_ADVERTISEMENT_LENGTH];
+        message[0] = Icmpv6Types::RouterAdvert.0;
+        message[4] = DEFAULT_HOP_LIMIT;
+        if self.config.managed {
+            message[5] |= RouterAdvertFlags::ManagedAddressConf;
+        }
+        if self.config.other {
+            message[5] |= RouterAdvertFlags::OtherConf;
+        }
+        message[6..8].copy_from_slice(&self.router_lifetime().to_be_bytes());
+
+        if let Some(mac) = mac {
+            message.extend_from_slice(&[NdpOptionTypes::SourceLLAddr.0, 1]);
+            message.extend_from_slice(&mac.octets());
+        }
+
+        if self.config.advertise_mtu {
+            message.extend_from_slice(&[NdpOptionTypes::MTU.0, 1, 0, 0]);
+            message.extend_from_slice(&(self.mtu as u32).to_be_bytes());
+        }
+
+        for prefix in &self.prefixes {
+            let mut flags = 0;
+            if prefix.on_link {
+                flags |= PREFIX_FLAG_ON_LINK;
+            }
+            if prefix.autonomous {
+                flags |= PREFIX_FLAG_AUTONOMOUS;
+            }
+            message.extend_from_slice(&[
+                NdpOptionTypes::PrefixInformation.0,
+                4,
+                prefix.prefix.prefix(),
+                flags,
+            ]);
+            message.extend_from_slice(&prefix.valid_lifetime.to_be_bytes());
+            message.extend_from_slice(&prefix.preferred_lifetime.to_be_bytes());
+            message.extend_from_slice(&[0; 4]);
+            message.extend_from_slice(&prefix.prefix.network().octets());
+        }
+
+        if !self.config.rdnss.is_empty() {
+            // Servers may be used for up to three intervals (RFC 8106 5.1).
+            let lifetime = self.config.interval.saturating_mul(3);
+            message.extend_from_slice(&[OPTION_RDNSS, 1 + 2 * self.config.rdnss.len() as u8, 0, 0]);
+            message.extend_from_slice(&lifetime.to_be_bytes());
+            for server in &self.config.rdnss {
+                message.extend_from_slice(&server.octets());
+            }
+        }
+
+        message
+    }
+
+    /// Seconds. Defaults to three times the maximum interval.
+    fn router_lifetime(&self) -> u16 {
+        self.config.lifetime.unwrap_or_else(|| {
+            self.config
+                .interval
+                .saturating_mul(3)
+                .min(MAX_ROUTER_LIFETIME) as u16
+        })
+    }
+}
+
+/// A pseudo-random duration up to the span, to desynchronize the advertisements of routers on a
+/// link.
+fn jitter(span: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos() as u64;
+    match span.as_millis() as u64 {
+        0 => Duration::ZERO,
+        millis => Duration::from_millis(nanos % millis),
+    }
+}
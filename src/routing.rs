@@ -0,0 +1,329 @@
+use crate::config::Config;
+use ipnetwork::{IpNetwork, Ipv4Network};
+use pnet_datalink::NetworkInterface;
+use pnet_packet::ipv4::Ipv4Packet;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use tracing::{debug, error};
+
+/// The routing table used when no policy rule selects another one. Connected routes live here.
+pub(crate) const MAIN_TABLE: &str = "main";
+
+/// Named routing tables together with the ordered policy rules selecting among them.
+pub(crate) struct RoutingPolicy {
+    rules: Vec<RoutingRule>,
+    tables: HashMap<String, RoutingTable>,
+}
+
+#[derive(Debug)]
+struct RoutingRule {
+    source: Option<Ipv4Network>,
+    destination: Option<Ipv4Network>,
+    protocol: Option<u8>,
+    dscp: Option<u8>,
+    /// The interface index (operating system specific) of the ingress interface.
+    interface_index: Option<u32>,
+    table: String,
+}
+
+/// The fields of a packet the rules match on.
+struct LookupKey {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    dscp: u8,
+    /// `None` for packets originated by us.
+    ingress_interface_index: Option<u32>,
+}
+
+/// Unicast reverse path forwarding (uRPF) mode of an interface (RFC 3704).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum RpfMode {
+    #[default]
+    Off,
+    /// The route back to the source must go out the ingress interface.
+    Strict,
+    /// Any unicast route back to the source is enough.
+    Loose,
+}
+
+/// IPv4 routing table. Lookups are longest-prefix-match.
+pub(crate) struct RoutingTable {
+    /// Routes sorted by prefix length in descending order so that the first match is the longest.
+    routes: Vec<Route>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Route {
+    pub(crate) destination: Ipv4Network,
+    pub(crate) route_type: RouteType,
+    /// Equal-cost next hops. A flow is pinned to one of them by its hash. Empty unless the route
+    /// is a unicast route.
+    pub(crate) next_hops: Vec<NextHop>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RouteType {
+    /// Forward to the next hops.
+    Unicast,
+    /// Silently drop.
+    Blackhole,
+    /// Drop and send ICMP host unreachable.
+    Unreachable,
+    /// Drop and send ICMP communication administratively prohibited.
+    Prohibit,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct NextHop {
+    /// `None` if the destination is directly connected to the interface.
+    pub(crate) gateway: Option<Ipv4Addr>,
+    /// The interface index (operating system specific).
+    pub(crate) interface_index: u32,
+}
+
+impl RoutingPolicy {
+    /// Builds the routing tables of the VRF from the connected routes of its interfaces and the
+    /// static routes, and the rules from the configuration.
+    pub(crate) fn build(vrf: &str, interfaces: &[NetworkInterface], config: &Config) -> Self {
+        let mut tables = HashMap::new();
+        tables.insert(MAIN_TABLE.to_string(), RoutingTable::connected(interfaces));
+
+        for static_route in config.routes.iter().filter(|r| r.vrf == vrf) {
+            if static_route.route_type != RouteType::Unicast {
+                tables
+                    .entry(static_route.table.clone())
+                    .or_insert_with(RoutingTable::new)
+                    .add(Route {
+                        destination: static_route.destination,
+                        route_type: static_route.route_type,
+                        next_hops: vec![],
+                    });
+                continue;
+            }
+
+            let next_hops = static_route
+                .next_hops
+                .iter()
+                .filter_map(|nh| {
+                    let interface_index = match &nh.interface {
+                        Some(name) => interfaces.iter().find(|i| &i.name == name).map(|i| i.index),
+                        None => tables[MAIN_TABLE].resolve_interface(&nh.gateway),
+                    };
+
+                    if interface_index.is_none() {
+                        error!(
+                            "Could not resolve the interface of the next hop {} for the route {}",
+                            nh.gateway, static_route.destination
+                        );
+                    }
+
+                    interface_index.map(|interface_index| NextHop {
+                        gateway: Some(nh.gateway),
+                        interface_index,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            if next_hops.is_empty() {
+                error!(
+                    "Ignored the route {} as it has no usable next hop",
+                    static_route.destination
+                );
+                continue;
+            }
+
+            tables
+                .entry(static_route.table.clone())
+                .or_insert_with(RoutingTable::new)
+                .add(Route {
+                    destination: static_route.destination,
+                    route_type: RouteType::Unicast,
+                    next_hops,
+                });
+        }
+
+        let rules = config
+            .rules
+            .iter()
+            .filter(|r| r.vrf == vrf)
+            .filter_map(|rule| {
+                if !tables.contains_key(&rule.table) {
+                    error!("Ignored the rule {:?} as the table has no routes", rule);
+                    return None;
+                }
+
+                let interface_index = match &rule.interface {
+                    Some(name) => match interfaces.iter().find(|i| &i.name == name) {
+                        Some(i) => Some(i.index),
+                        None => {
+                            error!("Ignored the rule {:?} as the interface is unknown", rule);
+                            return None;
+                        }
+                    },
+                    None => None,
+                };
+
+                Some(RoutingRule {
+                    source: rule.source,
+                    destination: rule.destination,
+                    protocol: rule.protocol,
+                    dscp: rule.dscp,
+                    interface_index,
+                    table: rule.table.clone(),
+                })
+            })
+            .collect();
+
+        RoutingPolicy { rules, tables }
+    }
+
+    /// Evaluates the rules in order and looks up the table selected by the first matching rule.
+    /// If that table has no route to the destination, evaluation continues with the next rule.
+    /// The main table is consulted when no rule yields a route.
+    ///
+    /// `ingress_interface_index` is `None` for packets originated by us.
+    pub(crate) fn lookup(
+        &self,
+        packet: &Ipv4Packet,
+        ingress_interface_index: Option<u32>,
+    ) -> Option<&Route> {
+        self.lookup_by(&LookupKey {
+            source: packet.get_source(),
+            destination: packet.get_destination(),
+            protocol: packet.get_next_level_protocol().0,
+            dscp: packet.get_dscp(),
+            ingress_interface_index,
+        })
+    }
+
+    /// Unicast reverse path forwarding check of the route back to the source of the packet.
+    pub(crate) fn check_reverse_path(
+        &self,
+        packet: &Ipv4Packet,
+        ingress_interface_index: u32,
+        mode: RpfMode,
+    ) -> bool {
+        if mode == RpfMode::Off {
+            return true;
+        }
+
+        match self.lookup_reverse(packet) {
+            Some(route) if route.route_type == RouteType::Unicast => match mode {
+                RpfMode::Strict => route
+                    .next_hops
+                    .iter()
+                    .any(|nh| nh.interface_index == ingress_interface_index),
+                RpfMode::Loose | RpfMode::Off => true,
+            },
+            _ => false,
+        }
+    }
+
+    /// Looks up the route back to the source of the packet, as if we were sending a reply.
+    pub(crate) fn lookup_reverse(&self, packet: &Ipv4Packet) -> Option<&Route> {
+        self.lookup_by(&LookupKey {
+            source: packet.get_destination(),
+            destination: packet.get_source(),
+            protocol: packet.get_next_level_protocol().0,
+            dscp: packet.get_dscp(),
+            ingress_interface_index: None,
+        })
+    }
+
+    fn lookup_by(&self, key: &LookupKey) -> Option<&Route> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(key))
+            .find_map(|rule| self.tables[&rule.table].lookup(&key.destination))
+            .or_else(|| self.tables[MAIN_TABLE].lookup(&key.destination))
+    }
+}
+
+impl RoutingRule {
+    fn matches(&self, key: &LookupKey) -> bool {
+        self.source.iter().all(|p| p.contains(key.source))
+            && self.destination.iter().all(|p| p.contains(key.destination))
+            && self.protocol.iter().all(|&p| p == key.protocol)
+            && self.dscp.iter().all(|&d| d == key.dscp)
+            && self
+                .interface_index
+                .iter()
+                .all(|&i| Some(i) == key.ingress_interface_index)
+    }
+}
+
+impl RoutingTable {
+    pub(crate) fn new() -> Self {
+        RoutingTable { routes: vec![] }
+    }
+
+    /// Builds a routing table containing the connected routes of the interfaces.
+    pub(crate) fn connected(interfaces: &[NetworkInterface]) -> Self {
+        let mut table = RoutingTable::new();
+
+        for i in interfaces {
+            for ipn in &i.ips {
+                if let IpNetwork::V4(ipv4n) = ipn {
+                    table.add(Route {
+                        destination: Ipv4Network::new(ipv4n.network(), ipv4n.prefix())
+                            .expect("valid prefix"),
+                        route_type: RouteType::Unicast,
+                        next_hops: vec![NextHop {
+                            gateway: None,
+                            interface_index: i.index,
+                        }],
+                    });
+                }
+            }
+        }
+
+        table
+    }
+
+    pub(crate) fn add(&mut self, route: Route) {
+        debug!("Added a route: {:?}", route);
+
+        // Insert after any route with the same or a longer prefix, so that earlier routes win ties.
+        let position = self
+            .routes
+            .iter()
+            .position(|r| r.destination.prefix() < route.destination.prefix())
+            .unwrap_or(self.routes.len());
+        self.routes.insert(position, route);
+    }
+
+    pub(crate) fn lookup(&self, destination: &Ipv4Addr) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|r| r.destination.contains(*destination))
+    }
+
+    /// Returns the interface of the connected route covering the address.
+    fn resolve_interface(&self, address: &Ipv4Addr) -> Option<u32> {
+        self.routes
+            .iter()
+            .filter(|r| r.destination.contains(*address))
+            .flat_map(|r| r.next_hops.iter())
+            .find(|nh| nh.gateway.is_none())
+            .map(|nh| nh.interface_index)
+    }
+}
+
+impl Route {
+    /// Picks one of the next hops by the flow hash so that packets of a flow take the same path.
+    /// Returns `None` if the route is not a unicast route.
+    pub(crate) fn select_next_hop(&self, flow_hash: u64) -> Option<&NextHop> {
+        if self.next_hops.is_empty() {
+            return None;
+        }
+        Some(&self.next_hops[(flow_hash % self.next_hops.len() as u64) as usize])
+    }
+}
+
+impl NextHop {
+    /// The address to be resolved into a MAC address when forwarding to the destination.
+    pub(crate) fn address(&self, destination: Ipv4Addr) -> Ipv4Addr {
+        self.gateway.unwrap_or(destination)
+    }
+}
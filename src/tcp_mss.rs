@@ -0,0 +1,213 @@
+use crate::checksum;
+use crate::ipv4::IPV4_HEADER_LENGTH;
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::tcp::{TcpFlags, TcpPacket};
+
+/// The length of the IPv4 and TCP headers without options, subtracted from the MTU to get the MSS.
+const TCP_IPV4_HEADERS_LENGTH: u16 = 40;
+
+const TCP_HEADER_LENGTH: usize = 20;
+const TCP_CHECKSUM_OFFSET: usize = 16;
+
+const OPTION_END_OF_LIST: u8 = 0;
+const OPTION_NO_OPERATION: u8 = 1;
+const OPTION_MAXIMUM_SEGMENT_SIZE: u8 = 2;
+
+/// How the MSS option of the TCP SYNs forwarded via an interface is clamped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum MssClamp {
+    #[default]
+    Off,
+    /// To the MTU of the interface minus the IPv4 and TCP headers.
+    Mtu,
+    Fixed(u16),
+}
+
+impl MssClamp {
+    /// The maximum MSS allowed via an interface with the MTU.
+    pub(crate) fn limit(&self, mtu: u16) -> Option<u16> {
+        match self {
+            MssClamp::Off => None,
+            MssClamp::Mtu => Some(mtu.saturating_sub(TCP_IPV4_HEADERS_LENGTH)),
+            MssClamp::Fixed(mss) => Some(*mss),
+        }
+    }
+}
+
+/// Lowers the MSS option of a TCP SYN or SYN-ACK in the IPv4 packet to the limit, updating the
+/// TCP checksum. Other packets are left untouched.
+pub(crate) fn clamp(packet: &mut [u8], limit: u16) {
+    let header_length = match Ipv4Packet::new(packet) {
+        Some(ipv4)
+            if ipv4.get_next_level_protocol() == IpNextHeaderProtocols::Tcp
+                && ipv4.get_fragment_offset() == 0
+                && ipv4.get_header_length() as usize * 4 >= IPV4_HEADER_LENGTH =>
+        {
+            ipv4.get_header_length() as usize * 4
+        }
+        _ => return,
+    };
+    let segment = match packet.get_mut(header_length..) {
+        Some(segment) => segment,
+        None => return,
+    };
+
+    let options_end = match TcpPacket::new(segment) {
+        Some(tcp) if tcp.get_flags() & TcpFlags::SYN != 0 => tcp.get_data_offset() as usize * 4,
+        _ => return,
+    };
+    if options_end < TCP_HEADER_LENGTH || options_end > segment.len() {
+        return;
+    }
+
+    let mut offset = TCP_HEADER_LENGTH;
+    while offset < options_end {
+        match segment[offset] {
+            OPTION_END_OF_LIST => return,
+            OPTION_NO_OPERATION => {
+                offset += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        let length = match segment.get(offset + 1) {
+            Some(&length) if length >= 2 && offset + length as usize <= options_end => {
+                length as usize
+            }
+            _ => return,
+        };
+
+        if segment[offset] == OPTION_MAXIMUM_SEGMENT_SIZE && length == 4 {
+            let mss = u16::from_be_bytes([segment[offset + 2], segment[offset + 3]]);
+            if mss > limit {
+                segment[offset + 2..offset + 4].copy_from_slice(&limit.to_be_bytes());
+                checksum::update_field(
+                    segment,
+                    TCP_CHECKSUM_OFFSET,
+                    &mss.to_be_bytes(),
+                    &limit.to_be_bytes(),
+                );
+            }
+            return;
+        }
+
+        offset += length;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipv4::build_ipv4_packet;
+    use pnet_packet::tcp::MutableTcpPacket;
+    use std::net::Ipv4Addr;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 2);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(192, 168, 2, 2);
+
+    /// A TCP segment in an IPv4 packet with the flags and options and a valid checksum.
+    fn segment(flags: u16, options: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0u8; TCP_HEADER_LENGTH + options.len()];
+        let mut tcp = MutableTcpPacket::new(&mut buffer).unwrap();
+        tcp.set_source(49152);
+        tcp.set_destination(80);
+        tcp.set_sequence(0x12345678);
+        tcp.set_data_offset(((TCP_HEADER_LENGTH + options.len()) / 4) as u8);
+        tcp.set_flags(flags);
+        tcp.set_window(65535);
+        buffer[TCP_HEADER_LENGTH..].copy_from_slice(options);
+        let mut packet =
+            build_ipv4_packet(SOURCE, DESTINATION, IpNextHeaderProtocols::Tcp, &buffer);
+        set_checksum(&mut packet);
+        packet
+    }
+
+    fn set_checksum(packet: &mut [u8]) {
+        let checksum = full_checksum(packet);
+        packet[IPV4_HEADER_LENGTH + TCP_CHECKSUM_OFFSET..][..2]
+            .copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    fn full_checksum(packet: &[u8]) -> u16 {
+        let tcp = TcpPacket::new(&packet[IPV4_HEADER_LENGTH..]).unwrap();
+        pnet_packet::tcp::ipv4_checksum(&tcp, &SOURCE, &DESTINATION)
+    }
+
+    fn mss(packet: &[u8], offset: usize) -> u16 {
+        let offset = IPV4_HEADER_LENGTH + TCP_HEADER_LENGTH + offset + 2;
+        u16::from_be_bytes([packet[offset], packet[offset + 1]])
+    }
+
+    fn checksum(packet: &[u8]) -> u16 {
+        TcpPacket::new(&packet[IPV4_HEADER_LENGTH..])
+            .unwrap()
+            .get_checksum()
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(MssClamp::Off.limit(1500), None);
+        assert_eq!(MssClamp::Mtu.limit(1500), Some(1460));
+        assert_eq!(MssClamp::Mtu.limit(68), Some(28));
+        assert_eq!(MssClamp::Fixed(1360).limit(1500), Some(1360));
+    }
+
+    #[test]
+    fn clamps_syns() {
+        // NOP, NOP, MSS 1460, window scale 7, NOP
+        let options = [1, 1, 2, 4, 0x05, 0xb4, 3, 3, 7, 1, 0, 0];
+        for flags in [TcpFlags::SYN, TcpFlags::SYN | TcpFlags::ACK] {
+            let mut packet = segment(flags, &options);
+            clamp(&mut packet, 1360);
+            assert_eq!(mss(&packet, 2), 1360);
+            assert_eq!(checksum(&packet), full_checksum(&packet));
+            assert_eq!(
+                &packet[IPV4_HEADER_LENGTH + TCP_HEADER_LENGTH + 6..],
+                &options[6..]
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_lower_mss() {
+        let packet = segment(TcpFlags::SYN, &[2, 4, 0x05, 0x00]);
+        let mut clamped = packet.clone();
+        clamp(&mut clamped, 1360);
+        assert_eq!(mss(&clamped, 0), 1280);
+        assert_eq!(clamped, packet);
+    }
+
+    #[test]
+    fn ignores_other_segments() {
+        let options = [2, 4, 0x05, 0xb4];
+        let packet = segment(TcpFlags::ACK, &options);
+        let mut clamped = packet.clone();
+        clamp(&mut clamped, 1360);
+        assert_eq!(clamped, packet);
+
+        // The MSS option after the end of the option list.
+        let packet = segment(TcpFlags::SYN, &[0, 1, 1, 1, 2, 4, 0x05, 0xb4]);
+        let mut clamped = packet.clone();
+        clamp(&mut clamped, 1360);
+        assert_eq!(clamped, packet);
+
+        // An option running past the header.
+        let packet = segment(TcpFlags::SYN, &[1, 1, 3, 8]);
+        let mut clamped = packet.clone();
+        clamp(&mut clamped, 1360);
+        assert_eq!(clamped, packet);
+
+        // Not TCP.
+        let packet = build_ipv4_packet(
+            SOURCE,
+            DESTINATION,
+            IpNextHeaderProtocols::Udp,
+            &segment(TcpFlags::SYN, &options)[IPV4_HEADER_LENGTH..],
+        );
+        let mut clamped = packet.clone();
+        clamp(&mut clamped, 1360);
+        assert_eq!(clamped, packet);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::config::InterfaceConfig;
+use pnet_datalink::NetworkInterface;
+use std::collections::{BTreeSet, HashMap};
+
+/// The VRF interfaces belong to unless assigned to another one.
+pub(crate) const DEFAULT_VRF: &str = "default";
+
+/// Assignments of interfaces to VRFs (Virtual Routing and Forwarding instances). Each VRF has its
+/// own routing tables and ARP scope, and packets are forwarded only within the VRF of the ingress
+/// interface.
+#[derive(Clone, Debug)]
+pub(crate) struct VrfAssignments {
+    /// The VRF names keyed by the interface index (operating system specific).
+    vrfs: HashMap<u32, String>,
+}
+
+impl VrfAssignments {
+    pub(crate) fn new(
+        interfaces: &[NetworkInterface],
+        interface_configs: &HashMap<String, InterfaceConfig>,
+    ) -> Self {
+        let vrfs = interfaces
+            .iter()
+            .map(|i| {
+                let vrf = interface_configs
+                    .get(&i.name)
+                    .and_then(|c| c.vrf.clone())
+                    .unwrap_or_else(|| DEFAULT_VRF.to_string());
+                (i.index, vrf)
+            })
+            .collect();
+
+        VrfAssignments { vrfs }
+    }
+
+    /// Returns the name of the VRF the interface belongs to.
+    pub(crate) fn get(&self, interface_index: u32) -> &str {
+        self.vrfs
+            .get(&interface_index)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_VRF)
+    }
+
+    /// Returns the names of the VRFs having at least one interface.
+    pub(crate) fn names(&self) -> BTreeSet<&str> {
+        self.vrfs.values().map(String::as_str).collect()
+    }
+
+    /// Returns the interfaces belonging to the VRF.
+    pub(crate) fn interfaces(
+        &self,
+        vrf: &str,
+        interfaces: &[NetworkInterface],
+    ) -> Vec<NetworkInterface> {
+        interfaces
+            .iter()
+            .filter(|i| self.get(i.index) == vrf)
+            .cloned()
+            .collect()
+    }
+}